@@ -3,70 +3,184 @@
 
 //! Main collect loop that also processes XMR requests.
 
-use std::{path::Path, sync::Arc, thread, time::Duration};
+use std::{fmt, fs, path::{Path, PathBuf}, thread, time::{Duration, Instant}};
+use std::collections::{BTreeMap, HashSet};
+use std::sync::{Arc, atomic::{AtomicBool, AtomicI64, Ordering}};
 use anyhow::{bail, Context, Result};
 use crossbeam_channel::{after, never, select, tick, Receiver};
 use itertools::Itertools;
-use rand::rngs::OsRng;
+use parking_lot::Mutex;
+use rand::{Rng, rngs::OsRng};
+use time::OffsetDateTime;
 use rsa::{RsaPrivateKey, RsaPublicKey, pkcs8::{DecodePrivateKey, EncodePrivateKey, EncodePublicKey}};
-use crate::config::{CmsSettings, PlayerSettings};
-use crate::{logger, util, xmds, xmr};
-use crate::resource::{Cache, LayoutInfo};
+use crate::command::Command;
+use crate::config::{CmsSettings, Identity, PlayerSettings};
+use crate::{command, layout, logger, stats, util, xmds, xmr};
+use crate::resource::{Cache, LayoutInfo, ReqFile};
 use crate::schedule::Schedule;
+use crate::util::TIME_FMT;
+
+/// Delay in seconds before collecting again, if scheduled layouts are missing.
+const RECOLLECT_DELAY: u64 = 60;
+
+/// Time in seconds without XMR messages after which the connection is
+/// considered stale.  The CMS sends heartbeats every 30 seconds.
+const XMR_STALE: i64 = 120;
+
+/// Fault codes reported to the CMS.
+const FAULT_MEDIA: i64 = 1001;
+const FAULT_LAYOUT: i64 = 1002;
+
+/// Time in seconds after which a reported fault expires, unless reported again.
+const FAULT_EXPIRY: u64 = 86400;
 
 /// Messages sent to the GUI thread
 pub enum ToGui {
     Settings(PlayerSettings),
     Layouts(Vec<Arc<LayoutInfo>>),
+    /// Layouts to show on top of the scheduled layout.
+    Overlays(Vec<Arc<LayoutInfo>>),
     Screenshot,
+    Unauthorized,
+    Unreachable,
+    Diagnostics(Diagnostics),
+    Pause(bool),
+    /// A resource was updated, the media showing it should reload it.
+    ReloadResource(i64),
+    /// Show the layout instead of the schedule, for the given number of
+    /// seconds (0 = until reverted).
+    ChangeLayout(Arc<LayoutInfo>, u64),
+    /// Go back to the schedule after ChangeLayout.
+    RevertToSchedule,
+    /// Run layout actions triggered by the given webhook code.
+    WebHook(String),
+}
+
+/// Information shown by the diagnostics overlay.
+#[derive(Debug, Clone, Default)]
+pub struct Diagnostics {
+    pub display_id: String,
+    pub cms_address: String,
+    pub last_collect: Option<(String, Result<(), String>)>,
+    pub ip_address: Option<String>,
+    pub xmr_status: String,
+}
+
+impl Diagnostics {
+    /// Format the information for display, together with the currently
+    /// shown layout.
+    pub fn overlay_text(&self, current_layout: i64) -> String {
+        let last_collect = match &self.last_collect {
+            None => "not yet".into(),
+            Some((time, Ok(()))) => format!("{} (ok)", time),
+            Some((time, Err(e))) => format!("{} (failed: {})", time, e),
+        };
+        format!("Arexibo {}\nDisplay ID: {}\nCMS: {}\nLast collect: {}\n\
+                 Current layout: {}\nIP address: {}\nXMR: {}",
+                clap::crate_version!(), self.display_id, self.cms_address, last_collect,
+                current_layout, self.ip_address.as_deref().unwrap_or("unknown"),
+                self.xmr_status)
+    }
 }
 
 /// Messages received from the GUI thread
 pub enum FromGui {
     Showing(i64),
+    /// A media started (true) or stopped (false) being shown.
+    StatEvent(bool, i64),
     Screenshot(Vec<u8>),
+    Command(String),
 }
 
 /// Backend handler that performs the collect loop and XMDS requests.
 pub struct Handler {
+    cms: CmsSettings,
     to_gui: glib::Sender<ToGui>,
     from_gui: Receiver<FromGui>,
     settings: PlayerSettings,
+    authorized: bool,
+    unreachable: bool,
+    privkey: RsaPrivateKey,
     xmds: xmds::Cms,
     cache: Cache,
+    stats: stats::Queue,
+    stats_tracker: stats::Tracker,
     xmr: Receiver<xmr::Message>,
+    xmr_last_seen: Option<Arc<AtomicI64>>,
     schedule: Schedule,
+    schedule_hash: String,
+    schedule_hash_path: PathBuf,
     layouts: Vec<Arc<LayoutInfo>>,
+    overlays: Vec<Arc<LayoutInfo>>,
+    layouts_missing: bool,
     current_layout: i64,
+    /// Mechanism that currently keeps the display awake.
+    keep_awake: Option<KeepAwake>,
+    faults: Faults,
+    /// Layout change requested via XMR, waiting for the layout to be downloaded.
+    pending_change: Option<(i64, u64)>,
 }
 
 impl Handler {
     /// Create a new handler, with channels to the GUI thread.
-    pub fn new(cms: CmsSettings, clear_cache: bool, envdir: &Path,
+    pub fn new(cms: CmsSettings, clear_cache: bool, envdir: &Path, cachedir: &Path,
                to_gui: glib::Sender<ToGui>, from_gui: Receiver<FromGui>) -> Result<Self> {
         let (privkey, pubkey) = load_or_create_keypair(envdir)?;
-        let cache = Cache::new(&cms, envdir.join("res"), clear_cache).context("creating cache")?;
+        let cache = Cache::new(&cms, cachedir.to_path_buf(), clear_cache).context("creating cache")?;
+        let stats = stats::Queue::load(envdir.join("stats.json"));
         let schedule = Schedule::default();
+        // the hash of the last applied schedule is reported until we get a new one
+        let schedule_hash_path = envdir.join("schedule.hash");
+        let schedule_hash = fs::read_to_string(&schedule_hash_path).unwrap_or_default();
         let layouts = Default::default();
 
         // make an initial register call, in order to get player settings
-        let mut xmds = xmds::Cms::new(&cms, pubkey)?;
+        let mut xmds = xmds::Cms::new(&cms, pubkey, envdir.to_path_buf())?;
         log::info!("doing initial register call to CMS");
-        let res = xmds.register_display().context("initial registration")?;
-
-        // if we got settings, we are registered and authorized
-        if let Some(settings) = res {
-            // create the XMR manager which sends us updates via channel
-            let (manager, xmr) = xmr::Manager::new(&cms, &settings.xmr_network_address, privkey)?;
-            thread::spawn(|| manager.run());
-
-            let mut slf = Self { to_gui, from_gui, settings, cache, xmds, xmr, schedule,
-                                 layouts, current_layout: 0 };
-            slf.update_settings();
-            Ok(slf)
-        } else {
-            bail!("display is not authorized yet, try again after authorization in the CMS");
+        let res = xmds.register_display().context("initial registration");
+
+        let mut slf = Self { cms, to_gui, from_gui, settings: Default::default(),
+                             authorized: false, unreachable: false, privkey, cache, stats, xmds, xmr: never(),
+                             stats_tracker: Default::default(),
+                             xmr_last_seen: None,
+                             schedule, schedule_hash, schedule_hash_path, layouts,
+                             overlays: Vec::new(),
+                             layouts_missing: false, current_layout: 0,
+                             keep_awake: None, faults: Faults::default(),
+                             pending_change: None };
+
+        match res {
+            // if we got settings, we are registered and authorized
+            Ok(Some(settings)) => slf.authorize(settings)?,
+            Ok(None) => {
+                log::warn!("display is not authorized yet, please authorize it in the CMS");
+                slf.to_gui.send(ToGui::Unauthorized).unwrap();
+                slf.update_settings();
+            }
+            // show something while we keep trying to reach the CMS
+            Err(e) => {
+                log::error!("could not reach the CMS, will try again: {:#}", e);
+                slf.unreachable = true;
+                slf.to_gui.send(ToGui::Unreachable).unwrap();
+                slf.update_settings();
+            }
         }
+        Ok(slf)
+    }
+
+    /// Start normal operation once the display is authorized.
+    fn authorize(&mut self, settings: PlayerSettings) -> Result<()> {
+        // create the XMR manager which sends us updates via channel
+        let (manager, xmr) = xmr::Manager::new(&self.cms, &settings.xmr_network_address,
+                                               self.privkey.clone())?;
+        self.xmr_last_seen = Some(manager.last_seen());
+        thread::spawn(|| manager.run());
+        self.xmr = xmr;
+        self.settings = settings;
+        self.authorized = true;
+        self.unreachable = false;
+        self.update_settings();
+        Ok(())
     }
 
     pub fn player_settings(&self) -> PlayerSettings {
@@ -75,39 +189,77 @@ impl Handler {
 
     /// Run the main collect loop.
     pub fn run(mut self) -> Result<()> {
-        let mut collect = after(Duration::from_secs(0));  // do first collect immediately
-        let mut screenshot = if self.settings.screenshot_interval != 0 {
-            after(Duration::from_secs(self.settings.screenshot_interval * 60))
-        } else {
-            never()
-        };
+        // do first collect immediately, or after a delay if configured
+        let first_delay = first_collect_delay(self.cms.first_collect_delay,
+                                              self.cms.first_collect_jitter,
+                                              &mut rand::thread_rng());
+        let mut collect = after(first_delay);
+        let mut screenshot = ScreenshotTimer::new(self.settings.screenshot_interval);
         let schedule_check = tick(Duration::from_secs(60));
+        // the layout hook runs at most once per interval, with the latest layout
+        let hook_interval = Duration::from_secs(self.cms.layout_hook_interval);
+        let mut hook_timer = never();
+        let mut hook_last = None;
+        let mut hook_pending = None;
         loop {
             select! {
                 // timer channel that fires when collect is needed
                 recv(collect) -> _ => {
-                    if let Err(e) = self.collect_once() {
+                    let result = self.collect_once();
+                    if let Err(e) = &result {
                         log::error!("during collect: {:#}", e);
                     }
-                    collect = after(Duration::from_secs(self.settings.collect_interval));
+                    self.send_diagnostics(result.map_err(|e| format!("{:#}", e)));
+                    collect = after(collect_delay(self.settings.collect_interval,
+                                                  self.cms.collect_jitter, self.authorized,
+                                                  self.layouts_missing, &mut rand::thread_rng()));
+                    if let Some((layout_id, duration)) = self.pending_change.take() {
+                        self.change_layout(layout_id, duration, false);
+                    }
+                    // a changed screenshot interval applies from now on
+                    if screenshot.update(self.settings.screenshot_interval) {
+                        log::debug!("screenshot interval changed, rescheduling screenshots");
+                    }
                 },
                 // timer channel that fires when screenshot is needed
-                recv(screenshot) -> _ => {
+                recv(screenshot.timer) -> _ => {
                     self.to_gui.send(ToGui::Screenshot).unwrap();
-                    screenshot = if self.settings.screenshot_interval != 0 {
-                        after(Duration::from_secs(self.settings.screenshot_interval * 60))
-                    } else {
-                        never()
-                    };
+                    screenshot.restart();
                 },
                 // timer channel that fires every minute, to check if current layouts change
                 recv(schedule_check) -> _ => {
-                    self.schedule_check();
+                    if self.schedule_check() {
+                        collect = after(Duration::from_secs(0));
+                    }
                 },
                 // channel for XMR messages
                 recv(self.xmr) -> msg => match msg {
                     Ok(xmr::Message::CollectNow) => collect = after(Duration::from_secs(0)),
-                    Ok(xmr::Message::Screenshot) => screenshot = after(Duration::from_secs(0)),
+                    Ok(xmr::Message::Screenshot) => screenshot.fire_now(),
+                    Ok(xmr::Message::ChangeLayout { layout_id, duration, download_required }) => {
+                        if self.change_layout(layout_id, duration, download_required) {
+                            collect = after(Duration::from_secs(0));
+                        }
+                    }
+                    Ok(xmr::Message::RevertToSchedule) => {
+                        log::info!("reverting to the schedule on request");
+                        self.pending_change = None;
+                        self.to_gui.send(ToGui::RevertToSchedule).unwrap();
+                        if self.schedule_check() {
+                            collect = after(Duration::from_secs(0));
+                        }
+                    }
+                    Ok(xmr::Message::Purge) => {
+                        log::info!("purging all cached files on request");
+                        if let Err(e) = self.cache.purge() {
+                            log::error!("purging cache: {:#}", e);
+                        }
+                        collect = after(Duration::from_secs(0));
+                    }
+                    Ok(xmr::Message::WebHook(code)) => {
+                        self.to_gui.send(ToGui::WebHook(code)).unwrap();
+                    }
+                    Ok(xmr::Message::Command(code)) => self.run_command(code, "CMS"),
                     Err(_) => ()
                 },
                 // channel for screenshot data from the GUI thread
@@ -117,9 +269,31 @@ impl Handler {
                             log::error!("submitting screenshot: {:#}", e);
                         }
                     }
-                    Ok(FromGui::Showing(layout)) =>
-                        self.current_layout = layout,
+                    Ok(FromGui::Command(code)) => self.run_command(code, "layout"),
+                    Ok(FromGui::StatEvent(start, media)) => {
+                        let now = OffsetDateTime::now_utc().unix_timestamp();
+                        if let Some(record) = self.stats_tracker.media_event(start, media, now) {
+                            self.record_stats(vec![record]);
+                        }
+                    }
+                    Ok(FromGui::Showing(layout)) => {
+                        self.current_layout = layout;
+                        let now = OffsetDateTime::now_utc().unix_timestamp();
+                        let records = self.stats_tracker.layout_shown(layout, now);
+                        self.record_stats(records);
+                        if self.cms.layout_hook.is_some() && hook_pending.replace(layout).is_none() {
+                            hook_timer = after(hook_delay(hook_last, Instant::now(), hook_interval));
+                        }
+                    }
                     _ => ()
+                },
+                // timer channel that fires when the layout hook should run
+                recv(hook_timer) -> _ => {
+                    hook_timer = never();
+                    hook_last = Some(Instant::now());
+                    if let (Some(layout), Some(cmd)) = (hook_pending.take(), &self.cms.layout_hook) {
+                        run_layout_hook(Command::new(cmd), layout);
+                    }
                 }
             }
         }
@@ -128,52 +302,124 @@ impl Handler {
     /// Do a single collection cycle.
     fn collect_once(&mut self) -> Result<()> {
         log::info!("doing collection");
+        let mut timings = Timings::new();
 
         // call register to get updated player settings
         if let Some(settings) = self.xmds.register_display()? {
-            if settings != self.settings {
-                self.settings = settings;
+            if !self.authorized {
+                log::info!("display is now authorized");
+                self.authorize(settings)?;
+            } else if take_settings(&mut self.settings, settings) {
                 self.update_settings();
             }
-        } else {
+        } else if self.authorized {
             bail!("display is not authorized anymore");
+        } else {
+            log::info!("display is not authorized yet, waiting");
+            if std::mem::take(&mut self.unreachable) {
+                self.to_gui.send(ToGui::Unauthorized).unwrap();
+            }
+            return Ok(());
         }
+        timings.mark("register");
 
         // get the missing files
-        let required = self.xmds.required_files()?;
+        let (required, layout_codes) = self.xmds.required_files()?;
+        self.cache.set_required(&required);
+        if let Err(e) = self.cache.set_layout_codes(layout_codes) {
+            log::warn!("could not translate layouts for new layout codes: {:#}", e);
+        }
+        if let Err(e) = self.cache.reconcile(&required) {
+            log::warn!("could not check cache content: {:#}", e);
+        }
+        timings.mark("required files");
 
         // get the schedule
         let schedule = self.xmds.get_schedule()?;
+        timings.mark("schedule");
 
-        // download all missing files
+        // download all missing files, unless they are too large
         let mut result = Vec::new();
-        let total = required.len();
-        for (i, file) in required.into_iter().enumerate() {
-            if !self.cache.has(&file) {
-                let filedesc = file.description();
-                let inventory = file.inventory();
-                log::info!("downloading required file {}/{}: {}", i+1, total, filedesc);
-                match self.cache.download(file, &mut self.xmds)
-                                .with_context(|| format!("downloading {}", filedesc))
-                {
-                    Ok(_) => result.push((inventory, true)),
-                    Err(e) => {
-                        log::error!("{:#}", e);
-                        result.push((inventory, false));
+        // faults of files that are no longer required are obsolete
+        let inventories = required.iter().map(|file| file.inventory()).collect::<HashSet<_>>();
+        self.faults.retain(|file| inventories.contains(file));
+        let missing = required.into_iter().filter(|file| !self.cache.has(file)).collect();
+        let (files, oversized) = check_sizes(missing, self.cms.max_file_size);
+        for (file, e) in &oversized {
+            let inventory = file.inventory();
+            crate::file_error!(inventory, "not downloading {}: {:#}", file.description(), e);
+            self.faults.set(inventory, file.resource_media(), format!("{:#}", e));
+            result.push((inventory, false));
+        }
+        let total = files.len();
+        let mut downloads = Vec::new();
+        for (i, file) in files.iter().enumerate() {
+            let filedesc = file.description();
+            log::info!("downloading required file {}/{}: {}", i+1, total, filedesc);
+            downloads.push((filedesc, file.inventory(), file.resource_media()));
+        }
+        let results = self.cache.download_many(files, &mut self.xmds, self.cms.download_workers);
+        for ((filedesc, inventory, resource_media), res) in downloads.into_iter().zip(results) {
+            match res.with_context(|| format!("downloading {}", filedesc)) {
+                Ok(_) => {
+                    result.push((inventory, true));
+                    self.faults.clear(inventory);
+                    if let Some(mid) = resource_media {
+                        self.to_gui.send(ToGui::ReloadResource(mid)).unwrap();
                     }
                 }
+                Err(e) => {
+                    let reason = format!("{:#} ({})", e, download_failure_reason(&e));
+                    self.faults.set(inventory, resource_media, reason.clone());
+                    crate::file_error!(inventory, "{}", reason);
+                    result.push((inventory, false));
+                }
             }
         }
+        if !oversized.is_empty() {
+            log::warn!("skipped files over the size limit, content using them can't be shown: {}",
+                       oversized.iter().map(|(file, _)| file.description()).join(", "));
+        }
+        timings.mark("downloads");
 
         // let the CMS know we have the media
         self.xmds.submit_media_inventory(result)?;
+        timings.mark("inventory");
+
+        // let operators know why content is missing
+        if self.faults.changed {
+            match self.xmds.report_faults(&self.faults.report()) {
+                Ok(()) => self.faults.changed = false,
+                // older CMS versions don't support this call
+                Err(e) => log::warn!("could not report faults: {:#}", e),
+            }
+        }
 
         // now that we should have all media, apply the schedule
+        let hash = schedule.hash();
+        if hash != self.schedule_hash {
+            if let Err(e) = util::write_atomic(&self.schedule_hash_path, hash.as_bytes()) {
+                log::warn!("could not save schedule hash: {:#}", e);
+            }
+            self.schedule_hash = hash;
+        }
         self.schedule = schedule;
         self.schedule_check();
+        if self.cms.verify_content {
+            self.verify_layouts();
+        }
 
         // send log messages
         self.xmds.submit_log(&logger::pop_entries())?;
+        timings.mark("log");
+
+        // send buffered statistics; they are kept on disk if this fails
+        if self.settings.stats_enabled {
+            if let Err(e) = self.stats.submit(|xml| self.xmds.submit_stats(xml)) {
+                log::error!("submitting stats: {:#}", e);
+            }
+            timings.mark("stats");
+        }
 
         // collect status info
         let (avail, total) = util::space_info(self.cache.dir())?;
@@ -184,22 +430,112 @@ impl Handler {
             lastCommandSuccess: false,  // not implemented yet
             deviceName: &self.settings.display_name,
             timeZone: &util::timezone(),
+            scheduleHash: &self.schedule_hash,
+            latitude: self.cms.latitude,
+            longitude: self.cms.longitude,
         };
         self.xmds.notify_status(status)?;
+        timings.mark("status");
 
+        log::debug!("collect timings: {}", timings);
         log::info!("collection successful");
         Ok(())
     }
 
-    /// Check if need to update the layouts to show.
-    fn schedule_check(&mut self) {
-        let new_layouts = self.schedule.layouts_now(&self.cache);
+    /// Check that the currently scheduled layouts can be shown, and log
+    /// any problems found.
+    fn verify_layouts(&self) {
+        for layout in &self.layouts {
+            let problems = self.cache.verify_layout(layout.id);
+            if problems.is_empty() {
+                log::info!("layout {} verified ok", layout.id);
+            } else {
+                log::warn!("layout {} is not healthy: {}", layout.id, problems.join(", "));
+            }
+        }
+    }
+
+    /// Queue statistics records for submission, if enabled.
+    fn record_stats(&mut self, records: Vec<stats::Record>) {
+        if !self.settings.stats_enabled || records.is_empty() {
+            return;
+        }
+        if let Err(e) = self.stats.push(records, self.settings.stats_aggregation) {
+            log::warn!("could not record stats: {:#}", e);
+        }
+    }
+
+    /// Let the GUI know about the current state, for the diagnostics overlay.
+    fn send_diagnostics(&self, collect_result: Result<(), String>) {
+        let now = OffsetDateTime::now_local().unwrap_or_else(|_| OffsetDateTime::now_utc());
+        let diag = Diagnostics {
+            display_id: self.cms.display_id.clone(),
+            cms_address: self.cms.address.clone(),
+            last_collect: Some((now.format(&TIME_FMT).unwrap_or_default(), collect_result)),
+            ip_address: util::local_ip().map(|ip| ip.to_string()),
+            xmr_status: xmr_status(&self.cms.xmr_channel(),
+                                   self.xmr_last_seen.as_ref().map(|t| t.load(Ordering::Relaxed)),
+                                   now.unix_timestamp()),
+        };
+        self.to_gui.send(ToGui::Diagnostics(diag)).unwrap();
+    }
+
+    /// Check if need to update the layouts to show.  Returns true if
+    /// scheduled layouts are newly found to be missing from the cache.
+    fn schedule_check(&mut self) -> bool {
+        let dedup = !self.cms.duplicate_layouts;
+        let location = self.cms.latitude.zip(self.cms.longitude);
+        let (new_layouts, mut missing) = self.schedule.layouts_now(&self.cache, dedup, location);
+        let (new_overlays, missing_overlays) = self.schedule.overlays_now(&self.cache);
+        missing.extend(missing_overlays);
+        let was_missing = std::mem::replace(&mut self.layouts_missing, !missing.is_empty());
+        if !missing.is_empty() && !was_missing {
+            log::warn!("scheduled layouts not in cache: {}, collecting again",
+                       missing.iter().format(", "));
+        }
         if new_layouts != self.layouts {
             log::info!("new layouts in schedule: {}",
                        new_layouts.iter().map(|l| l.id).format(", ").to_string());
             self.to_gui.send(ToGui::Layouts(new_layouts.clone())).unwrap();
             self.layouts = new_layouts;
         }
+        if new_overlays != self.overlays {
+            log::info!("new overlays in schedule: {}",
+                       new_overlays.iter().map(|l| l.id).format(", ").to_string());
+            self.to_gui.send(ToGui::Overlays(new_overlays.clone())).unwrap();
+            self.overlays = new_overlays;
+        }
+        self.layouts_missing && !was_missing
+    }
+
+    /// Run a stored command, as requested by a layout or the CMS.
+    fn run_command(&self, code: String, source: &str) {
+        if let Some(stored) = self.settings.commands.get(&code) {
+            match Command::new(&stored.command).validated_by(stored.validation.as_deref()) {
+                Ok(cmd) => run_stored_command(cmd, code),
+                Err(e) => log::error!("stored command {:?}: {:#}", code, e),
+            }
+        } else {
+            log::warn!("{} requested unknown stored command {:?}", source, code);
+        }
+    }
+
+    /// Show a layout instead of the schedule, if it is in the cache.  If it
+    /// is not, but may be downloaded, returns true to request a collect,
+    /// after which the change is tried again.
+    fn change_layout(&mut self, layout_id: i64, duration: u64, download: bool) -> bool {
+        if let Some(info) = self.cache.get_layout(layout_id) {
+            log::info!("changing to layout {} on request{}", layout_id,
+                       if duration > 0 { format!(" for {}s", duration) } else { String::new() });
+            self.to_gui.send(ToGui::ChangeLayout(info, duration)).unwrap();
+        } else if download {
+            log::info!("layout {} requested, downloading it first", layout_id);
+            self.pending_change = Some((layout_id, duration));
+            return true;
+        } else {
+            log::warn!("layout {} requested, but it is not in the cache", layout_id);
+        }
+        false
     }
 
     /// Apply new player settings.
@@ -207,18 +543,341 @@ impl Handler {
         // let the GUI know to reconfigure itself
         self.to_gui.send(ToGui::Settings(self.settings.clone())).unwrap();
 
-        match &*self.settings.log_level {
-            "trace" => log::set_max_level(log::LevelFilter::Trace),
-            "debug" => log::set_max_level(log::LevelFilter::Debug),
-            "info" => log::set_max_level(log::LevelFilter::Info),
-            "error" => log::set_max_level(log::LevelFilter::Warn),
-            "off" => log::set_max_level(log::LevelFilter::Off),
-            s => log::error!("invalid log level {}", s)
+        // layouts may need to be translated again with the new settings
+        let options = layout::Options::from_settings(&self.settings, &self.cms);
+        if let Err(e) = self.cache.set_layout_options(options) {
+            log::error!("updating layout options: {:#}", e);
+        }
+
+        // apply setting to prevent sleep, or allow it again if switched off
+        if !self.settings.prevent_sleep {
+            if let Some(active) = self.keep_awake.take() {
+                log::info!("allowing sleep again");
+                active.release();
+            }
+        } else if self.keep_awake.is_none() {
+            let native = || util::inhibit_screensaver().map(KeepAwake::Screensaver);
+            self.keep_awake = keep_awake(native, self.cms.keep_awake_command.as_deref(),
+                                         start_keep_awake_command);
+        }
+
+        match logger::cms_level(&self.settings.log_level) {
+            Some(level) => {
+                // warnings are still useful on the console
+                log::set_max_level(if level == log::LevelFilter::Error {
+                    log::LevelFilter::Warn
+                } else {
+                    level
+                });
+                logger::set_submit_level(level);
+            }
+            None => log::error!("invalid log level {}", self.settings.log_level),
         }
     }
 }
 
 
+/// Take over the player settings from a register call.  Only the settings
+/// controlled by the CMS are replaced; local options live in the CMS config,
+/// which is left alone.  Returns true if the settings changed.
+fn take_settings(settings: &mut PlayerSettings, update: PlayerSettings) -> bool {
+    if *settings == update {
+        return false;
+    }
+    *settings = update;
+    true
+}
+
+/// Describe the XMR connection state from the time of the last message.
+fn xmr_status(channel: &str, last_seen: Option<i64>, now: i64) -> String {
+    match last_seen {
+        None => "not connected".into(),
+        Some(0) => format!("channel {}, no message yet", channel),
+        Some(ts) => format!("channel {}, last message {}s ago{}", channel, now - ts,
+                            if now - ts > XMR_STALE { " (stale)" } else { "" }),
+    }
+}
+
+/// Apply a random jitter of up to `percent` percent to the interval, so that
+/// displays booted at the same time don't collect at the same time.
+fn jittered(interval: Duration, percent: u64, rng: &mut impl Rng) -> Duration {
+    let max = interval.as_secs_f64() * percent as f64 / 100.;
+    if max <= 0. {
+        return interval;
+    }
+    Duration::from_secs_f64((interval.as_secs_f64() + rng.gen_range(-max..=max)).max(0.))
+}
+
+/// Return the delay before the next collect, using the current collect
+/// interval in seconds.
+fn collect_delay(interval: u64, jitter: u64, authorized: bool, layouts_missing: bool,
+                 rng: &mut impl Rng) -> Duration {
+    if layouts_missing {
+        Duration::from_secs(RECOLLECT_DELAY)
+    } else if authorized {
+        jittered(Duration::from_secs(interval), jitter, rng)
+    } else {
+        // check for authorization more often
+        Duration::from_secs(60)
+    }
+}
+
+/// Return the delay before the next screenshot, using the current screenshot
+/// interval in minutes (0 = no screenshots).
+fn screenshot_delay(interval: u64) -> Option<Duration> {
+    (interval != 0).then(|| Duration::from_secs(interval * 60))
+}
+
+/// Timer for taking screenshots, following the screenshot interval setting.
+struct ScreenshotTimer {
+    /// Interval in minutes (0 = no screenshots).
+    interval: u64,
+    timer: Receiver<Instant>,
+}
+
+impl ScreenshotTimer {
+    fn new(interval: u64) -> Self {
+        let mut slf = Self { interval, timer: never() };
+        slf.restart();
+        slf
+    }
+
+    /// Start the timer again, with the current interval.
+    fn restart(&mut self) {
+        self.timer = screenshot_delay(self.interval).map_or_else(never, after);
+    }
+
+    /// Take a screenshot right away, e.g. when requested via XMR.
+    fn fire_now(&mut self) {
+        self.timer = after(Duration::ZERO);
+    }
+
+    /// Apply the screenshot interval from the settings.  Returns true if it
+    /// changed, and the timer was started again.
+    fn update(&mut self, interval: u64) -> bool {
+        if interval == self.interval {
+            return false;
+        }
+        self.interval = interval;
+        self.restart();
+        true
+    }
+}
+
+/// Return the delay before the first collect: a fixed delay, e.g. to let the
+/// GUI start up first, plus a random jitter of up to `jitter` seconds.
+fn first_collect_delay(delay: u64, jitter: u64, rng: &mut impl Rng) -> Duration {
+    Duration::from_secs(delay + rng.gen_range(0..=jitter))
+}
+
+/// Return the delay before running the layout hook, so that it runs at
+/// most once per interval.
+fn hook_delay(last: Option<Instant>, now: Instant, interval: Duration) -> Duration {
+    last.map_or(Duration::ZERO, |last| interval.saturating_sub(now - last))
+}
+
+/// Run the layout hook in the background.
+fn run_layout_hook(cmd: Command, layout: i64) {
+    thread::spawn(move || match cmd.run(&[layout.to_string()]) {
+        Ok(true) => (),
+        Ok(false) => log::warn!("layout hook failed for layout {}", layout),
+        Err(e) => log::error!("running layout hook: {:#}", e),
+    });
+}
+
+/// Time spent in the phases of a collect.
+struct Timings {
+    start: Instant,
+    last: Instant,
+    phases: Vec<(&'static str, Duration)>,
+}
+
+impl Timings {
+    fn new() -> Self {
+        let now = Instant::now();
+        Self { start: now, last: now, phases: Vec::new() }
+    }
+
+    /// Record the end of a phase that started with the previous one's end.
+    fn mark(&mut self, phase: &'static str) {
+        let now = Instant::now();
+        self.phases.push((phase, now - self.last));
+        self.last = now;
+    }
+}
+
+impl fmt::Display for Timings {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (phase, time) in &self.phases {
+            write!(f, "{} {:.2}s, ", phase, time.as_secs_f64())?;
+        }
+        write!(f, "total {:.2}s", (self.last - self.start).as_secs_f64())
+    }
+}
+
+/// Faults of required files, to report to the CMS.
+#[derive(Default)]
+struct Faults {
+    current: BTreeMap<(&'static str, i64), xmds::Fault>,
+    /// Whether the faults changed since the last successful report.
+    changed: bool,
+}
+
+impl Faults {
+    /// Record a fault for the given file; `media` is the media ID of resources.
+    fn set(&mut self, file: (&'static str, i64), media: Option<i64>, reason: String) {
+        if self.current.get(&file).map_or(false, |fault| fault.reason == reason) {
+            return;
+        }
+        let now = OffsetDateTime::now_local().unwrap_or_else(|_| OffsetDateTime::now_utc());
+        let format = |t: OffsetDateTime| t.format(&TIME_FMT).expect("time fmt");
+        let (code, layout_id, media_id) = match file {
+            ("layout", id) => (FAULT_LAYOUT, id, 0),
+            (_, id) => (FAULT_MEDIA, 0, media.unwrap_or(id)),
+        };
+        self.current.insert(file, xmds::Fault {
+            date: format(now),
+            expires: format(now + Duration::from_secs(FAULT_EXPIRY)),
+            code, reason, layoutId: layout_id, mediaId: media_id,
+        });
+        self.changed = true;
+    }
+
+    /// Clear the fault of a file, e.g. after it was downloaded successfully.
+    fn clear(&mut self, file: (&'static str, i64)) {
+        self.changed |= self.current.remove(&file).is_some();
+    }
+
+    fn retain(&mut self, mut keep: impl FnMut(&(&'static str, i64)) -> bool) {
+        let before = self.current.len();
+        self.current.retain(|file, _| keep(file));
+        self.changed |= self.current.len() != before;
+    }
+
+    fn report(&self) -> Vec<xmds::Fault> {
+        self.current.values().cloned().collect()
+    }
+}
+
+/// Classify the reason for a failed download, for the log entry sent to the CMS.
+fn download_failure_reason(err: &anyhow::Error) -> String {
+    for cause in err.chain() {
+        if let Some(ureq::Error::Status(code, _)) = cause.downcast_ref::<ureq::Error>() {
+            return format!("HTTP status {}", code);
+        }
+        if let Some(e) = cause.downcast_ref::<std::io::Error>() {
+            if matches!(e.kind(), std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock) {
+                return "timeout".into();
+            }
+        }
+        let msg = cause.to_string();
+        if msg.contains("timed out") {
+            return "timeout".into();
+        }
+        if msg == "md5 mismatch" {
+            return "checksum mismatch".into();
+        }
+    }
+    "other error".into()
+}
+
+/// Split the files to download into those within the size limit (in MiB,
+/// 0 = no limit) and those over it, along with the reason.
+fn check_sizes(files: Vec<ReqFile>, max_mb: u64) -> (Vec<ReqFile>, Vec<(ReqFile, anyhow::Error)>) {
+    let mut allowed = Vec::new();
+    let mut oversized = Vec::new();
+    for file in files {
+        match file.check_size(max_mb) {
+            Ok(()) => allowed.push(file),
+            Err(e) => oversized.push((file, e)),
+        }
+    }
+    (allowed, oversized)
+}
+
+/// Run a stored command requested by a layout, in the background.
+fn run_stored_command(cmd: Command, code: String) {
+    thread::spawn(move || match cmd.run(&[]) {
+        Ok(true) => (),
+        Ok(false) => log::warn!("stored command {:?} failed", code),
+        Err(e) => log::error!("running stored command {:?}: {:#}", code, e),
+    });
+}
+
+/// Mechanism that keeps the display awake.
+enum KeepAwake {
+    /// Screensaver inhibited via D-Bus.
+    Screensaver(util::ScreensaverInhibit),
+    /// Keep-awake command, with its process ID while it is running.
+    Command { pid: Arc<Mutex<Option<u32>>>, released: Arc<AtomicBool> },
+}
+
+impl KeepAwake {
+    /// Allow the display to sleep again.  A keep-awake command that is
+    /// still running is terminated.
+    fn release(self) {
+        match self {
+            KeepAwake::Screensaver(inhibit) => drop(inhibit),
+            KeepAwake::Command { pid, released } => {
+                let mut pid = pid.lock();
+                released.store(true, Ordering::Relaxed);
+                if let Some(pid) = pid.take() {
+                    command::terminate(pid);
+                }
+            }
+        }
+    }
+}
+
+/// Prevent the display from going to sleep, using the screensaver D-Bus
+/// interface or, if that fails, the configured command.  Returns the
+/// mechanism that was started.
+fn keep_awake<T>(native: impl FnOnce() -> Result<T>, command: Option<&str>,
+                 start_command: impl FnOnce(Command) -> T) -> Option<T> {
+    match native() {
+        Ok(active) => {
+            log::info!("preventing sleep using the screensaver D-Bus interface");
+            Some(active)
+        }
+        Err(e) => {
+            log::warn!("could not inhibit screensaver: {:#}", e);
+            let Some(command) = command else {
+                log::warn!("could not prevent sleep, no keep-awake command configured");
+                return None;
+            };
+            Some(start_command(Command::new(command)))
+        }
+    }
+}
+
+fn start_keep_awake_command(cmd: Command) -> KeepAwake {
+    let pid = Arc::new(Mutex::new(None));
+    let released = Arc::new(AtomicBool::new(false));
+    let (running, stopped) = (pid.clone(), released.clone());
+    thread::spawn(move || {
+        let result = cmd.run_with_pid(&[], |p| {
+            let mut pid = running.lock();
+            // released while waiting for a process slot
+            if stopped.load(Ordering::Relaxed) {
+                command::terminate(p);
+            } else {
+                *pid = Some(p);
+            }
+        });
+        running.lock().take();
+        // terminated by release(), which is no failure
+        if stopped.load(Ordering::Relaxed) {
+            return;
+        }
+        match result {
+            Ok(true) => log::info!("preventing sleep using the keep-awake command"),
+            Ok(false) => log::warn!("keep-awake command failed"),
+            Err(e) => log::error!("running keep-awake command: {:#}", e),
+        }
+    });
+    KeepAwake::Command { pid, released }
+}
+
 /// Load the RSA private key for the XML channel from disk, or create a new
 /// key if needed.  Returns the public key as a PEM string, which is how
 /// it needs to be sent to the CMS.
@@ -226,7 +885,9 @@ fn load_or_create_keypair(dir: &Path) -> Result<(RsaPrivateKey, String)> {
     let privkey = if let Ok(key) = RsaPrivateKey::read_pkcs8_pem_file(dir.join("id_rsa")) {
         key
     } else {
-        log::info!("generating new RSA key for XMR, please wait...");
+        log::warn!("no RSA key found, generating a new one for XMR, please wait...");
+        log::warn!("XMR messages will only work again once the CMS has received the \
+                    new key with the next register call");
         let key = RsaPrivateKey::new(&mut OsRng, 2048)?;
         key.write_pkcs8_pem_file(dir.join("id_rsa"), Default::default())?;
         key
@@ -234,3 +895,272 @@ fn load_or_create_keypair(dir: &Path) -> Result<(RsaPrivateKey, String)> {
     let pubkey = RsaPublicKey::from(&privkey).to_public_key_pem(Default::default())?;
     Ok((privkey, pubkey))
 }
+
+/// Export the display identity (display ID and XMR key) for migrating it
+/// to another environment directory.  The key must already exist.
+pub fn export_identity(dir: &Path, display_id: &str) -> Result<Identity> {
+    let privkey = RsaPrivateKey::read_pkcs8_pem_file(dir.join("id_rsa"))
+        .context("reading XMR key, has the player run in this directory?")?;
+    Ok(Identity {
+        display_id: display_id.into(),
+        private_key: privkey.to_pkcs8_pem(Default::default())?.as_str().into(),
+    })
+}
+
+/// Import a display identity created by `export_identity`.
+pub fn import_identity(dir: &Path, identity: &Identity) -> Result<()> {
+    let key = RsaPrivateKey::from_pkcs8_pem(&identity.private_key)
+        .context("invalid private key in identity")?;
+    key.write_pkcs8_pem_file(dir.join("id_rsa"), Default::default())?;
+    Ok(())
+}
+
+#[test]
+fn test_identity_roundtrip() {
+    use std::os::unix::fs::PermissionsExt;
+    let base = std::env::temp_dir().join(format!("arexibo-test-identity-{}", std::process::id()));
+    let (dir1, dir2) = (base.join("1"), base.join("2"));
+    std::fs::create_dir_all(&dir1).unwrap();
+    std::fs::create_dir_all(&dir2).unwrap();
+    let key = RsaPrivateKey::new(&mut OsRng, 512).unwrap();
+    key.write_pkcs8_pem_file(dir1.join("id_rsa"), Default::default()).unwrap();
+
+    // no key yet: exporting must not create one
+    assert!(export_identity(&dir2, "display").is_err());
+    assert!(!dir2.join("id_rsa").exists());
+
+    let identity = export_identity(&dir1, "display").unwrap();
+    identity.to_file(base.join("identity.json")).unwrap();
+    let mode = std::fs::metadata(base.join("identity.json")).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+    let identity = Identity::from_file(base.join("identity.json")).unwrap();
+    assert_eq!(identity.display_id, "display");
+    import_identity(&dir2, &identity).unwrap();
+    assert_eq!(load_or_create_keypair(&dir2).unwrap().0, key);
+    std::fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn test_layout_hook() {
+    let now = Instant::now();
+    let secs = Duration::from_secs;
+    assert_eq!(hook_delay(None, now, secs(5)), secs(0));
+    assert_eq!(hook_delay(Some(now - secs(2)), now, secs(5)), secs(3));
+    assert_eq!(hook_delay(Some(now - secs(10)), now, secs(5)), secs(0));
+
+    let path = std::env::temp_dir().join(format!("arexibo-test-hook-{}", std::process::id()));
+    let cmd = Command::new(format!("printf %s > {}", path.display()));
+    assert!(cmd.run(&["42".into()]).unwrap());
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "42");
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_collect_jitter() {
+    let mut rng = rand::thread_rng();
+    let interval = Duration::from_secs(900);
+    assert_eq!(jittered(interval, 0, &mut rng), interval);
+    for _ in 0..1000 {
+        let value = jittered(interval, 5, &mut rng);
+        assert!(value >= Duration::from_secs(855) && value <= Duration::from_secs(945));
+    }
+}
+
+#[test]
+fn test_timer_delays() {
+    let mut rng = rand::thread_rng();
+    let secs = Duration::from_secs;
+    assert_eq!(collect_delay(900, 0, true, false, &mut rng), secs(900));
+    // a changed interval is used for the next collect
+    assert_eq!(collect_delay(300, 0, true, false, &mut rng), secs(300));
+    assert_eq!(collect_delay(300, 0, false, false, &mut rng), secs(60));
+    assert_eq!(collect_delay(300, 0, true, true, &mut rng), secs(RECOLLECT_DELAY));
+    assert_eq!(screenshot_delay(0), None);
+    assert_eq!(screenshot_delay(5), Some(secs(300)));
+}
+
+#[test]
+fn test_screenshot_timer() {
+    let short = Duration::from_millis(50);
+    // before authorization there are no settings, and no screenshots
+    let mut timer = ScreenshotTimer::new(0);
+    assert!(timer.timer.recv_timeout(short).is_err());
+    // the interval from the settings starts the timer, but only if it changed
+    assert!(timer.update(5));
+    assert!(!timer.update(5));
+    assert_eq!(timer.interval, 5);
+    assert!(timer.timer.recv_timeout(short).is_err());
+    timer.fire_now();
+    assert!(timer.timer.recv_timeout(short).is_ok());
+    // switching screenshots off stops the timer
+    assert!(timer.update(0));
+    timer.fire_now();
+    timer.restart();
+    assert!(timer.timer.recv_timeout(short).is_err());
+}
+
+#[test]
+fn test_first_collect_delay() {
+    let mut rng = rand::thread_rng();
+    assert_eq!(first_collect_delay(0, 0, &mut rng), Duration::ZERO);
+    assert_eq!(first_collect_delay(10, 0, &mut rng), Duration::from_secs(10));
+    for _ in 0..100 {
+        let value = first_collect_delay(10, 5, &mut rng);
+        assert!(value >= Duration::from_secs(10) && value <= Duration::from_secs(15));
+    }
+}
+
+#[test]
+fn test_keep_awake() {
+    use std::cell::Cell;
+    let ran = Cell::new(false);
+    let run = |_| { ran.set(true); "command" };
+    assert_eq!(keep_awake(|| Ok("dbus"), Some("keepawake"), run), Some("dbus"));
+    assert!(!ran.get());
+    assert_eq!(keep_awake(|| bail!("no dbus"), None, run), None);
+    assert!(!ran.get());
+    assert_eq!(keep_awake(|| bail!("no dbus"), Some("keepawake"), run), Some("command"));
+    assert!(ran.get());
+}
+
+#[test]
+fn test_keep_awake_release() {
+    let active = start_keep_awake_command(Command::new("sleep 30"));
+    let KeepAwake::Command { pid, .. } = &active else { unreachable!() };
+    let pid = pid.clone();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while pid.lock().is_none() {
+        assert!(Instant::now() < deadline);
+        thread::sleep(Duration::from_millis(10));
+    }
+    let child = pid.lock().unwrap();
+    active.release();
+    // the command is gone once its process group can't be signalled anymore
+    while nix::sys::signal::killpg(nix::unistd::Pid::from_raw(child as i32), None).is_ok() {
+        assert!(Instant::now() < deadline);
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn test_diagnostics_text() {
+    let mut diag = Diagnostics {
+        display_id: "abc".into(),
+        cms_address: "https://cms/".into(),
+        last_collect: None,
+        ip_address: Some("10.0.0.5".into()),
+        xmr_status: "not connected".into(),
+    };
+    let text = diag.overlay_text(12);
+    assert!(text.starts_with(&format!("Arexibo {}\n", clap::crate_version!())));
+    assert!(text.contains("Display ID: abc\nCMS: https://cms/\nLast collect: not yet\n"));
+    assert!(text.contains("Current layout: 12\nIP address: 10.0.0.5\nXMR: not connected"));
+    diag.last_collect = Some(("2024-01-01 12:00:00".into(), Err("timeout".into())));
+    assert!(diag.overlay_text(0).contains("Last collect: 2024-01-01 12:00:00 (failed: timeout)\n"));
+}
+
+#[test]
+fn test_xmr_status() {
+    assert_eq!(xmr_status("ch", None, 1000), "not connected");
+    assert_eq!(xmr_status("ch", Some(0), 1000), "channel ch, no message yet");
+    assert_eq!(xmr_status("ch", Some(970), 1000), "channel ch, last message 30s ago");
+    assert_eq!(xmr_status("ch", Some(500), 1000), "channel ch, last message 500s ago (stale)");
+}
+
+#[test]
+fn test_download_failure() {
+    use anyhow::anyhow;
+    let md5 = anyhow!("md5 mismatch").context("downloading media a.mp4");
+    assert_eq!(download_failure_reason(&md5), "checksum mismatch");
+    let resp = ureq::Response::new(404, "Not Found", "").unwrap();
+    let status = anyhow::Error::new(ureq::Error::Status(404, resp)).context("downloading");
+    assert_eq!(download_failure_reason(&status), "HTTP status 404");
+    let timeout = anyhow::Error::new(std::io::Error::from(std::io::ErrorKind::TimedOut));
+    assert_eq!(download_failure_reason(&timeout), "timeout");
+
+    log::set_max_level(log::LevelFilter::Trace);
+    crate::file_error!(("media", 42), "{:#} ({})", md5, download_failure_reason(&md5));
+    let entries = logger::pop_entries();
+    let entry = entries.iter().find(|e| e.file == Some(("media", 42))).unwrap();
+    assert_eq!(entry.message, "downloading media a.mp4: md5 mismatch (checksum mismatch)");
+    assert_eq!(entry.category, "ERROR");
+}
+
+#[test]
+fn test_check_sizes() {
+    let file = |id, size| ReqFile::File { id, typ: "media", size, md5: vec![], http: true,
+                                          path: "/x".into(), name: format!("{}.mp4", id) };
+    let res = ReqFile::Resource { id: 4, layoutid: 1, regionid: 1, mediaid: 1, updated: 0 };
+    let (allowed, oversized) = check_sizes(vec![file(1, 10 << 20), file(2, 200 << 20),
+                                                file(3, 100 << 20), res], 100);
+    assert_eq!(allowed.iter().map(|f| f.inventory().1).collect::<Vec<_>>(), [1, 3, 4]);
+    assert_eq!(oversized.len(), 1);
+    assert_eq!(oversized[0].0.inventory(), ("media", 2));
+    assert!(oversized[0].1.to_string().contains("exceeds the maximum of 100 MiB"));
+    // without a limit, everything is downloaded
+    let (allowed, oversized) = check_sizes(vec![file(2, 200 << 20)], 0);
+    assert_eq!((allowed.len(), oversized.len()), (1, 0));
+}
+
+#[test]
+fn test_collect_timings() {
+    let mut timings = Timings::new();
+    timings.mark("register");
+    thread::sleep(Duration::from_millis(10));
+    timings.mark("downloads");
+    assert_eq!(timings.phases.iter().map(|p| p.0).collect::<Vec<_>>(), ["register", "downloads"]);
+    assert!(timings.phases[1].1 >= Duration::from_millis(10));
+    let text = timings.to_string();
+    assert!(text.starts_with("register 0.00s, downloads 0.0"), "{}", text);
+    assert!(text.contains("s, total 0."), "{}", text);
+}
+
+#[test]
+fn test_faults() {
+    let mut faults = Faults::default();
+    faults.set(("media", 5), None, "md5 mismatch".into());
+    faults.set(("layout", 7), None, "invalid XLF".into());
+    faults.set(("resource", 9), Some(12), "HTTP status 500".into());
+    assert!(faults.changed);
+    let report = faults.report();
+    assert_eq!(report.iter().map(|f| (f.code, f.layoutId, f.mediaId)).collect::<Vec<_>>(),
+               [(FAULT_LAYOUT, 7, 0), (FAULT_MEDIA, 0, 5), (FAULT_MEDIA, 0, 12)]);
+    let json = serde_json::to_value(&report[1]).unwrap();
+    assert_eq!(json["reason"], "md5 mismatch");
+    assert!(json["date"].is_string() && json["expires"].is_string());
+
+    // the same fault again doesn't need a new report
+    faults.changed = false;
+    faults.set(("media", 5), None, "md5 mismatch".into());
+    assert!(!faults.changed);
+    // a successful download clears the fault
+    faults.clear(("media", 5));
+    assert!(faults.changed);
+    assert_eq!(faults.report().len(), 2);
+    // as does the file not being required anymore
+    faults.changed = false;
+    faults.retain(|file| file.0 == "layout");
+    assert!(faults.changed);
+    assert_eq!(faults.report().len(), 1);
+}
+
+#[test]
+fn test_update_keeps_local_settings() {
+    let cms = CmsSettings { display_name: Some("Entrance".into()), rotation: 90,
+                            media_interstitial: 500, ..Default::default() };
+    let mut settings = PlayerSettings::default();
+    let before = layout::Options::from_settings(&settings, &cms);
+    // the CMS may send settings named like local options, they must not
+    // override the local configuration
+    let update = xmds::parse_settings(r#"<display code="READY"><displayName>Lobby</displayName>
+        <rotation>180</rotation><interstitial>0</interstitial><statsEnabled>1</statsEnabled>
+        </display>"#).unwrap().unwrap();
+    assert!(take_settings(&mut settings, update.clone()));
+    assert!(!take_settings(&mut settings, update));
+    assert_eq!(settings.display_name, "Lobby");
+    assert_eq!((cms.display_name.as_deref(), cms.rotation), (Some("Entrance"), 90));
+    let after = layout::Options::from_settings(&settings, &cms);
+    assert!(after.stats_enabled);
+    assert_eq!(after.interstitial, before.interstitial);
+    assert_eq!(after.interstitial, 500);
+}
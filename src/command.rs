@@ -0,0 +1,273 @@
+// Xibo player Rust implementation, (c) 2022-2024 Georg Brandl.
+// Licensed under the GNU AGPL, version 3 or later.
+
+//! Execution of commands on the local system.
+
+use std::{process, os::unix::process::CommandExt, thread};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use anyhow::{bail, Context, Result};
+use nix::{sys::signal::{killpg, Signal}, unistd::Pid};
+use parking_lot::Mutex;
+use regex::Regex;
+
+/// Process slots shared by all commands.
+static SLOTS: Slots = Slots::new(4);
+
+/// Running commands, to terminate them on exit.
+static CHILDREN: Children = Children::new();
+
+/// Timeout for connecting to and talking with network devices.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Set the maximum number of command processes running at the same time.
+pub fn set_max_processes(max: usize) {
+    SLOTS.max.store(max, Ordering::Relaxed);
+}
+
+/// A command line to run on the local system.
+#[derive(Debug, Clone)]
+pub struct Command {
+    command: String,
+    /// Regex the reply of a network device must match for success.
+    validation: Option<Regex>,
+}
+
+impl Command {
+    pub fn new(command: impl Into<String>) -> Self {
+        Self { command: command.into(), validation: None }
+    }
+
+    /// Check the reply of a network device against the given regex, if any.
+    pub fn validated_by(mut self, pattern: Option<&str>) -> Result<Self> {
+        self.validation = pattern.map(Regex::new).transpose().context("invalid validation regex")?;
+        Ok(self)
+    }
+
+    /// Run the command with the shell, passing the given arguments to it.
+    /// Returns whether the command exited successfully.
+    ///
+    /// Commands of the form `tcp|host:port|message` instead send the message
+    /// to a network device.  With `tcp|host:port,hex|message`, the message is
+    /// given in hex.  The device's reply is checked against the validation
+    /// regex, if one is set.
+    pub fn run(&self, args: &[String]) -> Result<bool> {
+        self.run_with_pid(args, |_| ())
+    }
+
+    /// Like `run`, but calls `started` with the process ID once the process
+    /// has been started.  Network commands have no process.
+    pub fn run_with_pid(&self, args: &[String], started: impl FnOnce(u32)) -> Result<bool> {
+        self.run_in(&CHILDREN, args, started)
+    }
+
+    fn run_in(&self, children: &Children, args: &[String], started: impl FnOnce(u32))
+              -> Result<bool> {
+        if let Some(spec) = self.command.strip_prefix("tcp|") {
+            let reply = send_tcp(spec)?;
+            log::debug!("reply to command {:?}: {:?}", self.command, reply);
+            return Ok(self.validation.as_ref().map_or(true, |re| re.is_match(&reply)));
+        }
+        let Some(_slot) = SLOTS.acquire() else {
+            log::warn!("too many commands running, not starting {:?}", self.command);
+            bail!("process limit of {} reached", SLOTS.max.load(Ordering::Relaxed));
+        };
+        log::debug!("running command: {} {:?}", self.command, args);
+        // in its own process group, so that it can be terminated together
+        // with any processes it starts
+        let mut child = process::Command::new("sh")
+            .arg("-c")
+            .arg(format!("{} \"$@\"", self.command))
+            .arg("sh")
+            .args(args)
+            .process_group(0)
+            .spawn()
+            .with_context(|| format!("running {:?}", self.command))?;
+        let pid = child.id();
+        children.pids.lock().push(pid);
+        started(pid);
+        let status = child.wait();
+        children.pids.lock().retain(|&p| p != pid);
+        Ok(status.with_context(|| format!("running {:?}", self.command))?.success())
+    }
+}
+
+/// Send a message to a network device, given as `host:port[,hex]|message`.
+/// Returns the first line of the reply, if any.
+fn send_tcp(spec: &str) -> Result<String> {
+    let (conn, message) = spec.split_once('|').context("missing message in TCP command")?;
+    let (addr, hex) = match conn.split_once(',') {
+        Some((addr, "hex")) => (addr, true),
+        Some((_, opt)) => bail!("unknown TCP command option {:?}", opt),
+        None => (conn, false),
+    };
+    let payload = if hex {
+        hex::decode(message.replace(' ', "")).context("invalid hex message")?
+    } else {
+        message.as_bytes().to_vec()
+    };
+    let addr = addr.to_socket_addrs()?.next().context("could not resolve address")?;
+    let mut stream = TcpStream::connect_timeout(&addr, TIMEOUT)
+        .with_context(|| format!("connecting to {}", addr))?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    stream.write_all(&payload)?;
+    // not all devices reply
+    let mut reply = String::new();
+    let _ = BufReader::new(stream).read_line(&mut reply);
+    Ok(reply.trim_end().into())
+}
+
+/// Terminate all running commands, with SIGTERM first and SIGKILL for those
+/// still running after the grace period.
+pub fn terminate_all(grace: Duration) {
+    CHILDREN.terminate_all(grace);
+}
+
+/// Terminate a single running command, given its process ID.
+pub fn terminate(pid: u32) {
+    if let Err(e) = killpg(Pid::from_raw(pid as i32), Signal::SIGTERM) {
+        log::warn!("could not terminate command process {}: {}", pid, e);
+    }
+}
+
+/// Process (group) IDs of running commands.
+struct Children {
+    pids: Mutex<Vec<u32>>,
+}
+
+impl Children {
+    const fn new() -> Self {
+        Self { pids: Mutex::new(Vec::new()) }
+    }
+
+    fn terminate_all(&self, grace: Duration) {
+        if self.pids.lock().is_empty() {
+            return;
+        }
+        log::info!("terminating running commands");
+        self.signal(Signal::SIGTERM);
+        let deadline = Instant::now() + grace;
+        while !self.pids.lock().is_empty() {
+            if Instant::now() >= deadline {
+                log::warn!("commands did not exit, killing them");
+                self.signal(Signal::SIGKILL);
+                return;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    fn signal(&self, signal: Signal) {
+        for &pid in self.pids.lock().iter() {
+            if let Err(e) = killpg(Pid::from_raw(pid as i32), signal) {
+                log::warn!("could not signal command process {}: {}", pid, e);
+            }
+        }
+    }
+}
+
+/// Limits the number of processes that run at the same time.
+struct Slots {
+    running: AtomicUsize,
+    max: AtomicUsize,
+}
+
+/// A taken process slot, which is given back when dropped.
+struct Slot<'a>(&'a Slots);
+
+impl Slots {
+    const fn new(max: usize) -> Self {
+        Self { running: AtomicUsize::new(0), max: AtomicUsize::new(max) }
+    }
+
+    /// Take a slot, if one is free.
+    fn acquire(&self) -> Option<Slot<'_>> {
+        let max = self.max.load(Ordering::Relaxed);
+        self.running.fetch_update(Ordering::AcqRel, Ordering::Acquire,
+                                  |n| (n < max).then_some(n + 1)).ok()?;
+        Some(Slot(self))
+    }
+}
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        self.0.running.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+#[test]
+fn test_process_slots() {
+    let slots = Slots::new(2);
+    let first = slots.acquire().unwrap();
+    let _second = slots.acquire().unwrap();
+    // beyond the limit, no more processes may be started
+    assert!(slots.acquire().is_none());
+    drop(first);
+    assert!(slots.acquire().is_some());
+    // the limit can be changed at runtime
+    slots.max.store(3, Ordering::Relaxed);
+    let _third = slots.acquire().unwrap();
+    let _fourth = slots.acquire().unwrap();
+    assert!(slots.acquire().is_none());
+}
+
+#[test]
+fn test_terminate_all() {
+    // with its own list, so that commands of other tests are not affected
+    let children = Children::new();
+    let wait_started = || while children.pids.lock().is_empty() {
+        thread::sleep(Duration::from_millis(10));
+    };
+    thread::scope(|scope| {
+        let start = Instant::now();
+        let cmd = scope.spawn(|| Command::new("sleep 30; true").run_in(&children, &[], |_| ()));
+        wait_started();
+        children.terminate_all(Duration::from_secs(5));
+        // the command was ended by the signal
+        assert!(!cmd.join().unwrap().unwrap());
+        assert!(children.pids.lock().is_empty());
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        // commands ignoring SIGTERM are killed after the grace period
+        let cmd = scope.spawn(|| Command::new("trap '' TERM; sleep 30; true")
+                              .run_in(&children, &[], |_| ()));
+        wait_started();
+        thread::sleep(Duration::from_millis(100));
+        children.terminate_all(Duration::from_millis(200));
+        assert!(!cmd.join().unwrap().unwrap());
+    });
+}
+
+#[test]
+fn test_tcp_command() {
+    use std::io::Read;
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let device = thread::spawn(move || {
+        let mut received = Vec::new();
+        for _ in 0..4 {
+            let (mut conn, _) = listener.accept().unwrap();
+            let mut buf = [0; 16];
+            let n = conn.read(&mut buf).unwrap();
+            received.push(buf[..n].to_vec());
+            conn.write_all(b"OK\r\n").unwrap();
+        }
+        received
+    });
+    assert_eq!(send_tcp(&format!("127.0.0.1:{}|PWR ON", port)).unwrap(), "OK");
+    assert!(Command::new(format!("tcp|127.0.0.1:{},hex|02 50 4f 03", port)).run(&[]).unwrap());
+    // the reply is checked against the validation regex
+    let validated = |pattern| Command::new(format!("tcp|127.0.0.1:{}|PWR?", port))
+        .validated_by(Some(pattern)).unwrap().run(&[]).unwrap();
+    assert!(validated("^OK$"));
+    assert!(!validated("^ERR"));
+    assert_eq!(device.join().unwrap(), [b"PWR ON".to_vec(), b"\x02PO\x03".to_vec(),
+                                        b"PWR?".to_vec(), b"PWR?".to_vec()]);
+    assert!(Command::new("tcp|x").validated_by(Some("(")).is_err());
+
+    assert!(send_tcp("127.0.0.1:1").is_err());
+    assert!(send_tcp("127.0.0.1:1,bin|x").is_err());
+}
@@ -4,27 +4,73 @@
 //! The GUI window.
 
 use std::convert::TryFrom;
-use std::{cell::RefCell, io::Cursor, rc::Rc, sync::Arc};
-use anyhow::{anyhow, Result};
+use std::{cell::Cell, cell::RefCell, io::Cursor, rc::Rc, sync::Arc};
+use std::time::{Duration, Instant};
+use anyhow::{anyhow, Context, Result};
 use cairo::{ImageSurface, Surface};
 use crossbeam_channel::Sender;
 use glib::{clone, prelude::*};
 use gdk_pixbuf::Pixbuf;
 use gtk::{prelude::*, Fixed, Inhibit, Window, WindowType};
+use nix::sys::signal::Signal;
 use webkit2gtk::{WebContext, WebView, UserContentManager, SnapshotRegion, SnapshotOptions,
-                 JavascriptResult};
+                 JavascriptResult, LoadEvent, UserScript, UserContentInjectedFrames,
+                 UserScriptInjectionTime, UserStyleSheet, UserStyleLevel};
 use webkit2gtk::traits::{UserContentManagerExt, SettingsExt, WebViewExt, WebInspectorExt};
-use crate::collect::{FromGui, ToGui};
-use crate::config::PlayerSettings;
+use crate::collect::{Diagnostics, FromGui, ToGui};
+use crate::config::{CmsSettings, ConsoleLog, PlayerSettings, ScreenshotFormat};
 use crate::resource::LayoutInfo;
+use crate::util;
 
 const LOGO_PNG: &[u8] = include_bytes!("../assets/logo.png");
 
+/// Signals on which the main loop is quit.
+pub const QUIT_SIGNALS: [Signal; 2] = [Signal::SIGTERM, Signal::SIGINT];
 
-pub fn run(settings: PlayerSettings, inspect: bool,
+/// Forwards console messages and uncaught errors to the player.
+const CONSOLE_SCRIPT: &str = r#"
+(function() {
+  function post(level, args) {
+    var msg = Array.prototype.map.call(args, String).join(' ');
+    try { window.webkit.messageHandlers.xibo.postMessage('console:' + level + ':' + msg); }
+    catch (e) {}
+  }
+  ['error', 'warn', 'info', 'log'].forEach(function(level) {
+    var orig = console[level];
+    console[level] = function() { post(level, arguments); orig.apply(console, arguments); };
+  });
+  window.addEventListener('error', function(e) {
+    post('error', [e.message + ' (' + e.filename + ':' + e.lineno + ')']);
+  });
+})();
+"#;
+
+
+/// Initialize GTK.  Returns false if that failed, but running without GUI
+/// is allowed.
+pub fn init(headless_fallback: bool) -> Result<bool> {
+    init_fallback(gtk::init(), headless_fallback)
+}
+
+fn init_fallback(result: Result<(), glib::BoolError>, headless_fallback: bool) -> Result<bool> {
+    let Err(e) = result else { return Ok(true) };
+    let hint = "check that DISPLAY or WAYLAND_DISPLAY is set and the display server is \
+                accessible, or select a different backend with --backend";
+    if headless_fallback {
+        log::error!("could not initialize GUI: {} ({})", e, hint);
+        log::warn!("continuing without GUI, no content will be shown");
+        Ok(false)
+    } else {
+        Err(anyhow!("{} ({})", e, hint)).context("could not initialize GUI")
+    }
+}
+
+pub fn run(settings: PlayerSettings, cms: &CmsSettings, inspect: bool,
            to_gui: glib::Receiver<ToGui>, from_gui: Sender<FromGui>) -> Result<()> {
-    gtk::init().expect("failed to init gtk");
     let base_uri = format!("http://localhost:{}/", settings.embedded_server_port);
+    log::info!("loading content from {}", base_uri);
+    let splash_min = Duration::from_secs(cms.splash_min_duration);
+    let splash_max = Duration::from_secs(cms.splash_max_duration);
 
     let logo = Pixbuf::from_read(Cursor::new(LOGO_PNG))?;
 
@@ -34,6 +80,16 @@ pub fn run(settings: PlayerSettings, inspect: bool,
     let context = WebContext::default().unwrap();
     let manager = UserContentManager::new();
     manager.register_script_message_handler("xibo");
+    let console_log = cms.console_log;
+    let screenshot_format = (cms.screenshot_format, cms.screenshot_quality);
+    let skip_broken_layouts = cms.skip_broken_layouts;
+    // rotation of the content in degrees
+    let rotation = cms.rotation;
+    add_rotation_style(&manager, rotation);
+    if console_log != ConsoleLog::Off {
+        manager.add_script(&UserScript::new(CONSOLE_SCRIPT, UserContentInjectedFrames::AllFrames,
+                                            UserScriptInjectionTime::Start, &[], &[]));
+    }
     let webview = WebView::builder()
         .web_context(&context)
         .user_content_manager(&manager)
@@ -47,6 +103,10 @@ pub fn run(settings: PlayerSettings, inspect: bool,
     }
 
     webview.load_uri(&format!("{}0.xlf.html", base_uri));
+    // keep track of since when the splash screen is shown
+    let splash_since = Rc::new(Cell::new(Some(Instant::now())));
+    let splash_pending = Rc::new(Cell::new(false));
+    blank_splash_after(splash_max, &splash_since, &webview);
     container.put(&webview, 0, 0);
     window.add(&container);
     window.set_app_paintable(true);
@@ -54,7 +114,7 @@ pub fn run(settings: PlayerSettings, inspect: bool,
     window.set_title(&settings.display_name);
     window.set_icon(Some(&logo));
     apply_size(&window, settings);
-    apply_scale((0, 0), &window, &container, &webview);
+    apply_scale((0, 0), rotation, &window, &container, &webview);
     window.show_all();
 
     if let Some(gdkwin) = window.window() {
@@ -68,8 +128,72 @@ pub fn run(settings: PlayerSettings, inspect: bool,
         gtk::main_quit();
         Inhibit(false)
     });
+    // quit cleanly on signals, so that running commands can be terminated
+    for signal in QUIT_SIGNALS {
+        glib::unix_signal_add_local(signal as i32, || {
+            gtk::main_quit();
+            glib::Continue(false)
+        });
+    }
 
     let schedule = Rc::new(RefCell::new(Schedule::<Arc<LayoutInfo>>::default()));
+    // counts layout changes on request, to only revert the latest one
+    let change_count = Rc::new(Cell::new(0u64));
+    // overlay layouts, shown in their own webviews on top of the layout
+    let overlays = Rc::new(RefCell::new(Vec::<(Arc<LayoutInfo>, WebView)>::new()));
+
+    // diagnostics overlay, toggled with Ctrl+Shift+D
+    let diag_shown = Rc::new(Cell::new(false));
+    let diag_info = Rc::new(RefCell::new(Diagnostics::default()));
+    let diag_text = clone!(@strong schedule, @strong diag_info => move || {
+        diag_info.borrow().overlay_text(schedule.borrow().current().id)
+    });
+    window.connect_key_press_event(clone!(
+        @strong diag_shown, @strong diag_text, @weak webview => @default-return Inhibit(false),
+        move |_, event| {
+            let ctrl_shift = gdk::ModifierType::CONTROL_MASK | gdk::ModifierType::SHIFT_MASK;
+            if event.state().contains(ctrl_shift) &&
+                (event.keyval() == gdk::keys::constants::D || event.keyval() == gdk::keys::constants::d)
+            {
+                diag_shown.set(!diag_shown.get());
+                show_diagnostics(&webview, diag_shown.get().then(&diag_text));
+                return Inhibit(true);
+            }
+            Inhibit(false)
+        }
+    ));
+    // whether playback is paused on request
+    let paused = Rc::new(Cell::new(false));
+    // readiness is signaled once, when the first layout has loaded
+    let ready_file = cms.ready_file.clone();
+    let ready = Rc::new(Cell::new(false));
+
+    // the overlay and pause state need to be applied again when a new page is loaded
+    webview.connect_load_changed(clone!(
+        @strong diag_shown, @strong diag_text, @strong paused,
+        @strong overlays => move |webview, event| {
+            if event == LoadEvent::Started {
+                // overlay layouts are hidden while a status screen is shown
+                let visible = !webview.uri().map_or(false, |uri| is_status_uri(&uri));
+                for (_, view) in overlays.borrow().iter() {
+                    view.set_visible(visible);
+                }
+            }
+            if event == LoadEvent::Finished {
+                if !ready.get() && webview.uri().map_or(false, |uri| is_layout_uri(&uri)) {
+                    log::info!("first layout shown, player is ready");
+                    ready.set(true);
+                    util::notify_ready(ready_file.as_deref());
+                }
+                if diag_shown.get() {
+                    show_diagnostics(webview, Some(diag_text()));
+                }
+                if paused.get() {
+                    run_js(webview, "arexibo_pause();");
+                }
+            }
+        }
+    ));
 
     // handler for events from the webview content
     let from_gui_2 = from_gui.clone();
@@ -82,18 +206,67 @@ pub fn run(settings: PlayerSettings, inspect: bool,
                     // layout has run through, need to change layouts?
                     if let Some(info) = schedule.borrow_mut().next() {
                         log::info!("showing next layout: {}", info.id);
-                        apply_scale(info.size, &window, &container, &webview);
-                        webview.load_uri(&format!("{}{}.xlf.html", base_uri, info.id));
+                        show_layout(&info, &base_uri, rotation, &window, &container, &webview);
                         from_gui_2.send(FromGui::Showing(info.id)).unwrap();
                     } else {
                         // TODO: record that the layout is done so that we
                         // can switch to the next one on update.
                     }
+                } else if let Some(error) = request.strip_prefix("layout_error:") {
+                    let current = schedule.borrow().current();
+                    log::error!("layout {} failed to start: {}", current.id, error);
+                    if skip_broken_layouts && current.id != 0 {
+                        let info = match schedule.borrow_mut().next() {
+                            Some(info) => {
+                                log::info!("skipping to next layout: {}", info.id);
+                                info
+                            }
+                            None => {
+                                // nothing else scheduled, don't retry the broken layout
+                                log::info!("no other layout to skip to, showing the splash screen");
+                                Default::default()
+                            }
+                        };
+                        show_layout(&info, &base_uri, rotation, &window, &container, &webview);
+                        from_gui_2.send(FromGui::Showing(info.id)).unwrap();
+                    }
+                } else if let Some(code) = request.strip_prefix("layout:") {
+                    // the translator has resolved the layout code to an ID
+                    let id = code.parse::<i64>().ok();
+                    if id == Some(schedule.borrow().current().id) {
+                        // reloading would restart the layout, possibly in a loop
+                        log::info!("action requested the current layout, ignoring");
+                    } else if let Some(info) = id.and_then(
+                        |id| schedule.borrow_mut().jump(|info| info.id == id)
+                    ) {
+                        log::info!("action requested layout: {}", info.id);
+                        show_layout(&info, &base_uri, rotation, &window, &container, &webview);
+                        from_gui_2.send(FromGui::Showing(info.id)).unwrap();
+                    } else {
+                        log::warn!("action requested layout {:?}, which is not scheduled", code);
+                    }
+                } else if let Some(error) = request.strip_prefix("media_error:") {
+                    let (code, src) = error.split_once(':').unwrap_or(("0", error));
+                    log::error!("{}", media_error_message(code.parse().unwrap_or(0), src));
+                } else if let Some(msg) = request.strip_prefix("console:") {
+                    let (level, msg) = msg.split_once(':').unwrap_or(("log", msg));
+                    if let Some(level) = console_level(level, console_log) {
+                        log::log!(level, "layout console: {}", msg);
+                    }
+                } else if let Some(src) = request.strip_prefix("autoplay_muted:") {
+                    log::warn!("playing {} with sound was refused, playing it muted", src);
+                } else if let Some(stat) = request.strip_prefix("stat:") {
+                    let (what, mid) = stat.split_once(':').unwrap_or(("", stat));
+                    if let Ok(mid) = mid.parse() {
+                        from_gui_2.send(FromGui::StatEvent(what == "start", mid)).unwrap();
+                    }
+                } else if let Some(code) = request.strip_prefix("command:") {
+                    from_gui_2.send(FromGui::Command(code.into())).unwrap();
                 } else if let Some(play_id) = request.strip_prefix("play:") {
                     // request to start a non-muted video which needs to come
                     // from outside the webview...
                     webview.run_javascript(
-                        &format!("document.getElementById('m{}').play();", play_id),
+                        &format!("media_play({});", play_id),
                         None::<&gio::Cancellable>, |_| ());
                 }
             }
@@ -103,7 +276,7 @@ pub fn run(settings: PlayerSettings, inspect: bool,
 
     // handler for events from the collect backend
     to_gui.attach(None, clone!(
-        @weak webview, @weak window, @weak container => @default-return Continue(true),
+        @weak webview, @weak window, @weak container, @weak context => @default-return Continue(true),
         move |update| {
             match update {
                 ToGui::Screenshot => {
@@ -112,7 +285,7 @@ pub fn run(settings: PlayerSettings, inspect: bool,
                         SnapshotRegion::Visible,
                         SnapshotOptions::NONE,
                         None::<&gio::Cancellable>,
-                        move |result| match convert_shot(result) {
+                        move |result| match convert_shot(result, screenshot_format) {
                             Ok(data) => channel.send(FromGui::Screenshot(data)).unwrap(),
                             Err(e) => log::warn!("could not create snapshot: {:#}", e),
                         });
@@ -120,15 +293,123 @@ pub fn run(settings: PlayerSettings, inspect: bool,
                 ToGui::Settings(settings) => {
                     window.set_title(&settings.display_name);
                     apply_size(&window, settings);
-                    apply_scale(schedule.borrow().current().size,
+                    apply_scale(schedule.borrow().current().size, rotation,
                                 &window, &container, &webview);
+                    for (info, view) in overlays.borrow().iter() {
+                        apply_scale(info.size, rotation, &window, &container, view);
+                    }
+                }
+                ToGui::Diagnostics(diag) => {
+                    *diag_info.borrow_mut() = diag;
+                    if diag_shown.get() {
+                        show_diagnostics(&webview, Some(diag_text()));
+                    }
+                }
+                ToGui::ReloadResource(mid) => {
+                    // the page might not be a layout
+                    run_js(&webview, &format!("window.reload_resource && reload_resource({});", mid));
+                }
+                ToGui::Pause(pause) => {
+                    if paused.replace(pause) != pause {
+                        log::info!("{} playback", if pause { "pausing" } else { "resuming" });
+                        // resuming from outside the page allows playing non-muted videos
+                        run_js(&webview, if pause { "arexibo_pause();" } else { "arexibo_resume();" });
+                        for (_, view) in overlays.borrow().iter() {
+                            run_js(view, if pause { "arexibo_pause();" } else { "arexibo_resume();" });
+                        }
+                    }
+                }
+                ToGui::Unauthorized => {
+                    // show how to authorize instead of the splash screen
+                    splash_since.set(None);
+                    webview.load_uri(&format!("{}register.html", base_uri));
+                }
+                ToGui::Unreachable => {
+                    splash_since.set(None);
+                    webview.load_uri(&format!("{}fallback.html", base_uri));
+                }
+                ToGui::ChangeLayout(info, duration) => {
+                    schedule.borrow_mut().interrupt(info.clone());
+                    splash_since.set(None);
+                    show_layout(&info, &base_uri, rotation, &window, &container, &webview);
+                    from_gui.send(FromGui::Showing(info.id)).unwrap();
+                    let count = change_count.get() + 1;
+                    change_count.set(count);
+                    if duration > 0 {
+                        let from_gui = from_gui.clone();
+                        glib::timeout_add_local_once(Duration::from_secs(duration), clone!(
+                            @strong schedule, @strong change_count, @strong base_uri,
+                            @weak webview, @weak window, @weak container => move || {
+                                if change_count.get() != count {
+                                    return;
+                                }
+                                if let Some(info) = schedule.borrow_mut().revert() {
+                                    log::info!("requested layout done, showing layout: {}", info.id);
+                                    show_layout(&info, &base_uri, rotation, &window,
+                                                &container, &webview);
+                                    from_gui.send(FromGui::Showing(info.id)).unwrap();
+                                }
+                            }));
+                    }
+                }
+                ToGui::RevertToSchedule => {
+                    // nothing to do if no layout was requested
+                    if let Some(info) = schedule.borrow_mut().revert() {
+                        change_count.set(change_count.get() + 1);
+                        log::info!("back to schedule, showing layout: {}", info.id);
+                        show_layout(&info, &base_uri, rotation, &window, &container, &webview);
+                        from_gui.send(FromGui::Showing(info.id)).unwrap();
+                    }
+                }
+                ToGui::WebHook(code) => {
+                    let js = format!("window.arexibo_trigger && arexibo_trigger({});",
+                                     serde_json::Value::from(code));
+                    run_js(&webview, &js);
+                    for (_, view) in overlays.borrow().iter() {
+                        run_js(view, &js);
+                    }
+                }
+                ToGui::Overlays(new_overlays) => {
+                    let visible = !webview.uri().map_or(false, |uri| is_status_uri(&uri));
+                    update_overlays(&mut overlays.borrow_mut(), new_overlays, visible, &context,
+                                    &base_uri, rotation, &window, &container);
                 }
                 ToGui::Layouts(new_layouts) => {
-                    if let Some(info) = schedule.borrow_mut().update(new_layouts) {
+                    let Some(info) = schedule.borrow_mut().update(new_layouts) else {
+                        return Continue(true);
+                    };
+                    let wait = splash_since.get()
+                                           .and_then(|since| splash_wait(since.elapsed(), splash_min));
+                    if let (Some(wait), true) = (wait, info.id != 0) {
+                        // keep showing the splash screen a bit longer to avoid flicker,
+                        // then show whatever layout is current at that time
+                        if !splash_pending.replace(true) {
+                            let from_gui = from_gui.clone();
+                            glib::timeout_add_local_once(wait, clone!(
+                                @strong schedule, @strong splash_since, @strong splash_pending,
+                                @strong base_uri, @weak webview, @weak window, @weak container =>
+                                move || {
+                                    splash_pending.set(false);
+                                    let info = schedule.borrow().current();
+                                    if info.id != 0 {
+                                        log::info!("splash done, showing layout: {}", info.id);
+                                        splash_since.set(None);
+                                        show_layout(&info, &base_uri, rotation, &window,
+                                                    &container, &webview);
+                                        from_gui.send(FromGui::Showing(info.id)).unwrap();
+                                    }
+                                }));
+                        }
+                    } else {
                         log::info!("new schedule, showing layout: {}", info.id);
-                        apply_scale(info.size, &window, &container, &webview);
-                        webview.load_uri(&format!("{}{}.xlf.html", base_uri, info.id));
+                        show_layout(&info, &base_uri, rotation, &window, &container, &webview);
                         from_gui.send(FromGui::Showing(info.id)).unwrap();
+                        if info.id == 0 {
+                            splash_since.set(Some(Instant::now()));
+                            blank_splash_after(splash_max, &splash_since, &webview);
+                        } else {
+                            splash_since.set(None);
+                        }
                     }
                 }
             }
@@ -141,6 +422,166 @@ pub fn run(settings: PlayerSettings, inspect: bool,
     Ok(())
 }
 
+fn show_layout(info: &LayoutInfo, base_uri: &str, rotation: u16, window: &Window,
+               container: &Fixed, webview: &WebView) {
+    apply_scale(info.size, rotation, window, container, webview);
+    webview.load_uri(&format!("{}{}.xlf.html", base_uri, info.id));
+}
+
+/// Show the given overlay layouts, stacked in the given order, keeping those
+/// that are already shown running undisturbed.  `visible` is false while a
+/// status screen is shown instead of content.
+fn update_overlays(current: &mut Vec<(Arc<LayoutInfo>, WebView)>, new: Vec<Arc<LayoutInfo>>,
+                   visible: bool, context: &WebContext, base_uri: &str, rotation: u16,
+                   window: &Window, container: &Fixed) {
+    let mut old = std::mem::take(current);
+    for info in new {
+        let view = match old.iter().position(|(shown, _)| *shown == info) {
+            Some(index) => old.remove(index).1,
+            None => {
+                log::info!("showing overlay layout: {}", info.id);
+                let view = create_overlay(context, &info, base_uri, rotation);
+                view.set_visible(visible);
+                view
+            }
+        };
+        current.push((info, view));
+    }
+    for (info, view) in old {
+        log::info!("removing overlay layout: {}", info.id);
+        container.remove(&view);
+    }
+    // the container stacks its children in the order they were added, so
+    // from the first overlay out of place on, all are added again
+    let stacked = container.children();
+    let stacked = stacked.iter().filter(|child| current.iter().any(|(_, view)| view == *child));
+    let in_place = stacked.zip(current.iter())
+                          .take_while(|(child, (_, view))| view == *child).count();
+    for (info, view) in current.iter().skip(in_place) {
+        if view.parent().is_some() {
+            container.remove(view);
+        }
+        container.put(view, 0, 0);
+        apply_scale(info.size, rotation, window, container, view);
+    }
+}
+
+/// Create a transparent webview to show an overlay layout.  It doesn't
+/// receive input, so that the layout below stays interactive.
+fn create_overlay(context: &WebContext, info: &LayoutInfo, base_uri: &str,
+                  rotation: u16) -> WebView {
+    let manager = UserContentManager::new();
+    manager.register_script_message_handler("xibo");
+    manager.add_style_sheet(&UserStyleSheet::new(
+        "body { background: transparent !important; }", UserContentInjectedFrames::TopFrame,
+        UserStyleLevel::User, &[], &[]));
+    add_rotation_style(&manager, rotation);
+    let view = WebView::builder()
+        .web_context(context)
+        .user_content_manager(&manager)
+        .build();
+    view.set_background_color(&gdk::RGBA::new(0., 0., 0., 0.));
+    // overlays run independently of the schedule, and just start over when done
+    manager.connect_local("script-message-received::xibo", false, clone!(
+        @weak view => @default-return None,
+        move |args| {
+            let request = extract_js_string(args.get(1)).unwrap_or_default();
+            if request == "layout_done" {
+                view.reload();
+            } else if let Some(play_id) = request.strip_prefix("play:") {
+                run_js(&view, &format!("media_play({});", play_id));
+            }
+            None
+        }
+    ));
+    view.connect_realize(|view| {
+        if let Some(gdkwin) = view.window() {
+            gdkwin.input_shape_combine_region(&cairo::Region::create(), 0, 0);
+        }
+    });
+    view.load_uri(&format!("{}{}.xlf.html", base_uri, info.id));
+    view
+}
+
+/// Determine the log level for a console message, if it should be logged.
+fn console_level(level: &str, config: ConsoleLog) -> Option<log::Level> {
+    let (level, needed) = match level {
+        "error" => (log::Level::Warn, ConsoleLog::Error),
+        "warn" => (log::Level::Warn, ConsoleLog::Warn),
+        _ => (log::Level::Debug, ConsoleLog::All),
+    };
+    (config >= needed).then_some(level)
+}
+
+/// Check if the URI is a real layout, not the splash or waiting screen.
+fn is_layout_uri(uri: &str) -> bool {
+    let name = uri.rsplit('/').next().unwrap_or("");
+    name.ends_with(".xlf.html") && name != "0.xlf.html"
+}
+
+/// Check if the URI is a status screen shown instead of content, i.e. the
+/// authorization or fallback screen.
+fn is_status_uri(uri: &str) -> bool {
+    matches!(uri.rsplit('/').next(), Some("register.html" | "fallback.html"))
+}
+
+/// Show or update the diagnostics overlay with the given text, or remove it.
+fn show_diagnostics(webview: &WebView, text: Option<String>) {
+    run_js(webview, &diagnostics_js(text.as_deref()));
+}
+
+fn run_js(webview: &WebView, js: &str) {
+    webview.run_javascript(js, None::<&gio::Cancellable>, |_| ());
+}
+
+/// Create the JS that shows or removes the diagnostics overlay.  It doesn't
+/// react to input, so that it doesn't interfere with the content.
+fn diagnostics_js(text: Option<&str>) -> String {
+    let Some(text) = text else {
+        return "var d = document.getElementById('arexibo-diag'); if (d) d.remove();".into();
+    };
+    format!("var d = document.getElementById('arexibo-diag'); if (!d) {{ \
+             d = document.createElement('pre'); d.id = 'arexibo-diag'; \
+             d.style.cssText = 'position: fixed; top: 0; left: 0; z-index: 100000; margin: 0; \
+             padding: 1em; background: rgba(0, 0, 0, 0.7); color: #fff; font-size: 16px; \
+             pointer-events: none;'; document.body.appendChild(d); }} d.textContent = {};",
+            serde_json::to_string(text).unwrap())
+}
+
+/// Return how much longer the splash screen needs to be shown, if at all,
+/// before a layout may replace it.
+fn splash_wait(shown: Duration, min: Duration) -> Option<Duration> {
+    min.checked_sub(shown).filter(|wait| !wait.is_zero())
+}
+
+/// Blank the splash screen after the given maximum duration, if it is
+/// still shown at that time.
+fn blank_splash_after(max: Duration, splash_since: &Rc<Cell<Option<Instant>>>,
+                      webview: &WebView) {
+    let Some(since) = splash_since.get() else { return };
+    if max.is_zero() {
+        return;
+    }
+    let splash_since = splash_since.clone();
+    let webview = webview.downgrade();
+    glib::timeout_add_local_once(max, move || {
+        if let (Some(webview), true) = (webview.upgrade(), splash_since.get() == Some(since)) {
+            log::info!("still no layout to show, blanking splash screen");
+            webview.load_uri("about:blank");
+        }
+    });
+}
+
+/// Create a log message for a media playback error reported by the webview.
+fn media_error_message(code: u32, src: &str) -> String {
+    match code {
+        // MEDIA_ERR_DECODE, MEDIA_ERR_SRC_NOT_SUPPORTED
+        3 | 4 => format!("missing codec for {}, install gstreamer plugins \
+                          (e.g. gst-libav) to play it", src),
+        _ => format!("could not play {} (media error {})", src, code),
+    }
+}
+
 fn extract_js_string(arg: Option<&glib::Value>) -> Option<String> {
     Some(arg?.get::<JavascriptResult>().ok()?.js_value()?.to_string())
 }
@@ -169,43 +610,87 @@ fn apply_size(window: &Window, settings: PlayerSettings) {
     }
 }
 
-fn apply_scale(size: (i32, i32), window: &Window, container: &Fixed, webview: &WebView) {
-    let (window_w, window_h) = window.size_request();
-    let (mut layout_w, mut layout_h) = size;
-    // the easy case: direct match
-    if window_w == layout_w && window_h == layout_h {
-        container.move_(webview, 0, 0);
-        webview.set_size_request(layout_w, layout_h);
-        webview.set_zoom_level(1.0);
-        return;
+/// Add a style sheet that rotates the pages, if configured.
+fn add_rotation_style(manager: &UserContentManager, rotation: u16) {
+    if let Some(css) = rotation_css(rotation) {
+        manager.add_style_sheet(&UserStyleSheet::new(
+            css, UserContentInjectedFrames::TopFrame, UserStyleLevel::User, &[], &[]));
     }
-    // nothing specified for the layout (e.g. splash)
-    if layout_w == 0 || layout_h == 0 {
-        layout_w = 1920;
-        layout_h = 1080;
+}
+
+/// CSS to rotate the whole page clockwise by the given angle.  For 90 and
+/// 270 degrees, the page gets the swapped viewport size before rotating.
+fn rotation_css(rotation: u16) -> Option<&'static str> {
+    match rotation {
+        90 => Some("html { width: 100vh !important; height: 100vw !important; overflow: hidden; \
+                    transform-origin: 0 0; transform: rotate(90deg) translateY(-100%); }"),
+        180 => Some("html { width: 100vw !important; height: 100vh !important; overflow: hidden; \
+                     transform: rotate(180deg); }"),
+        270 => Some("html { width: 100vh !important; height: 100vw !important; overflow: hidden; \
+                     transform-origin: 0 0; transform: rotate(270deg) translateX(-100%); }"),
+        _ => None,
+    }
+}
+
+fn apply_scale(size: (i32, i32), rotation: u16, window: &Window, container: &Fixed,
+               webview: &WebView) {
+    let (x, y, w, h, zoom) = rotated_geometry(window.size_request(), size, rotation);
+    container.move_(webview, x, y);
+    webview.set_size_request(w, h);
+    webview.set_zoom_level(zoom);
+}
+
+/// Determine position, size and zoom level of the webview to show a layout
+/// of the given size in the window.
+fn scaled_geometry((window_w, window_h): (i32, i32),
+                   (layout_w, layout_h): (i32, i32)) -> (i32, i32, i32, i32, f64) {
+    // nothing specified for the layout (e.g. splash): fill the window
+    if layout_w <= 0 || layout_h <= 0 || (window_w == layout_w && window_h == layout_h) {
+        return (0, 0, window_w, window_h, 1.0);
     }
     let window_aspect = (window_w as f64) / (window_h as f64);
     let layout_aspect = (layout_w as f64) / (layout_h as f64);
     if window_aspect > layout_aspect {
         let scale_factor = (window_h as f64) / (layout_h as f64);
         let webview_w = (layout_w as f64 * scale_factor).round() as i32;
-        container.move_(webview, (window_w - webview_w) / 2, 0);
-        webview.set_size_request(webview_w, window_h);
-        webview.set_zoom_level(scale_factor);
+        ((window_w - webview_w) / 2, 0, webview_w, window_h, scale_factor)
     } else {
         let scale_factor = (window_w as f64) / (layout_w as f64);
         let webview_h = (layout_h as f64 * scale_factor).round() as i32;
-        container.move_(webview, 0, (window_h - webview_h) / 2);
-        webview.set_size_request(window_w, webview_h);
-        webview.set_zoom_level(scale_factor);
+        (0, (window_h - webview_h) / 2, window_w, webview_h, scale_factor)
+    }
+}
+
+/// Like `scaled_geometry`, but for content rotated by the given angle: the
+/// layout is fitted into the rotated window.
+fn rotated_geometry((window_w, window_h): (i32, i32), size: (i32, i32),
+                    rotation: u16) -> (i32, i32, i32, i32, f64) {
+    if rotation % 180 == 90 {
+        let (y, x, h, w, zoom) = scaled_geometry((window_h, window_w), size);
+        (x, y, w, h, zoom)
+    } else {
+        scaled_geometry((window_w, window_h), size)
     }
 }
 
-fn convert_shot(surface_result: std::result::Result<Surface, glib::Error>) -> Result<Vec<u8>> {
+fn convert_shot(surface_result: std::result::Result<Surface, glib::Error>,
+                (format, quality): (ScreenshotFormat, u8)) -> Result<Vec<u8>> {
     let img = ImageSurface::try_from(surface_result?)
         .map_err(|_| anyhow!("could not convert surface"))?;
+    encode_shot(&img, format, quality)
+}
+
+fn encode_shot(img: &ImageSurface, format: ScreenshotFormat, quality: u8) -> Result<Vec<u8>> {
     let mut vec = Vec::new();
-    img.write_to_png(&mut vec)?;
+    match format {
+        ScreenshotFormat::Png => img.write_to_png(&mut vec)?,
+        ScreenshotFormat::Jpeg => {
+            let pixbuf = gdk::pixbuf_get_from_surface(img, 0, 0, img.width(), img.height())
+                .context("could not convert surface")?;
+            let quality = quality.clamp(1, 100).to_string();
+            vec = pixbuf.save_to_bufferv("jpeg", &[("quality", &quality)])?;
+        }
+    }
     Ok(vec)
 }
 
@@ -215,19 +700,25 @@ fn convert_shot(surface_result: std::result::Result<Surface, glib::Error>) -> Re
 struct Schedule<T> {
     index: Option<usize>,
     layouts: Vec<T>,
+    /// Layout shown instead of the schedule on request.
+    interrupt: Option<T>,
 }
 
 impl<T: Eq + Default + Clone> Schedule<T> {
     /// Update the scheduled layouts and return Some(id) if we need to change
     fn update(&mut self, new: Vec<T>) -> Option<T> {
-        // determine the currently shown layout
-        let cur_t = self.current();
+        // determine the currently scheduled layout
+        let cur_t = self.scheduled();
         self.layouts = new;
 
         // if this layout is also in the new schedule, keep it
         if let Some(new_index) = self.layouts.iter().position(|t| t == &cur_t) {
             self.index = Some(new_index);
             None
+        } else if self.interrupt.is_some() {
+            // the schedule continues once the interrupting layout is done
+            self.index = (!self.layouts.is_empty()).then_some(0);
+            None
         } else if !self.layouts.is_empty() {
             // otherwise, start showing the first of the new layouts if we have some
             self.index = Some(0);
@@ -243,7 +734,7 @@ impl<T: Eq + Default + Clone> Schedule<T> {
     fn next(&mut self) -> Option<T> {
         let nlayouts = self.layouts.len();
         // if there is no layout or only one scheduled, no change
-        if nlayouts < 2 {
+        if nlayouts < 2 || self.interrupt.is_some() {
             None
         } else {
             // otherwise just go further in the schedule
@@ -253,8 +744,31 @@ impl<T: Eq + Default + Clone> Schedule<T> {
         }
     }
 
+    /// Go to the first scheduled layout matching the predicate, if any.
+    fn jump(&mut self, pred: impl Fn(&T) -> bool) -> Option<T> {
+        let new_index = self.layouts.iter().position(pred)?;
+        self.index = Some(new_index);
+        self.interrupt = None;
+        Some(self.layouts[new_index].clone())
+    }
+
+    /// Show the given layout instead of the schedule, until reverted.
+    fn interrupt(&mut self, layout: T) {
+        self.interrupt = Some(layout);
+    }
+
+    /// Go back to the schedule, returning Some(id) if a layout was interrupting.
+    fn revert(&mut self) -> Option<T> {
+        self.interrupt.take().map(|_| self.scheduled())
+    }
+
     /// Return current layout.
     fn current(&self) -> T {
+        self.interrupt.clone().unwrap_or_else(|| self.scheduled())
+    }
+
+    /// Return the current layout of the schedule.
+    fn scheduled(&self) -> T {
         self.index.map(|i| self.layouts[i].clone()).unwrap_or_default()
     }
 }
@@ -262,7 +776,7 @@ impl<T: Eq + Default + Clone> Schedule<T> {
 #[cfg(test)]
 #[test]
 fn test_schedule() {
-    let mut schedule = Schedule { index: None, layouts: vec![] };
+    let mut schedule = Schedule { index: None, layouts: vec![], interrupt: None };
     assert_eq!(schedule.next(), None);
     assert_eq!(schedule.update(vec![]), Some(0));
     assert_eq!(schedule.update(vec![1]), Some(1));
@@ -272,3 +786,129 @@ fn test_schedule() {
     assert_eq!(schedule.next(), Some(2));
     assert_eq!(schedule.update(vec![1, 3]), Some(1));
 }
+
+#[test]
+fn test_schedule_jump() {
+    let mut schedule = Schedule { index: None, layouts: vec![], interrupt: None };
+    assert_eq!(schedule.update(vec![1, 3]), Some(1));
+    assert_eq!(schedule.jump(|&id| id == 3), Some(3));
+    assert_eq!(schedule.jump(|&id| id == 4), None);
+    assert_eq!(schedule.current(), 3);
+    assert_eq!(schedule.next(), Some(1));
+}
+
+#[test]
+fn test_schedule_interrupt() {
+    let mut schedule = Schedule { index: None, layouts: vec![], interrupt: None };
+    assert_eq!(schedule.update(vec![1, 3]), Some(1));
+    assert_eq!(schedule.next(), Some(3));
+    // an interrupting layout stays until reverted
+    schedule.interrupt(7);
+    assert_eq!(schedule.current(), 7);
+    assert_eq!(schedule.next(), None);
+    assert_eq!(schedule.update(vec![3, 4]), None);
+    assert_eq!(schedule.current(), 7);
+    assert_eq!(schedule.revert(), Some(3));
+    assert_eq!(schedule.revert(), None);
+    assert_eq!(schedule.next(), Some(4));
+    // jumping to a scheduled layout ends it
+    schedule.interrupt(7);
+    assert_eq!(schedule.jump(|&id| id == 3), Some(3));
+    assert_eq!(schedule.current(), 3);
+}
+
+#[test]
+fn test_splash_wait() {
+    let secs = Duration::from_secs;
+    assert_eq!(splash_wait(secs(0), secs(0)), None);
+    assert_eq!(splash_wait(secs(1), secs(3)), Some(secs(2)));
+    assert_eq!(splash_wait(secs(3), secs(3)), None);
+    assert_eq!(splash_wait(secs(5), secs(3)), None);
+}
+
+#[test]
+fn test_media_error_message() {
+    assert_eq!(media_error_message(4, "a.mp4"),
+               "missing codec for a.mp4, install gstreamer plugins (e.g. gst-libav) to play it");
+    assert_eq!(media_error_message(2, "a.mp4"), "could not play a.mp4 (media error 2)");
+}
+
+#[test]
+fn test_diagnostics_js() {
+    let js = diagnostics_js(Some("Display ID: 'abc'\nCMS: x"));
+    assert!(js.contains(r#"d.textContent = "Display ID: 'abc'\nCMS: x";"#));
+    assert!(js.contains("pointer-events: none;"));
+    assert!(diagnostics_js(None).contains("d.remove()"));
+}
+
+#[test]
+fn test_scaled_geometry() {
+    // splash and waiting screens fill the configured window size
+    assert_eq!(scaled_geometry((1280, 1024), (0, 0)), (0, 0, 1280, 1024, 1.0));
+    assert_eq!(scaled_geometry((1920, 1080), (1920, 1080)), (0, 0, 1920, 1080, 1.0));
+    // layouts are scaled and centered
+    assert_eq!(scaled_geometry((1920, 1200), (1920, 1080)), (0, 60, 1920, 1080, 1.0));
+    assert_eq!(scaled_geometry((1280, 720), (1920, 1080)), (0, 0, 1280, 720, 2.0 / 3.0));
+    assert_eq!(scaled_geometry((2000, 1080), (1920, 1080)), (40, 0, 1920, 1080, 1.0));
+}
+
+#[test]
+fn test_rotated_geometry() {
+    // a portrait layout fills a landscape window rotated by 90 degrees
+    assert_eq!(rotated_geometry((1920, 1080), (1080, 1920), 90), (0, 0, 1920, 1080, 1.0));
+    assert_eq!(rotated_geometry((1920, 1080), (1080, 1920), 270), (0, 0, 1920, 1080, 1.0));
+    // a landscape layout on a rotated screen is centered along the long side
+    assert_eq!(rotated_geometry((1920, 1080), (1920, 1080), 90),
+               (656, 0, 608, 1080, 0.5625));
+    // 180 degrees doesn't change the geometry
+    assert_eq!(rotated_geometry((1920, 1080), (1920, 1080), 180), (0, 0, 1920, 1080, 1.0));
+    assert!(rotation_css(0).is_none());
+    assert!(rotation_css(90).unwrap().contains("rotate(90deg)"));
+}
+
+#[test]
+fn test_is_layout_uri() {
+    assert!(is_layout_uri("http://localhost:9696/12.xlf.html"));
+    // splash and waiting screens don't count as showing content
+    assert!(!is_layout_uri("http://localhost:9696/0.xlf.html"));
+    assert!(!is_layout_uri("http://localhost:9696/register.html"));
+    assert!(!is_layout_uri("about:blank"));
+}
+
+#[test]
+fn test_is_status_uri() {
+    assert!(is_status_uri("http://localhost:9696/register.html"));
+    assert!(is_status_uri("http://localhost:9696/fallback.html"));
+    assert!(!is_status_uri("http://localhost:9696/0.xlf.html"));
+    assert!(!is_status_uri("http://localhost:9696/12.xlf.html"));
+}
+
+#[test]
+fn test_console_level() {
+    assert_eq!(console_level("error", ConsoleLog::Error), Some(log::Level::Warn));
+    assert_eq!(console_level("warn", ConsoleLog::Error), None);
+    assert_eq!(console_level("warn", ConsoleLog::Warn), Some(log::Level::Warn));
+    assert_eq!(console_level("log", ConsoleLog::Warn), None);
+    assert_eq!(console_level("info", ConsoleLog::All), Some(log::Level::Debug));
+    assert_eq!(console_level("error", ConsoleLog::Off), None);
+}
+
+#[test]
+fn test_init_fallback() {
+    let failed = || Err(glib::bool_error!("cannot open display"));
+    assert!(init_fallback(Ok(()), false).unwrap());
+    assert!(init_fallback(Ok(()), true).unwrap());
+    assert!(!init_fallback(failed(), true).unwrap());
+    let err = init_fallback(failed(), false).unwrap_err();
+    assert!(format!("{:#}", err).contains("cannot open display"));
+    assert!(format!("{:#}", err).contains("--backend"));
+}
+
+#[test]
+fn test_encode_shot() {
+    let img = ImageSurface::create(cairo::Format::Rgb24, 64, 48).unwrap();
+    let png = encode_shot(&img, ScreenshotFormat::Png, 80).unwrap();
+    assert!(png.starts_with(b"\x89PNG"));
+    let jpeg = encode_shot(&img, ScreenshotFormat::Jpeg, 80).unwrap();
+    assert!(jpeg.starts_with(b"\xff\xd8"));
+}
@@ -4,15 +4,95 @@
 //! XLF layout parser and translator.
 
 use std::{fs, io::{Write, BufWriter}};
+use std::collections::{BTreeMap, BTreeSet, HashMap, hash_map::DefaultHasher};
+use std::hash::{Hash, Hasher};
 use std::path::Path;
-use anyhow::{Context, Result};
+use anyhow::{bail, ensure, Context, Result};
 use elementtree::Element;
-use crate::util::{ElementExt, percent_decode};
+use serde::Serialize;
+use crate::config::{CmsSettings, ObjectFit, PlayerSettings, RegionOrder, VideoEnd};
+use crate::util::{ElementExt, TIME_FMT, percent_decode};
 
-// TODO:
-// - transitions
-// - reloading resources in iframes
-// - overriding duration from resources
+/// Version of the generated HTML.  Bump this when the output changes, so that
+/// cached layouts are translated again.
+const TRANSLATOR_VERSION: u32 = 20;
+
+/// Duration in seconds for media without a given duration.
+const DEFAULT_DURATION: i32 = 10;
+
+/// Newest XLF schema version known to be supported by the translator.
+const MAX_SCHEMA_VERSION: u32 = 3;
+
+/// Player settings that influence the generated HTML.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Options {
+    pub stats_enabled: bool,
+    pub region_order: RegionOrder,
+    pub strict_schema: bool,
+    /// Codes of the stored commands defined in the CMS.
+    pub commands: BTreeSet<String>,
+    /// Duration in milliseconds of a black interstitial between media (0 = none).
+    pub interstitial: u64,
+    /// Play videos muted if playing them with sound is refused.
+    pub autoplay_muted: bool,
+    /// Layout codes from the CMS mapped to layout IDs, to resolve actions
+    /// navigating to another layout.
+    pub layout_codes: BTreeMap<String, i64>,
+    /// Default fit of images and videos.
+    pub image_fit: ObjectFit,
+    pub video_fit: ObjectFit,
+    /// What to show after a video has ended.
+    pub video_end: VideoEnd,
+}
+
+impl Options {
+    pub fn from_settings(settings: &PlayerSettings, cms: &CmsSettings) -> Self {
+        Self {
+            stats_enabled: settings.stats_enabled,
+            region_order: cms.region_order,
+            strict_schema: cms.strict_schema,
+            commands: settings.commands.keys().cloned().collect(),
+            interstitial: cms.media_interstitial,
+            autoplay_muted: cms.autoplay_muted_fallback,
+            layout_codes: BTreeMap::new(),
+            image_fit: cms.image_fit,
+            video_fit: cms.video_fit,
+            video_end: cms.video_end,
+        }
+    }
+
+    /// Return a key identifying the translator version, options and probed
+    /// media durations, in order to detect cached layouts that need to be
+    /// translated again.
+    pub fn key(&self, durations: &KnownDurations) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        (TRANSLATOR_VERSION, self).hash(&mut hasher);
+        // in a stable order, and durations by their bits since f64 isn't Hash
+        durations.media.iter()
+                       .map(|(name, duration)| (name, duration.to_bits()))
+                       .collect::<BTreeMap<_, _>>()
+                       .hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+/// Durations of cached files, as far as they are known at translation time.
+#[derive(Debug, Default)]
+pub struct KnownDurations {
+    /// Media file name -> duration in seconds.
+    pub media: HashMap<String, f64>,
+    /// Media ID -> duration information given by the resource.
+    pub resources: HashMap<i32, ResourceDuration>,
+}
+
+/// Duration information embedded in a resource's HTML.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ResourceDuration {
+    /// Duration in seconds, overriding the media's duration.
+    pub duration: Option<f64>,
+    /// Number of items, if the media's duration is per item.
+    pub numitems: Option<u32>,
+}
 
 
 const LAYOUT_CSS: &str = r#"
@@ -23,8 +103,12 @@ p { margin-top: 0; }
 "#;
 
 const SCRIPT: &str = r#"
+var regions = {};
 var regions_done = {};
 var regions_total = 0;
+var actions = [];
+var failed_media = {};
+var paused = false;
 function region_done(rid) {
   regions_done[rid] = 1;
   if (Object.keys(regions_done).length == regions_total) {
@@ -32,39 +116,380 @@ function region_done(rid) {
     regions_total = 0;  // no more messages
   }
 }
+function region_switch(rid, index) {
+  var region = regions[rid];
+  var nitems = region.items.length;
+  var forward = index == -1;
+  var step = index == -2 ? -1 : 1;
+  if (region.timer) {
+    window.clearTimeout(region.timer);
+    region.timer = null;
+  }
+  // drawers show a single item when requested, then hide again
+  if (region.drawer && forward) {
+    region_hide(rid);
+    return;
+  }
+  // -1 means next item, -2 means previous item
+  if (index == -1) index = region.index + 1;
+  else if (index == -2) index = region.index - 1;
+  index = (index + nitems) % nitems;
+  // skip media that failed to load or is not valid now, and interstitials
+  // when going back
+  for (var tries = 1; tries < nitems && (failed_media[region.items[index].id] ||
+                                         !item_valid(region.items[index]) ||
+                                         (step < 0 && region.items[index].interstitial)); tries++) {
+    index = (index + step + nitems) % nitems;
+  }
+  if (forward && region.index >= 0 && index <= region.index && !region.loop) region_done(rid);
+  var old = region.items[region.index];
+  var item = region.items[index];
+  if (old && old !== item) {
+    old.stop();
+    media_hide(old.id);
+  }
+  region.index = index;
+  if (failed_media[item.id] || !item_valid(item)) {
+    // nothing playable in this region, just let the time pass
+    region_timer(rid, 1000 * (item.duration || 10));
+    return;
+  }
+  document.getElementById('m' + item.id).style.visibility = 'visible';
+  item.start();
+  if (item.duration > 0) {
+    region_timer(rid, 1000 * item.duration);
+  }
+}
+function item_valid(item) {
+  var now = Date.now();
+  return !(item.from && now < item.from) && !(item.to && now >= item.to);
+}
+function check_validity() {
+  // advance regions whose current media has expired, and regions waiting
+  // for any media to become valid
+  for (var rid in regions) {
+    var region = regions[rid];
+    if (region.index < 0 || paused) continue;
+    var item = region.items[region.index];
+    if (item_valid(item) != (document.getElementById('m' + item.id).style.visibility == 'visible')) {
+      region_switch(rid, -1);
+    }
+  }
+}
+function media_hide(mid) {
+  var el = document.getElementById('m' + mid);
+  // with an out transition, the element is hidden when it's done
+  if (!el.dataset.transition) el.style.visibility = 'hidden';
+}
+var fly_offsets = {N: [0, -1], NE: [1, -1], E: [1, 0], SE: [1, 1],
+                   S: [0, 1], SW: [-1, 1], W: [-1, 0], NW: [-1, -1]};
+function transition_style(el, kind, dir) {
+  if (kind == 'fade') {
+    el.style.opacity = 0;
+  } else {
+    var offset = fly_offsets[dir] || fly_offsets.N;
+    el.style.transform = 'translate(' + offset[0] * 100 + 'vw, ' + offset[1] * 100 + 'vh)';
+  }
+}
+function transition_in(mid, kind, ms, dir) {
+  var el = document.getElementById('m' + mid);
+  window.clearTimeout(el.transition_timer);
+  delete el.dataset.transition;
+  el.style.transition = 'none';
+  transition_style(el, kind, dir);
+  el.getBoundingClientRect();  // apply the start state before transitioning
+  el.style.transition = (kind == 'fade' ? 'opacity ' : 'transform ') + ms + 'ms';
+  el.style.opacity = '';
+  el.style.transform = '';
+}
+function transition_out(mid, kind, ms, dir) {
+  var el = document.getElementById('m' + mid);
+  el.dataset.transition = 'out';
+  el.style.transition = (kind == 'fade' ? 'opacity ' : 'transform ') + ms + 'ms';
+  transition_style(el, kind, dir);
+  el.transition_timer = window.setTimeout(function() {
+    delete el.dataset.transition;
+    el.style.visibility = 'hidden';
+    el.style.transition = 'none';
+    el.style.opacity = '';
+    el.style.transform = '';
+  }, ms);
+}
+function region_timer(rid, ms) {
+  var region = regions[rid];
+  region.deadline = Date.now() + ms;
+  region.timer = window.setTimeout(function() { region_switch(rid, -1); }, ms);
+}
+function arexibo_pause() {
+  if (paused) return;
+  paused = true;
+  // remember how much time is left for each region
+  for (var rid in regions) {
+    var region = regions[rid];
+    if (region.timer) {
+      window.clearTimeout(region.timer);
+      region.timer = null;
+      region.remaining = Math.max(0, region.deadline - Date.now());
+    }
+  }
+  for (var video of document.querySelectorAll('video, audio')) {
+    if (!video.paused) {
+      video.pause();
+      video.dataset.resume = '1';
+    }
+  }
+}
+function arexibo_resume() {
+  if (!paused) return;
+  paused = false;
+  for (var rid in regions) {
+    var region = regions[rid];
+    if (region.remaining !== undefined) {
+      region_timer(rid, region.remaining);
+      delete region.remaining;
+    }
+  }
+  for (var video of document.querySelectorAll('video[data-resume], audio[data-resume]')) {
+    delete video.dataset.resume;
+    video.play();
+  }
+}
+function media_play(mid) {
+  var video = document.getElementById('m' + mid);
+  var promise = video.play();
+  if (!promise) return;
+  promise.catch(function(e) {
+    if (e.name != 'NotAllowedError' || video.muted || !autoplay_muted) return;
+    // rather play without sound than show a frozen frame
+    window.webkit.messageHandlers.xibo.postMessage('autoplay_muted:' + video.getAttribute('src'));
+    video.muted = true;
+    video.play();
+  });
+}
+function media_error(rid, mid, code) {
+  failed_media[mid] = 1;
+  var src = document.getElementById('m' + mid).getAttribute('src');
+  window.webkit.messageHandlers.xibo.postMessage('media_error:' + code + ':' + src);
+  // if the media is currently shown, advance the region instead of hanging
+  var region = regions[rid];
+  if (region && region.index >= 0 && region.items[region.index].id == mid) {
+    region_switch(rid, -1);
+  }
+}
+function region_hide(rid) {
+  var region = regions[rid];
+  var item = region.items[region.index];
+  if (region.timer) {
+    window.clearTimeout(region.timer);
+    region.timer = null;
+  }
+  if (item) {
+    item.stop();
+    media_hide(item.id);
+  }
+  region.index = -1;
+}
+function widget_show(mid) {
+  for (var rid in regions) {
+    var items = regions[rid].items;
+    for (var i = 0; i < items.length; i++) {
+      if (items[i].id == mid) {
+        region_switch(rid, i);
+        return;
+      }
+    }
+  }
+}
+function arexibo_trigger(code) {
+  for (var action of actions) {
+    if (action.trigger == 'webhook' && action.code == code) action.run();
+  }
+}
+function reload_resource(mid) {
+  var el = document.getElementById('m' + mid);
+  if (el && el.tagName == 'IFRAME') el.src = el.getAttribute('src');
+}
+function touch_attach() {
+  // widgets are also matched by their region's selector: attach widget
+  // actions first, so that they can stop the region's actions
+  var touch = actions.filter(function(action) { return action.trigger == 'touch'; });
+  touch.sort(function(a, b) { return (b.source[0] == '#') - (a.source[0] == '#'); });
+  touch.forEach(function(action) {
+    for (var el of document.querySelectorAll(action.source)) {
+      el.addEventListener('click', function(e) { e.stopImmediatePropagation(); action.run(); });
+    }
+  });
+}
 "#;
 
+const STATS_SCRIPT: &str = r#"
+function media_stat(what, mid) {
+  window.webkit.messageHandlers.xibo.postMessage('stat:' + what + ':' + mid);
+}
+"#;
+
+
+/// Media element ID, duration, JS to run on start and JS to run on stop.
+type MediaInfo = (i32, i32, String, String);
+
+/// An animated transition when showing or hiding a media.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Transition {
+    /// Either "fade" or "fly".
+    kind: &'static str,
+    /// Duration in milliseconds.
+    duration: u32,
+    /// Compass direction for "fly".
+    direction: String,
+}
+
+impl Transition {
+    /// Read the "in" or "out" transition from the media options.
+    fn from_options(opts: &Element, which: &str) -> Option<Self> {
+        let kind = match opts.find(&*format!("trans{}", which))?.text() {
+            "" => return None,
+            "fade" | "fadeIn" | "fadeOut" => "fade",
+            "fly" | "flyIn" | "flyOut" => "fly",
+            other => {
+                log::warn!("layout: unsupported transition {:?}", other);
+                return None;
+            }
+        };
+        let duration = opts.find(&*format!("trans{}Duration", which))
+                           .and_then(|el| el.text().parse().ok()).unwrap_or(1000);
+        let direction = opts.find(&*format!("trans{}Direction", which))
+                            .map_or("N", |el| el.text()).to_string();
+        Some(Self { kind, duration, direction })
+    }
 
-type MediaInfo = (i32, i32, String, Option<String>);
+    /// Return the JS to run the transition for the given media.
+    fn js(&self, which: &str, mid: i32) -> String {
+        format!("transition_{}({}, '{}', {}, {:?}); ", which, mid, self.kind, self.duration,
+                self.direction)
+    }
+}
+
+/// Structure of a translated layout, for external tools.
+#[derive(Debug, Serialize, PartialEq)]
+pub struct LayoutMeta {
+    pub width: i32,
+    pub height: i32,
+    pub regions: Vec<RegionMeta>,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct RegionMeta {
+    pub id: i32,
+    pub drawer: bool,
+    pub left: i32,
+    pub top: i32,
+    pub width: i32,
+    pub height: i32,
+    pub media: Vec<MediaMeta>,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct MediaMeta {
+    pub id: i32,
+    #[serde(rename = "type")]
+    pub typ: String,
+    /// Duration in seconds, 0 if the media plays until it ends.
+    pub duration: i32,
+}
+
+/// Information about a region needed to determine the stacking order.
+struct Layer {
+    id: i32,
+    geom: [i32; 4],
+    zindex: i32,
+    drawer: bool,
+    interactive: bool,
+}
+
+/// Position of a region in the stacking order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Stacking {
+    zindex: usize,
+    pointer_events: bool,
+}
 
 pub struct Translator {
     tree: Option<Element>,
+    /// ID of the layout, taken from the file name.
+    layout_id: Option<i64>,
+    options: Options,
+    durations: KnownDurations,
     out: BufWriter<fs::File>,
     regions: Vec<i32>,
+    /// Media ID -> region ID, index in the region and whether it's a drawer.
+    positions: HashMap<i32, (i32, usize, bool)>,
+    /// Whether any media has a validity window that must be checked.
+    check_validity: bool,
+    pending_actions: Vec<Element>,
+    actions: Vec<String>,
+    stacking: HashMap<i32, Stacking>,
     size: (i32, i32),
+    meta: Vec<RegionMeta>,
+    /// Emitted regions that loop their media instead of completing; they
+    /// only complete the layout if no other region can.
+    looping: Vec<i32>,
 }
 
 impl Translator {
-    pub fn new(xlf: &Path, html: &Path) -> Result<Self> {
+    pub fn new(xlf: &Path, html: &Path, options: Options,
+               durations: KnownDurations) -> Result<Self> {
         let file = fs::File::open(xlf)?;
-        let tree = Some(Element::from_reader(file).context("parsing XLF")?);
+        let tree = Element::from_reader(file).context("parsing XLF")?;
+
+        // check this before creating the output, to keep a previous translation
+        if let Some(version) = unsupported_schema(&tree) {
+            ensure!(!options.strict_schema, "unsupported XLF schema version {}", version);
+            log::warn!("layout {} has XLF schema version {}, newer than the supported \
+                        version {}; it might not be displayed correctly",
+                       xlf.display(), version, MAX_SCHEMA_VERSION);
+        }
+        let tree = Some(tree);
+        let layout_id = xlf.file_stem().and_then(|s| s.to_str()).and_then(|s| s.parse().ok());
 
         let out = fs::File::create(html)?;
         let out = BufWriter::new(out);
 
-        Ok(Self { tree, out, regions: Vec::new(), size: (0, 0) })
+        Ok(Self { tree, layout_id, options, durations, out, regions: Vec::new(), positions: HashMap::new(),
+                  check_validity: false, pending_actions: Vec::new(), actions: Vec::new(), stacking: HashMap::new(),
+                  size: (0, 0), meta: Vec::new(), looping: Vec::new() })
     }
 
-    pub fn translate(mut self) -> Result<(i32, i32)> {
+    pub fn translate(mut self) -> Result<LayoutMeta> {
         let tree = self.tree.take().unwrap();
         self.write_header(&tree)?;
+        let layers = tree.find_all("region").map(|el| (el, false))
+                         .chain(tree.find_all("drawer").map(|el| (el, true)))
+                         .filter_map(|(el, drawer)| region_layer(el, drawer))
+                         .collect();
+        self.stacking = compute_stacking(layers, self.options.region_order);
         for region in tree.find_all("region") {
-            if let Err(e) = self.write_region(region) {
+            if let Err(e) = self.write_region(region, false) {
                 log::error!("layout: could not translate region: {:#}", e);
             }
         }
+        for drawer in tree.find_all("drawer") {
+            if let Err(e) = self.write_region(drawer, true) {
+                log::error!("layout: could not translate drawer: {:#}", e);
+            }
+        }
+        for action in tree.find_all("action") {
+            self.add_action(action);
+        }
+        // actions are translated once the position of all media is known
+        for action in std::mem::take(&mut self.pending_actions) {
+            if let Err(e) = self.write_action(&action) {
+                log::error!("layout: could not translate action: {:#}", e);
+            }
+        }
         self.write_footer()?;
-        Ok(self.size)
+        self.out.flush()?;
+        let (width, height) = self.size;
+        Ok(LayoutMeta { width, height, regions: self.meta })
     }
 
     fn write_header(&mut self, el: &Element) -> Result<()> {
@@ -73,6 +498,11 @@ impl Translator {
         writeln!(self.out, "<!doctype html>\n<html><head>")?;
         writeln!(self.out, "<meta charset='utf-8'>")?;
         writeln!(self.out, "<script type='text/javascript'>{}</script>", SCRIPT)?;
+        writeln!(self.out, "<script type='text/javascript'>var autoplay_muted = {};</script>",
+                 self.options.autoplay_muted)?;
+        if self.options.stats_enabled {
+            writeln!(self.out, "<script type='text/javascript'>{}</script>", STATS_SCRIPT)?;
+        }
         writeln!(self.out, "<style type='text/css'>{}", LAYOUT_CSS)?;
 
         if let Some(file) = el.get_attr("background") {
@@ -88,18 +518,46 @@ impl Translator {
     }
 
     fn write_footer(&mut self) -> Result<()> {
-        // start all regions' first item
-        writeln!(self.out, "<script type='text/javascript'>\n\
-                            document.addEventListener('DOMContentLoaded', function() {{")?;
+        writeln!(self.out, "<script type='text/javascript'>")?;
+        for action in &self.actions {
+            writeln!(self.out, "actions.push({});", action)?;
+        }
+        // start all regions' first item; drawers stay hidden until an
+        // action requests one of their items
+        // errors during startup are reported, since they can leave the
+        // screen blank
+        writeln!(self.out, "document.addEventListener('DOMContentLoaded', function() {{")?;
+        writeln!(self.out, "  try {{")?;
+        writeln!(self.out, "    touch_attach();")?;
+        if self.looping.len() == self.regions.len() {
+            // all regions loop, so they must complete the layout themselves
+            for rid in &self.looping {
+                writeln!(self.out, "    regions[{}].loop = false; regions_total += 1;", rid)?;
+            }
+        }
         for rid in &self.regions {
-            writeln!(self.out, "  r{}_s0(true);", rid)?;
+            writeln!(self.out, "    region_switch({}, 0);", rid)?;
+        }
+        if self.check_validity {
+            writeln!(self.out, "    window.setInterval(check_validity, 10000);")?;
         }
+        if self.regions.is_empty() {
+            // nothing to show, let the schedule continue after a moment
+            // (not immediately, to avoid busy cycling between such layouts)
+            log::warn!("layout: no region has any media to show");
+            writeln!(self.out, "    window.setTimeout(function() {{ \
+                                window.webkit.messageHandlers.xibo.postMessage('layout_done'); \
+                                }}, 1000);")?;
+        }
+        writeln!(self.out, "  }} catch (e) {{")?;
+        writeln!(self.out, "    window.webkit.messageHandlers.xibo.postMessage('layout_error:' + e);")?;
+        writeln!(self.out, "  }}")?;
         writeln!(self.out, "}});\n</script>")?;
         writeln!(self.out, "</body></html>")?;
         Ok(())
     }
 
-    fn write_region(&mut self, region: &Element) -> Result<()> {
+    fn write_region(&mut self, region: &Element, drawer: bool) -> Result<()> {
         let rid = region.parse_attr("id")?;
         let x = region.parse_attr("left")?;
         let y = region.parse_attr("top")?;
@@ -107,62 +565,98 @@ impl Translator {
         let h = region.parse_attr("height")?;
         let geom = [x, y, w, h];
 
-        writeln!(self.out, "<!-- region {} -->", rid)?;
+        writeln!(self.out, "<!-- {} {} -->", if drawer { "drawer" } else { "region" }, rid)?;
+        if let Some(stacking) = self.stacking.get(&rid) {
+            writeln!(self.out, "<style type='text/css'>.r{} {{ z-index: {}; pointer-events: {}; }}</style>",
+                     rid, stacking.zindex, if stacking.pointer_events { "auto" } else { "none" })?;
+        }
         let mut sequence = Vec::new();
+        let mut media_meta = Vec::new();
         for media in region.find_all("media") {
             match self.write_media(rid, geom, media) {
                 Err(e) => log::error!("layout: could not translate media: {:#}", e),
                 Ok(None) => continue,
-                Ok(Some(res)) => sequence.push(res),
+                Ok(Some(((mid, len, mut start, mut stop), [trans_in, trans_out]))) => {
+                    media_meta.push(MediaMeta { id: mid, typ: media.def_attr("type", "").into(),
+                                                duration: len });
+                    if let Some(trans) = trans_in {
+                        start.insert_str(0, &trans.js("in", mid));
+                    }
+                    if let Some(trans) = trans_out {
+                        stop += &trans.js("out", mid);
+                    }
+                    sequence.push(((mid, len, start, stop), media_validity(media)));
+                }
+            }
+            for action in media.find_all("action") {
+                self.add_action(action);
             }
         }
-        let nitems = sequence.len();
+        for action in region.find_all("action") {
+            self.add_action(action);
+        }
+        self.meta.push(RegionMeta { id: rid, drawer, left: x, top: y, width: w, height: h,
+                                    media: media_meta });
 
-        if nitems == 0 {
+        if sequence.is_empty() {
             return Ok(());
         }
 
+        // for each media, record how to display it and how long;
+        // the region_switch() function then takes care of the rotation
         writeln!(self.out, "<script type='text/javascript'>")?;
-        writeln!(self.out, "regions_total += 1;")?;
-        // for each media, create a function to display it and schedule the next one
-        for (i, (mid, duration, custom_start, custom_transition)) in sequence.iter().enumerate() {
-            writeln!(self.out, "function r{}_s{}(first) {{", rid, i)?;
-
-            // when the first media is called for the second time, the region is "done"
-            if i == 0 {
-                writeln!(self.out, "  if (!first) {{ region_done('r{}'); }}", rid)?;
+        // looping regions play until the other regions are done
+        let looped = region_loops(region);
+        if !drawer && !looped {
+            writeln!(self.out, "regions_total += 1;")?;
+        }
+        writeln!(self.out, "regions[{}] = {{index: -1, timer: null, drawer: {}, loop: {}, items: [",
+                 rid, drawer, looped)?;
+        // optionally, a black interstitial is shown between the media
+        let interstitial = self.options.interstitial > 0 && sequence.len() > 1;
+        let mut index = 0;
+        for ((mid, duration, start, stop), validity) in &mut sequence {
+            self.positions.insert(*mid, (rid, index, drawer));
+            if self.options.stats_enabled {
+                start.insert_str(0, &format!("media_stat('start', {}); ", mid));
+                stop.insert_str(0, &format!("media_stat('stop', {}); ", mid));
             }
-
-            // if only one item is present, don't need to hide the others
-            if nitems > 1 {
-                writeln!(self.out, "  for (el of document.querySelectorAll('.r{}')) el.style.visibility = 'hidden';", rid)?;
+            self.check_validity |= !validity.is_empty();
+            writeln!(self.out, "  {{id: {}, duration: {},{} start: function() {{ {} }}, \
+                                stop: function() {{ {} }}}},", mid, duration, validity, start, stop)?;
+            index += 1;
+            if interstitial {
+                writeln!(self.out, "  {{id: 'i{}', duration: {}, interstitial: true,{} \
+                                    start: function() {{}}, stop: function() {{}}}},",
+                         mid, self.options.interstitial as f64 / 1000., validity)?;
+                index += 1;
             }
-            writeln!(self.out, "  document.querySelector('#m{}').style.visibility = 'visible'; {}", mid, custom_start)?;
-
-            // schedule the next one: either after duration, or with custom code
-            let next_i = if i == sequence.len() - 1 { 0 } else { i+1 };
-            let next_fn = format!("r{}_s{}();", rid, next_i);
-            if let Some(tmpl) = custom_transition {
-                writeln!(self.out, "  {}", tmpl.replace("###", &next_fn))?;
+        }
+        writeln!(self.out, "]}};\n</script>")?;
+        if interstitial {
+            let [x, y, w, h] = geom;
+            for ((mid, ..), _) in &sequence {
+                writeln!(self.out, "<div class='media r{}' id='mi{}' style='left: {}px; top: {}px; \
+                                    width: {}px; height: {}px; background: black;'></div>",
+                         rid, mid, x, y, w, h)?;
             }
-            if *duration != 0 {
-                writeln!(self.out, "  window.setTimeout(() => {{ {} }}, {});",
-                         next_fn, 1000 * duration)?;
+        }
+        if !drawer {
+            self.regions.push(rid);
+            if looped {
+                self.looping.push(rid);
             }
-            writeln!(self.out, "}}")?;
         }
-        writeln!(self.out, "</script>")?;
-        self.regions.push(rid);
         Ok(())
     }
 
-    fn write_media(&mut self, rid: i32, [x, y, w, h]: [i32; 4],
-                   media: &Element) -> Result<Option<MediaInfo>> {
+    fn write_media(&mut self, rid: i32, [x, y, w, h]: [i32; 4], media: &Element)
+                   -> Result<Option<(MediaInfo, [Option<Transition>; 2])>> {
         let mid = media.parse_attr("id")?;
         let opts = media.find("options").context("no options")?;
-        let len = media.def_attr("duration", "").parse::<i32>().unwrap_or(10);
-        let mut custom_start = "".into();
-        let mut custom_transition = None;
+        let mut len = media.def_attr("duration", "").parse::<i32>().unwrap_or(DEFAULT_DURATION);
+        let mut start = String::new();
+        let mut stop = String::new();
         writeln!(self.out, "  <!-- media {} -->", mid)?;
         match (media.get_attr("render"), media.get_attr("type")) {
             (Some("html"), _) |
@@ -171,6 +665,9 @@ impl Translator {
                                     style='left: {}px; top: {}px; width: {}px; \
                                     height: {}px;'></iframe>",
                          rid, mid, mid, x, y, w, h)?;
+                let per_item = opts.find("durationIsPerItem").map_or(false, |el| el.text() == "1");
+                len = resource_duration(self.durations.resources.get(&mid), len, per_item)
+                    .unwrap_or_else(|| iframe_duration(mid, len));
             }
             (_, Some("webpage")) => {
                 let url = percent_decode(opts.find("uri").context("no web uri")?.text());
@@ -178,48 +675,302 @@ impl Translator {
                                     style='left: {}px; top: {}px; width: {}px; \
                                     height: {}px;'></iframe>",
                          rid, mid, url, x, y, w, h)?;
+                len = iframe_duration(mid, len);
             }
             (_, Some("image")) => {
                 let filename = opts.find("uri").context("no image uri")?.text();
                 writeln!(self.out, "<img class='media r{}' id='m{}' src='{}' \
                                     style='left: {}px; top: {}px; width: {}px; \
                                     height: {}px;{}{}'>",
-                         rid, mid, filename, x, y, w, h, object_fit(opts), object_pos(opts))?;
+                         rid, mid, filename, x, y, w, h, object_fit(opts, self.options.image_fit),
+                         object_pos(opts))?;
             }
             (_, Some("video")) => {
                 let filename = opts.find("uri").context("no video uri")?.text();
+                let offset = media_offset(opts);
+                // if the video should play to its end, use the probed duration
+                // if known; otherwise the region waits for the video to end
+                if len == 0 || media.def_attr("useDuration", "1") == "0" {
+                    len = self.durations.media.get(filename)
+                                             .map_or(0, |d| (d - offset).max(0.0).ceil() as i32);
+                }
                 let mute = opts.find("mute").map_or(false, |el| el.text() == "1");
                 writeln!(self.out, "<video class='media r{}' id='m{}' src='{}' {} \
+                                    onerror='media_error({}, {}, this.error ? this.error.code : 0)' \
                                     style='left: {}px; top: {}px; width: {}px; \
                                     height: {}px;{}{}'></video>",
-                         rid, mid, filename, if mute { "muted" } else { "" },
-                         x, y, w, h, object_fit(opts), object_pos(opts))?;
+                         rid, mid, filename, if mute { "muted" } else { "" }, rid, mid,
+                         x, y, w, h, object_fit(opts, self.options.video_fit), object_pos(opts))?;
+                let black = if self.options.video_end == VideoEnd::Black {
+                    "e.target.style.filter = 'brightness(0)'; "
+                } else { "" };
+                start = format!("document.querySelector('#m{}').onended = (e) => {{ {}\
+                                 e.target.fastSeek({}); region_switch({}, -1); }}; ",
+                                mid, black, offset, rid);
+                if !black.is_empty() {
+                    start += &format!("document.querySelector('#m{}').style.filter = ''; ", mid);
+                }
+                if offset > 0.0 {
+                    start += &format!("document.querySelector('#m{}').currentTime = {}; ",
+                                      mid, offset);
+                }
                 if mute {
-                    custom_start = format!("document.querySelector('#m{}').play();", mid);
+                    start += &format!("media_play({});", mid);
                 } else {
                     // WebKit doesn't allow non-muted media to be started by JS,
                     // even with media-playback-requires-user-gesture set to false.
                     // However, if the script is executed from outside it seems
                     // to work. So we request this by posting a request back.
-                    custom_start = format!(
+                    start += &format!(
+                        "window.webkit.messageHandlers.xibo.postMessage('play:{}');", mid);
+                }
+                stop = format!("document.querySelector('#m{}').onended = null; \
+                                document.querySelector('#m{}').pause();", mid, mid);
+            }
+            (_, Some("audio")) => {
+                let filename = opts.find("uri").context("no audio uri")?.text();
+                let offset = media_offset(opts);
+                // as for videos, play to the end unless a duration is given
+                let use_duration = len > 0 && media.def_attr("useDuration", "1") != "0";
+                if !use_duration {
+                    len = self.durations.media.get(filename)
+                                             .map_or(0, |d| (d - offset).max(0.0).ceil() as i32);
+                }
+                let mute = opts.find("mute").map_or(false, |el| el.text() == "1");
+                // looping only makes sense if the duration is given
+                let looped = use_duration && opts.find("loop").map_or(false, |el| el.text() == "1");
+                writeln!(self.out, "<audio class='media r{}' id='m{}' src='{}' {} {} \
+                                    onerror='media_error({}, {}, this.error ? this.error.code : 0)' \
+                                    style='left: {}px; top: {}px; width: 0; height: 0;'></audio>",
+                         rid, mid, filename, if mute { "muted" } else { "" },
+                         if looped { "loop" } else { "" }, rid, mid, x, y)?;
+                start = format!("document.querySelector('#m{}').currentTime = {}; ", mid, offset);
+                if !looped {
+                    start += &format!("document.querySelector('#m{}').onended = () => {{ \
+                                       region_switch({}, -1); }}; ", mid, rid);
+                }
+                if mute {
+                    start += &format!("media_play({});", mid);
+                } else {
+                    // see above for videos
+                    start += &format!(
                         "window.webkit.messageHandlers.xibo.postMessage('play:{}');", mid);
                 }
-                custom_transition = Some(format!("document.querySelector('#m{}').onended = (e) => {{ \
-                                                  e.target.fastSeek(0); ### }};", mid));
+                stop = format!("document.querySelector('#m{}').onended = null; \
+                                document.querySelector('#m{}').pause();", mid, mid);
+            }
+            (_, Some("shellcommand")) => {
+                // only commands stored in the CMS can be run, they are looked
+                // up by the backend when the layout requests them
+                if !opts.find("useGlobalCommand").map_or(false, |el| el.text() == "1") {
+                    log::warn!("layout: shell command media {} does not use a stored \
+                                command, which is not supported", mid);
+                    return Ok(None);
+                }
+                let code = opts.find("globalCommand").or_else(|| opts.find("storedCommand"))
+                               .map(|el| el.text()).unwrap_or_default();
+                if !self.options.commands.contains(code) {
+                    log::warn!("layout: shell command media {} references stored command {:?}, \
+                                which is not defined in the CMS", mid, code);
+                    return Ok(None);
+                }
+                writeln!(self.out, "<div class='media r{}' id='m{}'></div>", rid, mid)?;
+                start = format!("window.webkit.messageHandlers.xibo.postMessage({:?});",
+                                format!("command:{}", code));
             }
             _ => {
                 log::warn!("unsupported media type: {:?}", media.get_attr("type"));
                 return Ok(None);
             }
         }
-        Ok(Some((mid, len, custom_start, custom_transition)))
+        let transitions = [Transition::from_options(opts, "In"),
+                           Transition::from_options(opts, "Out")];
+        Ok(Some(((mid, len, start, stop), transitions)))
+    }
+
+    /// Record an interactive action, to be registered in the layout's JS.
+    fn add_action(&mut self, action: &Element) {
+        self.pending_actions.push(action.clone());
+    }
+
+    fn write_action(&mut self, action: &Element) -> Result<()> {
+        let run = match action.def_attr("actionType", "") {
+            "next" => format!("region_switch({}, -1);", action.parse_attr::<i32>("targetId")?),
+            "previous" => format!("region_switch({}, -2);", action.parse_attr::<i32>("targetId")?),
+            "navWidget" => {
+                let wid = action.parse_attr::<i32>("widgetId")?;
+                match self.positions.get(&wid) {
+                    // widgets in drawers are shown on top of the layout
+                    Some(&(_, _, true)) => format!("widget_show({});", wid),
+                    // otherwise the region containing the widget jumps to it
+                    Some(&(rid, index, false)) => format!("region_switch({}, {});", rid, index),
+                    None => bail!("widget {} is not part of the layout", wid),
+                }
+            }
+            "navLayout" => {
+                // the GUI switches to the layout, if it is scheduled
+                let code = action.get_attr("layoutCode").context("no layout code")?;
+                // older CMS releases don't send codes, try it as an ID then
+                let Some(id) = self.options.layout_codes.get(code).copied()
+                                   .or_else(|| code.parse().ok()) else {
+                    log::warn!("layout: action navigates to unknown layout code {:?}, ignoring",
+                               code);
+                    return Ok(());
+                };
+                if self.layout_id == Some(id) {
+                    log::warn!("layout: action navigates to the layout itself, ignoring");
+                    return Ok(());
+                }
+                format!("window.webkit.messageHandlers.xibo.postMessage({:?});",
+                        format!("layout:{}", id))
+            }
+            typ => {
+                log::warn!("layout: unsupported action type {:?}", typ);
+                return Ok(());
+            }
+        };
+        match action.def_attr("triggerType", "") {
+            "webhook" => {
+                let code = action.get_attr("triggerCode").context("no trigger code")?;
+                self.actions.push(format!("{{trigger: 'webhook', code: {:?}, run: function() {{ {} }}}}",
+                                          code, run));
+            }
+            "touch" => {
+                let id = action.def_attr("sourceId", "");
+                let source = match action.def_attr("source", "") {
+                    "layout" => "body".into(),
+                    "region" => format!(".r{}", id.parse::<i32>().context("invalid source id")?),
+                    "widget" => format!("#m{}", id.parse::<i32>().context("invalid source id")?),
+                    src => bail!("unsupported touch action source {:?}", src),
+                };
+                self.actions.push(format!("{{trigger: 'touch', source: '{}', run: function() {{ {} }}}}",
+                                          source, run));
+            }
+            typ => log::warn!("layout: unsupported action trigger {:?}", typ),
+        }
+        Ok(())
+    }
+}
+
+/// Return the names of the cached files a layout needs to be shown.
+pub fn required_files(xlf: &Path) -> Result<Vec<String>> {
+    let tree = Element::from_reader(fs::File::open(xlf)?).context("parsing XLF")?;
+    let mut files = Vec::new();
+    if let Some(file) = tree.get_attr("background") {
+        files.push(file.to_owned());
+    }
+    for media in tree.find_all("region").chain(tree.find_all("drawer"))
+                     .flat_map(|region| region.find_all("media"))
+    {
+        match (media.get_attr("render"), media.get_attr("type")) {
+            (Some("html"), _) | (_, Some("text" | "ticker")) => {
+                files.push(format!("{}.html", media.def_attr("id", "")));
+            }
+            (_, Some("image" | "video" | "audio")) => {
+                if let Some(uri) = media.find("options").and_then(|opts| opts.find("uri")) {
+                    files.push(uri.text().to_owned());
+                }
+            }
+            _ => ()
+        }
+    }
+    Ok(files)
+}
+
+/// Get the JS properties for the validity window of a media, if it has one.
+/// The times are interpreted as local time, like the schedule.
+fn media_validity(media: &Element) -> String {
+    let mut result = String::new();
+    for (attr, prop) in [("fromDt", "from"), ("toDt", "to")] {
+        let Some(value) = media.get_attr(attr) else { continue };
+        match time::PrimitiveDateTime::parse(value, &TIME_FMT) {
+            Ok(dt) => result += &format!(" {}: Date.parse('{}T{:02}:{:02}:{:02}'),", prop,
+                                         dt.date(), dt.hour(), dt.minute(), dt.second()),
+            Err(_) => log::warn!("layout: media has invalid {} {:?}", attr, value),
+        }
+    }
+    result
+}
+
+/// Iframes never end by themselves, so they need a duration for the region
+/// to advance and eventually complete.
+fn iframe_duration(mid: i32, len: i32) -> i32 {
+    if len > 0 {
+        return len;
     }
+    log::warn!("layout: media {} has no duration, using {} seconds", mid, DEFAULT_DURATION);
+    DEFAULT_DURATION
+}
+
+/// Determine the duration of a media from the information given by its
+/// resource, if any.
+fn resource_duration(resource: Option<&ResourceDuration>, len: i32, per_item: bool) -> Option<i32> {
+    let resource = resource?;
+    if let Some(duration) = resource.duration.filter(|&d| d > 0.0) {
+        return Some(duration.ceil() as i32);
+    }
+    let numitems = resource.numitems.filter(|&n| n > 0 && per_item && len > 0)?;
+    Some(len * numitems as i32)
+}
+
+/// Whether the region is set to loop its media instead of completing.
+fn region_loops(region: &Element) -> bool {
+    region.find("options").and_then(|opts| opts.find("loop")).map_or(false, |el| el.text() == "1")
 }
 
-fn object_fit(el: &Element) -> &'static str {
+/// Return the schema version of the XLF if it is newer than supported.
+fn unsupported_schema(layout: &Element) -> Option<u32> {
+    layout.parse_attr::<u32>("schemaVersion").ok().filter(|&v| v > MAX_SCHEMA_VERSION)
+}
+
+/// Gather information to determine the stacking of a region.
+fn region_layer(region: &Element, drawer: bool) -> Option<Layer> {
+    let geom = [region.parse_attr("left").ok()?, region.parse_attr("top").ok()?,
+                region.parse_attr("width").ok()?, region.parse_attr("height").ok()?];
+    // regions showing iframes or with touch actions can capture pointer events
+    let interactive = region.find_all("media").any(|media| {
+        media.get_attr("render") == Some("html") ||
+            matches!(media.get_attr("type"), Some("webpage" | "text" | "ticker")) ||
+            media.find_all("action").any(|action| action.get_attr("triggerType") == Some("touch"))
+    }) || region.find_all("action").any(|action| action.get_attr("triggerType") == Some("touch"));
+    Some(Layer {
+        id: region.parse_attr("id").ok()?,
+        zindex: region.def_attr("zindex", "0").parse().unwrap_or(0),
+        geom, drawer, interactive,
+    })
+}
+
+/// Determine the stacking order of regions.  Drawers are always on top.
+/// Pointer events are only enabled for interactive regions that are not
+/// overlapped by another region higher up.
+fn compute_stacking(mut layers: Vec<Layer>, order: RegionOrder) -> HashMap<i32, Stacking> {
+    match order {
+        RegionOrder::Explicit => layers.sort_by_key(|l| (l.drawer, l.zindex)),
+        RegionOrder::Id => layers.sort_by_key(|l| (l.drawer, l.id)),
+        RegionOrder::Position => layers.sort_by_key(|l| (l.drawer, l.geom[1], l.geom[0])),
+    }
+    layers.iter().enumerate().map(|(i, layer)| {
+        let covered = layers[i+1..].iter().any(|above| overlaps(above.geom, layer.geom));
+        (layer.id, Stacking { zindex: i + 1, pointer_events: layer.interactive && !covered })
+    }).collect()
+}
+
+fn overlaps([x1, y1, w1, h1]: [i32; 4], [x2, y2, w2, h2]: [i32; 4]) -> bool {
+    x1 < x2 + w2 && x2 < x1 + w1 && y1 < y2 + h2 && y2 < y1 + h1
+}
+
+/// Start offset into a video or audio file, in seconds.
+fn media_offset(opts: &Element) -> f64 {
+    opts.find("seekTo").or_else(|| opts.find("from"))
+        .and_then(|el| el.text().parse::<f64>().ok())
+        .filter(|&v| v > 0.0).unwrap_or(0.0)
+}
+
+fn object_fit(el: &Element, default: ObjectFit) -> &'static str {
     match el.find("scaleType") {
-        Some(e) if e.text() == "stretch" => " object-fit: fill;",
-        _ => " object-fit: contain;",
+        Some(e) if e.text() == "stretch" => ObjectFit::Fill.css(),
+        Some(_) => ObjectFit::Contain.css(),
+        None => default.css(),
     }
 }
 
@@ -236,3 +987,494 @@ fn object_pos(el: &Element) -> &'static str {
         _ => "",
     }
 }
+
+
+#[cfg(test)]
+fn translate_str(name: &str, xlf: &str, options: Options) -> String {
+    let dir = std::env::temp_dir();
+    let xlf_path = dir.join(format!("arexibo-test-{}.xlf", name));
+    let html_path = dir.join(format!("arexibo-test-{}.xlf.html", name));
+    fs::write(&xlf_path, xlf).unwrap();
+    Translator::new(&xlf_path, &html_path, options, KnownDurations::default())
+        .unwrap().translate().unwrap();
+    fs::read_to_string(&html_path).unwrap()
+}
+
+#[test]
+fn test_drawer() {
+    let html = translate_str("drawer", r#"<layout width="1920" height="1080">
+<region id="1" left="0" top="0" width="1920" height="1080">
+  <media id="10" type="image" duration="10"><options><uri>a.png</uri></options></media>
+</region>
+<drawer id="2" left="0" top="0" width="960" height="540">
+  <media id="20" type="image" duration="5"><options><uri>b.png</uri></options></media>
+</drawer>
+<action actionType="navWidget" triggerType="webhook" triggerCode="show" widgetId="20"/>
+</layout>"#, Options::default());
+    // the drawer is registered, but not counted and not started
+    assert!(html.contains("regions[2] = {index: -1, timer: null, drawer: true"));
+    assert!(html.contains("region_switch(1, 0);"));
+    assert!(!html.contains("region_switch(2, 0);"));
+    assert_eq!(html.matches("regions_total += 1;").count(), 1);
+    // the action shows the drawer's widget
+    assert!(html.contains("actions.push({trigger: 'webhook', code: \"show\", \
+                           run: function() { widget_show(20); }});"));
+}
+
+#[test]
+fn test_stats_option() {
+    let xlf = r#"<layout width="1920" height="1080">
+<region id="1" left="0" top="0" width="1920" height="1080">
+  <media id="10" type="image" duration="10"><options><uri>a.png</uri></options></media>
+</region>
+</layout>"#;
+    let html = translate_str("stats-off", xlf, Options::default());
+    assert!(!html.contains("media_stat"));
+    let html = translate_str("stats-on", xlf, Options { stats_enabled: true, ..Default::default() });
+    assert!(html.contains("media_stat('start', 10);"));
+    assert!(html.contains("media_stat('stop', 10);"));
+    let none = KnownDurations::default();
+    assert_ne!(Options::default().key(&none),
+               Options { stats_enabled: true, ..Default::default() }.key(&none));
+}
+
+#[test]
+fn test_region_stacking() {
+    let xlf = r#"<layout width="1920" height="1080">
+<region id="1" left="0" top="0" width="1920" height="1080" zindex="2">
+  <media id="10" type="webpage" duration="10"><options><uri>http://a</uri></options></media>
+</region>
+<region id="2" left="100" top="100" width="500" height="500" zindex="1">
+  <media id="20" type="webpage" duration="10"><options><uri>http://b</uri></options></media>
+</region>
+<region id="3" left="1000" top="800" width="100" height="100">
+  <media id="30" type="image" duration="10"><options><uri>c.png</uri></options></media>
+</region>
+</layout>"#;
+    let html = translate_str("stacking-explicit", xlf, Options::default());
+    assert!(html.contains(".r3 { z-index: 1; pointer-events: none; }"));
+    assert!(html.contains(".r2 { z-index: 2; pointer-events: none; }"));
+    assert!(html.contains(".r1 { z-index: 3; pointer-events: auto; }"));
+    let html = translate_str("stacking-id", xlf, Options { region_order: RegionOrder::Id,
+                                                           ..Default::default() });
+    assert!(html.contains(".r1 { z-index: 1; pointer-events: none; }"));
+    assert!(html.contains(".r2 { z-index: 2; pointer-events: auto; }"));
+    assert!(html.contains(".r3 { z-index: 3; pointer-events: none; }"));
+}
+
+#[test]
+fn test_schema_version() {
+    let xlf = r#"<layout width="1920" height="1080" schemaVersion="99"></layout>"#;
+    let tree = Element::from_reader(xlf.as_bytes()).unwrap();
+    assert_eq!(unsupported_schema(&tree), Some(99));
+    let tree = Element::from_reader(&br#"<layout schemaVersion="3"/>"#[..]).unwrap();
+    assert_eq!(unsupported_schema(&tree), None);
+
+    // by default, the layout is translated anyway
+    assert!(translate_str("schema", xlf, Options::default()).contains("</html>"));
+    // in strict mode, it is refused
+    let path = std::env::temp_dir().join("arexibo-test-schema.xlf");
+    let html = std::env::temp_dir().join("arexibo-test-schema-strict.xlf.html");
+    let options = Options { strict_schema: true, ..Default::default() };
+    assert!(Translator::new(&path, &html, options, KnownDurations::default()).is_err());
+    assert!(!html.exists());
+}
+
+#[test]
+fn test_media_error() {
+    let html = translate_str("media-error", r#"<layout width="1920" height="1080">
+<region id="1" left="0" top="0" width="1920" height="1080">
+  <media id="10" type="video" duration="0"><options><uri>a.mp4</uri></options></media>
+  <media id="11" type="image" duration="10"><options><uri>a.png</uri></options></media>
+</region>
+</layout>"#, Options::default());
+    // a video that fails to play reports the error and lets the region advance
+    assert!(html.contains("onerror='media_error(1, 10, this.error ? this.error.code : 0)'"));
+}
+
+#[test]
+fn test_video_offset() {
+    let html = translate_str("offset", r#"<layout width="100" height="100" schemaVersion="3">
+<region id="1" width="100" height="100" top="0" left="0">
+  <media id="10" type="video" duration="20"><options><uri>a.mp4</uri><mute>1</mute>
+    <seekTo>12.5</seekTo></options></media>
+  <media id="11" type="video" duration="20"><options><uri>b.mp4</uri><mute>1</mute></options></media>
+</region>
+</layout>"#, Options::default());
+    // the offset is applied before starting, and when rewinding at the end
+    assert!(html.contains("document.querySelector('#m10').currentTime = 12.5; \
+                           media_play(10);"));
+    assert!(html.contains("e.target.fastSeek(12.5);"));
+    assert!(!html.contains("document.querySelector('#m11').currentTime"));
+    assert!(html.contains("e.target.fastSeek(0);"));
+
+    let html = translate_str("offset-audio", r#"<layout width="100" height="100" schemaVersion="3">
+<region id="1" width="100" height="100" top="0" left="0">
+  <media id="10" type="audio" duration="0"><options><uri>a.mp3</uri><mute>1</mute>
+    <seekTo>30</seekTo></options></media>
+</region>
+</layout>"#, Options::default());
+    assert!(html.contains("document.querySelector('#m10').currentTime = 30; "));
+}
+
+#[test]
+fn test_shell_command() {
+    let xlf = r#"<layout width="100" height="100" schemaVersion="3">
+<region id="1" width="100" height="100" top="0" left="0">
+  <media id="10" type="shellcommand" duration="10"><options>
+    <useGlobalCommand>1</useGlobalCommand><globalCommand>reboot</globalCommand></options></media>
+  <media id="11" type="shellcommand" duration="10"><options>
+    <useGlobalCommand>1</useGlobalCommand><globalCommand>missing</globalCommand></options></media>
+</region>
+</layout>"#;
+    let options = Options { commands: ["reboot".into()].into(), ..Default::default() };
+    let html = translate_str("command", xlf, options);
+    assert!(html.contains(r#"postMessage("command:reboot");"#));
+    // media referencing an undefined command is skipped
+    assert!(!html.contains("id='m11'"));
+    assert!(!html.contains("command:missing"));
+}
+
+#[test]
+fn test_nav_widget_index() {
+    let html = translate_str("navindex", r#"<layout width="100" height="100">
+<region id="1" left="0" top="0" width="100" height="100">
+  <media id="10" type="image" duration="10"><options><uri>a.png</uri></options></media>
+  <media id="11" type="unknown" duration="10"><options/></media>
+  <media id="12" type="image" duration="10"><options><uri>b.png</uri></options></media>
+</region>
+<action actionType="navWidget" triggerType="webhook" triggerCode="b" widgetId="12"/>
+<action actionType="navWidget" triggerType="webhook" triggerCode="x" widgetId="99"/>
+</layout>"#, Options::default());
+    // the index skips the media that could not be translated
+    assert!(html.contains("actions.push({trigger: 'webhook', code: \"b\", \
+                           run: function() { region_switch(1, 1); }});"));
+    // actions for widgets that don't exist are dropped
+    assert!(!html.contains("code: \"x\""));
+}
+
+#[test]
+fn test_webpage_duration() {
+    let html = translate_str("webpage", r#"<layout width="100" height="100">
+<region id="1" left="0" top="0" width="100" height="100">
+  <media id="10" type="webpage" duration="0"><options><uri>https%3A%2F%2Fexample.com</uri></options></media>
+  <media id="11" type="webpage" duration="30"><options><uri>https%3A%2F%2Fexample.org</uri></options></media>
+</region>
+</layout>"#, Options::default());
+    // the region is counted for completion, and each page has a duration
+    assert!(html.contains("regions_total += 1;"));
+    assert!(html.contains("{id: 10, duration: 10,"));
+    assert!(html.contains("{id: 11, duration: 30,"));
+}
+
+#[test]
+fn test_pause_script() {
+    let html = translate_str("pause", r#"<layout width="100" height="100">
+<region id="1" left="0" top="0" width="100" height="100">
+  <media id="10" type="image" duration="10"><options><uri>a.png</uri></options></media>
+</region>
+</layout>"#, Options::default());
+    // all region timers go through region_timer, so that they can be paused
+    assert!(html.contains("function arexibo_pause()"));
+    assert!(html.contains("function arexibo_resume()"));
+    assert_eq!(html.matches("window.setTimeout(").count(), 1);
+    assert!(html.contains("region.deadline = Date.now() + ms;"));
+}
+
+#[test]
+fn test_interstitial() {
+    let xlf = r#"<layout width="100" height="100">
+<region id="1" left="0" top="0" width="100" height="100">
+  <media id="10" type="image" duration="10"><options><uri>a.png</uri></options></media>
+  <media id="11" type="image" duration="10"><options><uri>b.png</uri></options></media>
+</region>
+<action actionType="navWidget" triggerType="webhook" triggerCode="b" widgetId="11"/>
+</layout>"#;
+    let html = translate_str("no-interstitial", xlf, Options::default());
+    assert!(!html.contains("interstitial: true"));
+    let html = translate_str("interstitial", xlf, Options { interstitial: 250, ..Default::default() });
+    assert!(html.contains("{id: 'i10', duration: 0.25, interstitial: true,"));
+    assert!(html.contains("{id: 'i11', duration: 0.25, interstitial: true,"));
+    assert!(html.contains("<div class='media r1' id='mi10' style='left: 0px; top: 0px; \
+                           width: 100px; height: 100px; background: black;'></div>"));
+    // item indices account for the interstitials
+    assert!(html.contains("region_switch(1, 2);"));
+}
+
+#[test]
+fn test_autoplay_fallback() {
+    let xlf = r#"<layout width="100" height="100">
+<region id="1" left="0" top="0" width="100" height="100">
+  <media id="10" type="video" duration="10"><options><uri>a.mp4</uri><mute>1</mute></options></media>
+</region>
+</layout>"#;
+    let html = translate_str("autoplay", xlf, Options { autoplay_muted: true, ..Default::default() });
+    // playing goes through media_play, which handles a rejected play()
+    assert!(html.contains("media_play(10);"));
+    assert!(html.contains("promise.catch(function(e) {"));
+    assert!(html.contains("var autoplay_muted = true;"));
+    let html = translate_str("no-autoplay", xlf, Options::default());
+    assert!(html.contains("var autoplay_muted = false;"));
+}
+
+#[test]
+fn test_media_validity() {
+    let xlf = r#"<layout width="100" height="100">
+<region id="1" left="0" top="0" width="100" height="100">
+  <media id="10" type="image" duration="10" toDt="2020-01-31 18:00:00"><options><uri>a.png</uri></options></media>
+  <media id="11" type="image" duration="10"><options><uri>b.png</uri></options></media>
+</region>
+</layout>"#;
+    let html = translate_str("validity", xlf, Options::default());
+    assert!(html.contains("{id: 10, duration: 10, to: Date.parse('2020-01-31T18:00:00'), start:"));
+    assert!(html.contains("{id: 11, duration: 10, start:"));
+    // expired media is skipped, and validity is re-checked periodically
+    assert!(html.contains("!item_valid(region.items[index]) ||"));
+    assert!(html.contains("    window.setInterval(check_validity, 10000);"));
+    let xlf = xlf.replace(r#" toDt="2020-01-31 18:00:00""#, "");
+    let html = translate_str("no-validity", &xlf, Options::default());
+    assert!(!html.contains("window.setInterval("));
+}
+
+#[test]
+fn test_transitions() {
+    let xlf = r#"<layout width="100" height="100">
+<region id="1" left="0" top="0" width="100" height="100">
+  <media id="10" type="image" duration="10"><options><uri>a.png</uri>
+    <transIn>fadeIn</transIn><transInDuration>500</transInDuration>
+    <transOut>flyOut</transOut><transOutDirection>SE</transOutDirection>
+  </options></media>
+  <media id="11" type="image" duration="10"><options><uri>b.png</uri></options></media>
+</region>
+</layout>"#;
+    let html = translate_str("transitions", xlf, Options::default());
+    assert!(html.contains("start: function() { transition_in(10, 'fade', 500, \"N\");  }, \
+                           stop: function() { transition_out(10, 'fly', 1000, \"SE\");  }}"));
+    // without transitions, media switch instantly
+    assert!(html.contains("{id: 11, duration: 10, start: function() {  }, stop: function() {  }}"));
+}
+
+#[test]
+fn test_touch_actions() {
+    let xlf = r#"<layout width="100" height="100">
+<region id="1" left="0" top="0" width="100" height="100">
+  <media id="10" type="image" duration="10"><options><uri>a.png</uri></options>
+    <action actionType="navLayout" triggerType="touch" source="widget" sourceId="10" layoutCode="lobby"/>
+    <action actionType="navLayout" triggerType="webhook" triggerCode="x" layoutCode="gone"/>
+  </media>
+  <media id="11" type="image" duration="10"><options><uri>b.png</uri></options></media>
+  <action actionType="next" triggerType="touch" source="region" sourceId="1" targetId="1"/>
+</region>
+</layout>"#;
+    let options = Options { layout_codes: [("lobby".into(), 7)].into(), ..Default::default() };
+    let html = translate_str("touch", xlf, options);
+    // layout codes are resolved to IDs, unknown codes are skipped
+    assert!(html.contains("actions.push({trigger: 'touch', source: '#m10', run: function() { \
+                           window.webkit.messageHandlers.xibo.postMessage(\"layout:7\"); }});"));
+    assert!(!html.contains("gone"));
+    assert!(html.contains("actions.push({trigger: 'touch', source: '.r1', run: function() { \
+                           region_switch(1, -1); }});"));
+    assert!(html.contains("    touch_attach();"));
+    // the region must receive clicks although it only shows images
+    assert!(html.contains(".r1 { z-index: 1; pointer-events: auto; }"));
+}
+
+#[test]
+fn test_audio() {
+    let xlf = r#"<layout width="100" height="100">
+<region id="1" left="10" top="20" width="100" height="100">
+  <media id="10" type="audio" duration="0"><options><uri>a.mp3</uri></options></media>
+  <media id="11" type="audio" duration="30"><options><uri>b.mp3</uri><mute>1</mute><loop>1</loop></options></media>
+</region>
+</layout>"#;
+    let html = translate_str("audio", xlf, Options::default());
+    // the element has no size, and plays to its end without a given duration
+    assert!(html.contains("<audio class='media r1' id='m10' src='a.mp3'   onerror="));
+    assert!(html.contains("style='left: 10px; top: 20px; width: 0; height: 0;'></audio>"));
+    assert!(html.contains("{id: 10, duration: 0, start: function() { \
+                           document.querySelector('#m10').currentTime = 0; \
+                           document.querySelector('#m10').onended = () => { region_switch(1, -1); }; \
+                           window.webkit.messageHandlers.xibo.postMessage('play:10'); }"));
+    // looping with a fixed duration
+    assert!(html.contains("<audio class='media r1' id='m11' src='b.mp3' muted loop onerror="));
+    assert!(html.contains("{id: 11, duration: 30, start: function() { \
+                           document.querySelector('#m11').currentTime = 0; media_play(11); }"));
+}
+
+#[test]
+fn test_layout_error() {
+    let html = translate_str("layout-error", r#"<layout width="100" height="100">
+<region id="1" left="0" top="0" width="100" height="100">
+  <media id="10" type="image" duration="10"><options><uri>a.png</uri></options></media>
+</region>
+</layout>"#, Options::default());
+    assert!(html.contains("  try {\n    touch_attach();\n    region_switch(1, 0);\n  } catch (e) {\n    \
+                           window.webkit.messageHandlers.xibo.postMessage('layout_error:' + e);\n  }\n"));
+}
+
+#[test]
+fn test_resource_duration() {
+    let res = |duration, numitems| ResourceDuration { duration, numitems };
+    assert_eq!(resource_duration(None, 10, true), None);
+    assert_eq!(resource_duration(Some(&res(Some(42.5), Some(3))), 10, true), Some(43));
+    assert_eq!(resource_duration(Some(&res(None, Some(3))), 10, true), Some(30));
+    assert_eq!(resource_duration(Some(&res(None, Some(3))), 10, false), None);
+    assert_eq!(resource_duration(Some(&res(Some(0.), None)), 10, true), None);
+
+    let dir = std::env::temp_dir();
+    let xlf_path = dir.join("arexibo-test-resource-duration.xlf");
+    let html_path = dir.join("arexibo-test-resource-duration.xlf.html");
+    fs::write(&xlf_path, r#"<layout width="100" height="100">
+<region id="1" left="0" top="0" width="100" height="100">
+  <media id="10" type="ticker" duration="5"><options><durationIsPerItem>1</durationIsPerItem></options></media>
+</region>
+</layout>"#).unwrap();
+    let mut durations = KnownDurations::default();
+    durations.resources.insert(10, res(None, Some(4)));
+    Translator::new(&xlf_path, &html_path, Options::default(), durations)
+        .unwrap().translate().unwrap();
+    assert!(fs::read_to_string(&html_path).unwrap().contains("{id: 10, duration: 20, start:"));
+}
+
+#[test]
+fn test_empty_layout() {
+    let html = translate_str("empty", r#"<layout width="100" height="100">
+<region id="1" left="0" top="0" width="100" height="100">
+  <media id="10" type="unknown" duration="10"><options/></media>
+</region>
+<region id="2" left="0" top="0" width="100" height="100"/>
+</layout>"#, Options::default());
+    // the layout is done by itself, so that the rotation doesn't stall
+    assert!(html.contains("window.setTimeout(function() { \
+                           window.webkit.messageHandlers.xibo.postMessage('layout_done'); }, 1000);"));
+    assert!(!html.contains("region_switch(1, 0);"));
+}
+
+#[test]
+fn test_layout_meta() {
+    let dir = std::env::temp_dir();
+    let xlf_path = dir.join("arexibo-test-meta.xlf");
+    let html_path = dir.join("arexibo-test-meta.xlf.html");
+    fs::write(&xlf_path, r#"<layout width="1920" height="1080">
+<region id="1" left="0" top="0" width="1280" height="1080">
+  <media id="10" type="image" duration="10"><options><uri>a.png</uri></options></media>
+  <media id="11" type="video" duration="0"><options><uri>b.mp4</uri></options></media>
+  <media id="12" type="unknown" duration="10"><options/></media>
+</region>
+<drawer id="2" left="1280" top="0" width="640" height="1080">
+  <media id="20" type="webpage" duration="30"><options><uri>http://x/</uri></options></media>
+</drawer>
+</layout>"#).unwrap();
+    let meta = Translator::new(&xlf_path, &html_path, Options::default(), KnownDurations::default())
+        .unwrap().translate().unwrap();
+    let json = serde_json::to_value(&meta).unwrap();
+    assert_eq!(json, serde_json::json!({
+        "width": 1920, "height": 1080,
+        "regions": [
+            {"id": 1, "drawer": false, "left": 0, "top": 0, "width": 1280, "height": 1080,
+             "media": [{"id": 10, "type": "image", "duration": 10},
+                       {"id": 11, "type": "video", "duration": 0}]},
+            {"id": 2, "drawer": true, "left": 1280, "top": 0, "width": 640, "height": 1080,
+             "media": [{"id": 20, "type": "webpage", "duration": 30}]},
+        ]
+    }));
+}
+
+#[test]
+fn test_object_fit_default() {
+    let xlf = r#"<layout width="100" height="100">
+<region id="1" left="0" top="0" width="100" height="100">
+  <media id="10" type="image" duration="10"><options><uri>a.png</uri></options></media>
+  <media id="11" type="image" duration="10"><options><uri>b.png</uri><scaleType>center</scaleType></options></media>
+  <media id="12" type="video" duration="10"><options><uri>c.mp4</uri></options></media>
+</region>
+</layout>"#;
+    let options = Options { image_fit: ObjectFit::Cover, ..Default::default() };
+    let html = translate_str("fit", xlf, options.clone());
+    let style = |mid| html.lines().find(|l| l.contains(&format!("id='m{}'", mid))).unwrap().to_owned();
+    assert!(style(10).contains("object-fit: cover;"));
+    // explicit scale types are respected
+    assert!(style(11).contains("object-fit: contain;"));
+    assert!(style(12).contains("object-fit: contain;"));
+    let none = KnownDurations::default();
+    assert_ne!(options.key(&none), Options::default().key(&none));
+}
+
+#[test]
+fn test_region_loop() {
+    let html = translate_str("loop", r#"<layout width="100" height="100">
+<region id="1" left="0" top="0" width="100" height="100">
+  <options><loop>1</loop></options>
+  <media id="10" type="image" duration="10"><options><uri>a.png</uri></options></media>
+</region>
+<region id="2" left="0" top="0" width="100" height="100">
+  <options><loop>0</loop></options>
+  <media id="20" type="image" duration="30"><options><uri>b.png</uri></options></media>
+</region>
+</layout>"#, Options::default());
+    // only the second region determines when the layout is done
+    assert_eq!(html.matches("regions_total += 1;").count(), 1);
+    assert!(html.contains("regions[1] = {index: -1, timer: null, drawer: false, loop: true"));
+    assert!(html.contains("regions[2] = {index: -1, timer: null, drawer: false, loop: false"));
+
+    // if all regions loop, the layout must still be able to finish
+    let html = translate_str("loop-all", r#"<layout width="100" height="100">
+<region id="1" left="0" top="0" width="100" height="100">
+  <options><loop>1</loop></options>
+  <media id="10" type="image" duration="10"><options><uri>a.png</uri></options></media>
+</region>
+</layout>"#, Options::default());
+    assert_eq!(html.matches("regions_total += 1;").count(), 1);
+    assert!(html.contains("    regions[1].loop = false; regions_total += 1;"));
+
+    // the same if the other regions have nothing to show
+    let html = translate_str("loop-empty", r#"<layout width="100" height="100">
+<region id="1" left="0" top="0" width="100" height="100">
+  <options><loop>1</loop></options>
+  <media id="10" type="image" duration="10"><options><uri>a.png</uri></options></media>
+</region>
+<region id="2" left="0" top="0" width="100" height="100">
+  <media id="20" type="image" duration="10"><options></options></media>
+</region>
+</layout>"#, Options::default());
+    assert!(html.contains("    regions[1].loop = false; regions_total += 1;"));
+}
+
+#[test]
+fn test_video_end_black() {
+    let xlf = r#"<layout width="100" height="100">
+<region id="1" left="0" top="0" width="100" height="100">
+  <media id="10" type="video" duration="0"><options><uri>a.mp4</uri><mute>1</mute></options></media>
+</region>
+</layout>"#;
+    let html = translate_str("video-hold", xlf, Options::default());
+    assert!(!html.contains("brightness(0)"));
+    let options = Options { video_end: VideoEnd::Black, ..Default::default() };
+    let html = translate_str("video-black", xlf, options.clone());
+    assert!(html.contains("document.querySelector('#m10').onended = (e) => { \
+                           e.target.style.filter = 'brightness(0)'; e.target.fastSeek(0);"));
+    // the video is visible again when it is restarted
+    assert!(html.contains("document.querySelector('#m10').style.filter = '';"));
+    let none = KnownDurations::default();
+    assert_ne!(options.key(&none), Options::default().key(&none));
+}
+
+#[test]
+fn test_self_navigation() {
+    let dir = std::env::temp_dir().join(format!("arexibo-test-selfnav-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("7.xlf"), r#"<layout width="100" height="100">
+<region id="1" left="0" top="0" width="100" height="100">
+  <media id="10" type="image" duration="10"><options><uri>a.png</uri></options>
+    <action actionType="navLayout" triggerType="touch" source="widget" sourceId="10" layoutCode="7"/>
+    <action actionType="navLayout" triggerType="webhook" triggerCode="other" layoutCode="8"/>
+  </media>
+</region>
+</layout>"#).unwrap();
+    Translator::new(&dir.join("7.xlf"), &dir.join("7.xlf.html"), Options::default(),
+                    KnownDurations::default()).unwrap().translate().unwrap();
+    let html = fs::read_to_string(dir.join("7.xlf.html")).unwrap();
+    assert!(!html.contains("layout:7"));
+    assert!(html.contains("layout:8"));
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -3,19 +3,182 @@
 
 //! Xibo logger.
 
+use std::{collections::BTreeMap, fmt, fs, io::{self, Write}, path::{Path, PathBuf}};
+use std::time::{Duration, Instant};
 use time::{OffsetDateTime, util::local_offset};
 use parking_lot::Mutex;
+use crate::util::TIME_FMT;
+
+/// Size of the log file in bytes after which it is rotated.
+const LOG_FILE_SIZE: u64 = 5 * 1024 * 1024;
+/// Number of rotated log files to keep.
+const LOG_FILE_KEEP: usize = 3;
 
 /// A single cached log entry.
 pub struct LogEntry {
     pub date: OffsetDateTime,
     pub category: &'static str,
     pub message: String,
+    /// Type and ID of the file this entry refers to, if any.
+    pub file: Option<(&'static str, i64)>,
 }
 
 
 static LOG_ENTRIES: Mutex<Vec<LogEntry>> = Mutex::new(Vec::new());
 
+static DEDUP: Mutex<Dedup> = Mutex::new(Dedup::new(Duration::from_secs(60)));
+
+static LOG_FILE: Mutex<Option<LogFile>> = Mutex::new(None);
+
+/// Most verbose level of entries that are submitted to the CMS.
+static SUBMIT_LEVEL: Mutex<log::LevelFilter> = Mutex::new(log::LevelFilter::Trace);
+
+/// Translate a log level setting from the CMS.
+pub fn cms_level(name: &str) -> Option<log::LevelFilter> {
+    Some(match name {
+        "audit" | "trace" => log::LevelFilter::Trace,
+        "debug" => log::LevelFilter::Debug,
+        "info" | "notice" => log::LevelFilter::Info,
+        "warning" => log::LevelFilter::Warn,
+        "error" | "critical" | "alert" | "emergency" => log::LevelFilter::Error,
+        "off" => log::LevelFilter::Off,
+        _ => return None,
+    })
+}
+
+/// Set the most verbose level of entries that are submitted to the CMS.
+/// Entries already stored are filtered as well.
+pub fn set_submit_level(level: log::LevelFilter) {
+    *SUBMIT_LEVEL.lock() = level;
+    LOG_ENTRIES.lock().retain(|entry| is_submitted(entry.category, level));
+}
+
+/// Check if an entry of the given category should be submitted.
+fn is_submitted(category: &str, level: log::LevelFilter) -> bool {
+    category.parse::<log::Level>().map_or(true, |cat| cat <= level)
+}
+
+/// Also write log messages to the given file, rotating it when it gets large.
+pub fn set_log_file(path: PathBuf) -> io::Result<()> {
+    *LOG_FILE.lock() = Some(LogFile::open(path, LOG_FILE_SIZE, LOG_FILE_KEEP)?);
+    Ok(())
+}
+
+/// A log file that is rotated to `.1`, `.2`, ... when it exceeds a size.
+struct LogFile {
+    path: PathBuf,
+    file: fs::File,
+    size: u64,
+    max_size: u64,
+    keep: usize,
+}
+
+impl LogFile {
+    fn open(path: PathBuf, max_size: u64, keep: usize) -> io::Result<Self> {
+        let file = fs::OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self { path, file, size, max_size, keep })
+    }
+
+    fn write(&mut self, line: &str) -> io::Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 + 1 > self.max_size {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{}", line)?;
+        self.size += line.len() as u64 + 1;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let rotated = |i: usize| {
+            let mut path = self.path.as_os_str().to_owned();
+            path.push(format!(".{}", i));
+            PathBuf::from(path)
+        };
+        for i in (1..self.keep).rev() {
+            let _ = fs::rename(rotated(i), rotated(i + 1));
+        }
+        fs::rename(&self.path, rotated(1))?;
+        self.file = fs::File::create(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+/// Print a message to the console and the log file, if configured.
+fn output(level: &str, path: &str, message: &str) {
+    println!("{:5}: [{}] {}", level, path, message);
+    if let Some(file) = &mut *LOG_FILE.lock() {
+        let now = OffsetDateTime::now_local().unwrap_or_else(|_| OffsetDateTime::now_utc());
+        let line = format!("{} {:5}: [{}] {}", now.format(&TIME_FMT).unwrap_or_default(),
+                           level, path, message);
+        // there is no good place to report this
+        let _ = file.write(&line);
+    }
+}
+
+/// Set the window within which identical messages are collapsed (0 = never).
+pub fn set_dedup_window(secs: u64) {
+    DEDUP.lock().window = Duration::from_secs(secs);
+}
+
+/// Collapses identical messages logged within a time window.
+struct Dedup {
+    window: Duration,
+    /// Maps (level, message) to the start of its window and the number of
+    /// suppressed repetitions.
+    seen: BTreeMap<(&'static str, String), (Instant, u32)>,
+}
+
+impl Dedup {
+    const fn new(window: Duration) -> Self {
+        Self { window, seen: BTreeMap::new() }
+    }
+
+    /// Return the messages that should be logged for this one: nothing if it
+    /// is a repetition, else the message, preceded by a summary of the
+    /// repetitions in the last window if there were any.
+    fn filter(&mut self, now: Instant, level: &'static str, message: String) -> Vec<String> {
+        if self.window.is_zero() {
+            return vec![message];
+        }
+        let window = self.window;
+        let mut result = Vec::new();
+        // forget messages whose window has expired without repetitions
+        self.seen.retain(|(_, msg), (start, count)| {
+            now.duration_since(*start) < window || *count > 0 || *msg == message
+        });
+        let key = (level, message);
+        if let Some((start, count)) = self.seen.get_mut(&key) {
+            if now.duration_since(*start) < window {
+                *count += 1;
+                return result;
+            }
+            if *count > 0 {
+                result.push(repeated(&key.1, *count));
+            }
+            *start = now;
+            *count = 0;
+        } else {
+            self.seen.insert(key.clone(), (now, 0));
+        }
+        result.push(key.1);
+        result
+    }
+
+    /// Return summaries for all suppressed repetitions, and reset the counts.
+    fn flush(&mut self) -> Vec<(&'static str, String)> {
+        self.seen.iter_mut()
+                 .filter(|(_, (_, count))| *count > 0)
+                 .map(|((level, msg), (_, count))| (*level, repeated(msg, std::mem::take(count))))
+                 .collect()
+    }
+}
+
+fn repeated(message: &str, count: u32) -> String {
+    format!("{} (repeated {} times)", message, count)
+}
+
 /// Xibo logger, logs to console and stores entries for transfer to
 /// the display.
 pub struct Logger;
@@ -26,32 +189,81 @@ impl log::Log for Logger {
     }
 
     fn log(&self, record: &log::Record) {
-        // filter out messages not from our modules
-        let path = record.module_path().unwrap_or("");
-        if !path.starts_with("arexibo") {
-            return;
-        }
+        log_record(record, None);
+    }
+
+    fn flush(&self) {}
+}
 
-        // print to console
-        println!("{:5}: [{}] {}", record.level(), path, record.args());
+/// Log a record, optionally concerning a specific file.
+fn log_record(record: &log::Record, file: Option<(&'static str, i64)>) {
+    // filter out messages not from our modules
+    let path = record.module_path().unwrap_or("");
+    if !path.starts_with("arexibo") {
+        return;
+    }
+
+    let level = record.level().as_str();
+    let messages = DEDUP.lock().filter(Instant::now(), level, record.args().to_string());
+    for message in messages {
+        // print to console and file
+        output(level, path, &message);
 
         // add to stashed entries for submission to CMS
-        let mut entries = LOG_ENTRIES.lock();
-        // avoid taking up arbitrary amounts of memory
-        if entries.len() > 1000 {
-            entries.drain(0..500).for_each(drop);
-        }
-        entries.push(LogEntry {
-            date: OffsetDateTime::now_local().unwrap(),
-            category: record.level().as_str(),
-            message: record.args().to_string(),
+        push_entry(LogEntry {
+            date: OffsetDateTime::now_local().unwrap_or_else(|_| OffsetDateTime::now_utc()),
+            category: level,
+            message,
+            file,
         });
     }
+}
 
-    fn flush(&self) {}
+fn push_entry(entry: LogEntry) {
+    // don't keep entries that won't be submitted
+    if !is_submitted(entry.category, *SUBMIT_LEVEL.lock()) {
+        return;
+    }
+    let mut entries = LOG_ENTRIES.lock();
+    // avoid taking up arbitrary amounts of memory
+    if entries.len() > 1000 {
+        entries.drain(0..500).for_each(drop);
+    }
+    entries.push(entry);
+}
+
+/// Log an error concerning a specific file, so that the CMS can relate it.
+#[macro_export]
+macro_rules! file_error {
+    ($file:expr, $($arg:tt)+) => {
+        $crate::logger::log_file_error($file, module_path!(), format_args!($($arg)+))
+    };
+}
+
+#[doc(hidden)]
+pub fn log_file_error(file: (&'static str, i64), module: &'static str, args: fmt::Arguments) {
+    if log::Level::Error > log::max_level() {
+        return;
+    }
+    log_record(&log::Record::builder()
+               .args(args)
+               .level(log::Level::Error)
+               .target(module)
+               .module_path_static(Some(module))
+               .build(), Some(file));
 }
 
 pub fn pop_entries() -> Vec<LogEntry> {
+    // include pending repetition counts in this submission
+    let summaries = DEDUP.lock().flush();
+    for (category, message) in summaries {
+        push_entry(LogEntry {
+            date: OffsetDateTime::now_local().unwrap_or_else(|_| OffsetDateTime::now_utc()),
+            category,
+            message,
+            file: None,
+        });
+    }
     std::mem::take(&mut LOG_ENTRIES.lock())
 }
 
@@ -61,3 +273,68 @@ pub fn init() {
         local_offset::set_soundness(local_offset::Soundness::Unsound);
     }
 }
+
+#[test]
+fn test_dedup() {
+    let mut dedup = Dedup::new(Duration::from_secs(60));
+    let t0 = Instant::now();
+    let msg = || "unsupported media type".to_string();
+    assert_eq!(dedup.filter(t0, "WARN", msg()), vec![msg()]);
+    for i in 1..=42 {
+        assert!(dedup.filter(t0 + Duration::from_secs(i), "WARN", msg()).is_empty());
+    }
+    // other messages and levels are not affected
+    assert_eq!(dedup.filter(t0, "WARN", "other".into()), vec!["other".to_string()]);
+    assert_eq!(dedup.filter(t0, "ERROR", msg()), vec![msg()]);
+    // after the window, the count is reported along with the new message
+    assert_eq!(dedup.filter(t0 + Duration::from_secs(61), "WARN", msg()),
+               vec!["unsupported media type (repeated 42 times)".to_string(), msg()]);
+    assert!(dedup.filter(t0 + Duration::from_secs(62), "WARN", msg()).is_empty());
+    assert_eq!(dedup.flush(), vec![("WARN", "unsupported media type (repeated 1 times)".into())]);
+    assert!(dedup.flush().is_empty());
+
+    let mut disabled = Dedup::new(Duration::ZERO);
+    assert_eq!(disabled.filter(t0, "WARN", msg()), vec![msg()]);
+    assert_eq!(disabled.filter(t0, "WARN", msg()), vec![msg()]);
+}
+
+#[test]
+fn test_submit_level() {
+    assert_eq!(cms_level("audit"), Some(log::LevelFilter::Trace));
+    assert_eq!(cms_level("error"), Some(log::LevelFilter::Error));
+    assert_eq!(cms_level("off"), Some(log::LevelFilter::Off));
+    assert_eq!(cms_level("loud"), None);
+
+    let level = cms_level("error").unwrap();
+    assert!(is_submitted("ERROR", level));
+    assert!(!is_submitted("WARN", level));
+    assert!(!is_submitted("DEBUG", level));
+    assert!(is_submitted("DEBUG", cms_level("debug").unwrap()));
+    assert!(!is_submitted("ERROR", cms_level("off").unwrap()));
+}
+
+#[test]
+fn test_log_file_rotation() {
+    let dir = std::env::temp_dir().join("arexibo-test-logfile");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("arexibo.log");
+    let mut file = LogFile::open(path.clone(), 100, 2).unwrap();
+    for i in 0..10 {
+        file.write(&format!("{:029}", i)).unwrap();
+    }
+    // three lines fit into one file; only two rotations are kept
+    let read = |name: &str| fs::read_to_string(dir.join(name)).unwrap();
+    assert_eq!(read("arexibo.log"), format!("{:029}\n", 9));
+    assert_eq!(read("arexibo.log.1").lines().count(), 3);
+    assert!(read("arexibo.log.1").starts_with(&format!("{:029}", 6)));
+    assert!(read("arexibo.log.2").starts_with(&format!("{:029}", 3)));
+    assert!(!dir.join("arexibo.log.3").exists());
+    // appending continues with the existing size
+    drop(file);
+    let mut file = LogFile::open(path, 100, 2).unwrap();
+    assert_eq!(file.size, 30);
+    file.write("x").unwrap();
+    assert_eq!(read("arexibo.log").lines().count(), 2);
+    fs::remove_dir_all(&dir).unwrap();
+}
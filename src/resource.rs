@@ -3,11 +3,12 @@
 
 //! Handling resources such as media and layout files.
 
-use std::collections::HashMap;
-use std::{fs, io::Read, path::PathBuf, sync::Arc};
-use anyhow::{ensure, Context, Result};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::{fs, io::Read, path::PathBuf, sync::Arc, time::Duration};
+use anyhow::{bail, ensure, Context, Result};
 use md5::{Md5, Digest};
 use serde::{Serialize, Deserialize};
+use time::OffsetDateTime;
 use ureq::Agent;
 use crate::{util, layout, xmds};
 use crate::config::CmsSettings;
@@ -48,6 +49,23 @@ impl ReqFile {
             ReqFile::Resource { id, .. } => ("resource", *id),
         }
     }
+
+    /// Return the ID of the media a resource belongs to.
+    pub fn resource_media(&self) -> Option<i64> {
+        match self {
+            ReqFile::Resource { mediaid, .. } => Some(*mediaid),
+            ReqFile::File { .. } => None,
+        }
+    }
+
+    /// Check the file against the maximum allowed size in MiB (0 = no limit).
+    pub fn check_size(&self, max_mb: u64) -> Result<()> {
+        if let ReqFile::File { size, .. } = self {
+            ensure!(max_mb == 0 || *size <= max_mb * 1024 * 1024,
+                    "file size of {} bytes exceeds the maximum of {} MiB", size, max_mb);
+        }
+        Ok(())
+    }
 }
 
 
@@ -57,14 +75,17 @@ pub struct LayoutInfo {
     #[serde(deserialize_with = "util::de_hex", serialize_with = "util::ser_hex")]
     pub md5: Vec<u8>,
     pub size: (i32, i32),
+    #[serde(default)]
+    pub translation_key: u64,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
 pub struct MediaInfo {
     pub id: i64,
     pub size: u64,
     #[serde(deserialize_with = "util::de_hex", serialize_with = "util::ser_hex")]
     pub md5: Vec<u8>,
+    pub duration: Option<f64>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -74,6 +95,8 @@ pub struct ResourceInfo {
     pub regionid: i64,
     pub updated: i64,
     pub duration: Option<f64>,
+    #[serde(default)]
+    pub numitems: Option<u32>,
 }
 
 /// A resource in the local cache.
@@ -85,10 +108,97 @@ pub enum Resource {
 }
 
 
+/// Number of downloads failing with "not found" after which a file is
+/// blacklisted.
+const NOT_FOUND_LIMIT: u32 = 3;
+
+/// Classification of a failed HTTP download.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum HttpFailure {
+    /// The file was deleted on the server.
+    NotFound(u16),
+    /// Authentication or authorization failed.
+    Denied(u16),
+    /// Any other error status.
+    Status(u16),
+    /// No response, e.g. connection refused or reset.
+    Connection,
+    /// Error while receiving or processing the data.
+    Other,
+}
+
+impl HttpFailure {
+    fn classify(err: &anyhow::Error) -> Self {
+        match err.downcast_ref::<ureq::Error>() {
+            Some(ureq::Error::Status(code @ (404 | 410), _)) => HttpFailure::NotFound(*code),
+            Some(ureq::Error::Status(code @ (401 | 403), _)) => HttpFailure::Denied(*code),
+            Some(ureq::Error::Status(code, _)) => HttpFailure::Status(*code),
+            Some(ureq::Error::Transport(_)) => HttpFailure::Connection,
+            None => HttpFailure::Other,
+        }
+    }
+
+    /// Whether retrying the download might succeed.
+    fn is_transient(&self) -> bool {
+        matches!(self, HttpFailure::Status(500..=599) | HttpFailure::Connection | HttpFailure::Other)
+    }
+}
+
+impl std::fmt::Display for HttpFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            HttpFailure::NotFound(code) => write!(f, "file not found on server (HTTP {})", code),
+            HttpFailure::Denied(code) => write!(f, "access denied (HTTP {})", code),
+            HttpFailure::Status(code) => write!(f, "server error (HTTP {})", code),
+            HttpFailure::Connection => f.write_str("connection error"),
+            HttpFailure::Other => f.write_str("transfer error"),
+        }
+    }
+}
+
+/// Downloads files over HTTP, retrying transient failures.
+#[derive(Clone)]
+struct HttpFetcher {
+    agent: Agent,
+    retries: u32,
+    retry_delay: Duration,
+}
+
+impl HttpFetcher {
+    fn fetch(&self, path: &str) -> Result<Vec<u8>> {
+        let mut attempt = 0;
+        loop {
+            let mut data = Vec::new();
+            let result = self.agent.get(path).call().map_err(anyhow::Error::from)
+                .and_then(|resp| Ok(resp.into_reader().read_to_end(&mut data)?));
+            let Err(e) = result else { return Ok(data) };
+            let failure = HttpFailure::classify(&e);
+            if attempt == self.retries || !failure.is_transient() {
+                return Err(e);
+            }
+            attempt += 1;
+            log::info!("download failed ({}), retry {}/{} in {:?}", failure, attempt,
+                       self.retries, self.retry_delay);
+            std::thread::sleep(self.retry_delay);
+        }
+    }
+}
+
 pub struct Cache {
     dir: PathBuf,
-    agent: Agent,
+    http: HttpFetcher,
     content: HashMap<String, Resource>,
+    layout_options: layout::Options,
+    /// Layout codes from the CMS, mapped to layout IDs.
+    layout_codes: BTreeMap<String, i64>,
+    /// Number of "not found" failures per file ID.
+    not_found: HashMap<i64, u32>,
+    /// Names of the files required in the current collect cycle.
+    required: HashSet<String>,
+    /// Last time (Unix timestamp) each file was required or downloaded.
+    last_used: HashMap<String, i64>,
+    /// Free space in MiB to keep when downloading.
+    min_free_space: u64,
 }
 
 impl Cache {
@@ -113,7 +223,40 @@ impl Cache {
             content.retain(|fname, _| dir.join(fname).is_file());
         }
 
-        Ok(Self { dir, agent: cms.make_agent()?, content })
+        // until files are required again, the download time is the last use
+        let last_used = content.keys().filter_map(|fname| {
+            let mtime = fs::metadata(dir.join(fname)).and_then(|m| m.modified()).ok()?;
+            Some((fname.clone(), OffsetDateTime::from(mtime).unix_timestamp()))
+        }).collect();
+
+        let http = HttpFetcher { agent: cms.make_agent()?, retries: cms.download_retries,
+                                 retry_delay: Duration::from_secs(cms.download_retry_delay) };
+        Ok(Self { dir, http, content, layout_options: Default::default(),
+                  layout_codes: BTreeMap::new(),
+                  not_found: HashMap::new(), required: HashSet::new(), last_used,
+                  min_free_space: cms.min_free_space })
+    }
+
+    /// Remove all cached files, so that they are downloaded again.  Only
+    /// files created by the cache are removed, the directory may be shared.
+    pub fn purge(&mut self) -> Result<()> {
+        for (name, res) in self.content.drain() {
+            let mut files = vec![name.clone()];
+            if let Resource::Layout(info) = res {
+                files.push(format!("{}.html", name));
+                files.push(format!("{}.meta.json", info.id));
+            }
+            for file in files {
+                match fs::remove_file(self.dir.join(&file)) {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound =>
+                        log::warn!("could not remove {}: {}", file, e),
+                    _ => ()
+                }
+            }
+        }
+        self.not_found.clear();
+        self.last_used.clear();
+        self.save()
     }
 
     pub fn dir(&self) -> &PathBuf {
@@ -135,6 +278,19 @@ impl Cache {
         }
     }
 
+    /// Set the files required in this collect cycle, which are protected from
+    /// removal when disk space runs low.
+    pub fn set_required(&mut self, files: &[ReqFile]) {
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        self.required = files.iter().filter_map(|file| match file {
+            ReqFile::File { name, .. } => Some(name.clone()),
+            ReqFile::Resource { .. } => None,
+        }).collect();
+        for name in &self.required {
+            self.last_used.insert(name.clone(), now);
+        }
+    }
+
     pub fn download(&mut self, res: ReqFile, cms: &mut xmds::Cms) -> Result<()> {
         match res {
             ReqFile::Resource { id, layoutid, regionid, mediaid, updated } => {
@@ -142,57 +298,324 @@ impl Cache {
                                             &mediaid.to_string())?;
                 let fname = format!("{}.html", id);
 
-                // TODO:
-                // - process (replace [[ViewPort]], get DURATION)
-                // - re-download after given updateInterval
-                let duration = None;
+                // TODO: re-download after given updateInterval
+                let duration = html_comment_value(&data, "DURATION");
+                let numitems = html_comment_value(&data, "NUMITEMS");
+                let width = self.get_layout(layoutid).map(|layout| layout.size.0);
+                let data = inject_fonts(&replace_viewport(&data, width), &self.font_css());
                 fs::write(self.dir.join(&fname), data)?;
                 self.content.insert(fname, Resource::Resource(Arc::new(
-                    ResourceInfo { id, layoutid, regionid, updated, duration }
+                    ResourceInfo { id, layoutid, regionid, updated, duration, numitems }
                 )));
                 self.save()?;
+                // the layout's timing depends on the resource
+                if duration.is_some() || numitems.is_some() {
+                    if let Some(layout) = self.get_layout(layoutid) {
+                        self.translate_layout(format!("{}.xlf", layoutid), layoutid,
+                                              layout.md5.clone())?;
+                        self.save()?;
+                    }
+                }
             }
-            ReqFile::File { id, typ, http, size, md5, path, name } => {
-                let data = if http {
-                    match self.download_http(&path) {
-                        Ok(data) => data,
-                        Err(e) => {
-                            log::warn!("failing download of {} over http, retrying \
-                                        xmds: {:#}", name, e);
-                            self.download_xmds(id, typ, size, cms)?
-                        }
+            ReqFile::File { http, size, ref path, .. } => {
+                self.make_room(size);
+                let fetched = http.then(|| self.http.fetch(path));
+                self.store_file(res, fetched, cms)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Download several files, returning the results in the same order.
+    ///
+    /// HTTP downloads run in parallel on the given number of worker threads.
+    /// XMDS downloads, which share one connection, are done one after another,
+    /// as well as the XMDS fallback for failed HTTP downloads.
+    pub fn download_many(&mut self, files: Vec<ReqFile>, cms: &mut xmds::Cms,
+                         workers: usize) -> Vec<Result<()>> {
+        let (job_tx, job_rx) = crossbeam_channel::unbounded();
+        let mut http_size = 0;
+        for (i, file) in files.iter().enumerate() {
+            if let ReqFile::File { http: true, path, size, .. } = file {
+                job_tx.send((i, path.clone())).unwrap();
+                http_size += size;
+            }
+        }
+        drop(job_tx);
+        self.make_room(http_size);
+
+        let fetcher = self.http.clone();
+        let mut results = files.iter().map(|_| None).collect::<Vec<_>>();
+        let mut files = files.into_iter().map(Some).collect::<Vec<_>>();
+        std::thread::scope(|scope| {
+            // limit the number of downloaded files held in memory
+            let (data_tx, data_rx) = crossbeam_channel::bounded(workers);
+            for _ in 0..workers.max(1) {
+                let (job_rx, data_tx, fetcher) = (job_rx.clone(), data_tx.clone(), &fetcher);
+                scope.spawn(move || {
+                    for (i, path) in job_rx {
+                        let _ = data_tx.send((i, fetcher.fetch(&path)));
                     }
-                } else {
-                    self.download_xmds(id, typ, size, cms)?
-                };
-                ensure!(Md5::digest(&data).as_slice() == md5, "md5 mismatch");
-                fs::write(self.dir.join(&name), data)?;
+                });
+            }
+            drop(data_tx);
+            for (file, result) in files.iter_mut().zip(&mut results) {
+                if !matches!(file, Some(ReqFile::File { http: true, .. })) {
+                    *result = Some(self.download(file.take().unwrap(), cms));
+                }
+            }
+            for (i, fetched) in data_rx {
+                results[i] = Some(self.store_file(files[i].take().unwrap(), Some(fetched), cms));
+            }
+        });
+        results.into_iter().map(|r| r.expect("all files are downloaded")).collect()
+    }
 
-                if typ == "layout" {
-                    // translate the layout into HTML
-                    let xl = layout::Translator::new(
-                        &self.dir.join(&name),
-                        &self.dir.join(format!("{}.html", name))
-                    )?;
-                    let size = xl.translate()?;
-                    self.content.insert(name, Resource::Layout(Arc::new(
-                        LayoutInfo { id, md5, size }
-                    )));
-                } else {
-                    self.content.insert(name, Resource::Media(Arc::new(
-                        MediaInfo { id, size, md5 }
-                    )));
+    /// Check and store a downloaded file.  `fetched` is the result of the HTTP
+    /// download, if any; if it is missing or failed, XMDS is used.
+    fn store_file(&mut self, res: ReqFile, fetched: Option<Result<Vec<u8>>>,
+                  cms: &mut xmds::Cms) -> Result<()> {
+        let ReqFile::File { id, typ, size, md5, name, .. } = res else {
+            bail!("{} is not a file", res.description());
+        };
+        let data = match fetched {
+            Some(Ok(data)) => data,
+            Some(Err(e)) => {
+                let failure = HttpFailure::classify(&e);
+                log::warn!("failing download of {} over http ({}), retrying \
+                            xmds: {:#}", name, failure, e);
+                match self.download_xmds(id, typ, size, cms) {
+                    Ok(data) => data,
+                    Err(e) => {
+                        // the CMS only blacklists media and layouts
+                        if typ != "dependency" && self.record_failure(id, failure) {
+                            log::error!("{} {} not found repeatedly, blacklisting it",
+                                        typ, name);
+                            if let Err(e) = cms.blacklist(id, typ, "file not found") {
+                                log::warn!("could not blacklist {}: {:#}", name, e);
+                            }
+                        }
+                        return Err(e);
+                    }
                 }
-                self.save()?;
             }
+            None => self.download_xmds(id, typ, size, cms)?,
+        };
+        ensure!(Md5::digest(&data).as_slice() == md5, "md5 mismatch");
+        fs::write(self.dir.join(&name), data)?;
+        self.last_used.insert(name.clone(), OffsetDateTime::now_utc().unix_timestamp());
+        self.add_file(id, typ, size, md5, name)?;
+        self.save()
+    }
+
+    /// Add an entry for a file present in the cache directory.
+    fn add_file(&mut self, id: i64, typ: &str, size: u64, md5: Vec<u8>, name: String) -> Result<()> {
+        if typ == "layout" {
+            self.translate_layout(name, id, md5)?;
+        } else {
+            // determine the playing time of videos, if possible
+            let duration = if is_playable(&name) {
+                util::probe_duration(&self.dir.join(&name))
+            } else {
+                None
+            };
+            let font = is_font(&name);
+            self.content.insert(name, Resource::Media(Arc::new(
+                MediaInfo { id, size, md5, duration }
+            )));
+            // fonts are registered in all widgets
+            if font {
+                self.update_resource_fonts()?;
+            }
+            // layouts playing the media to its end can now use its duration
+            if duration.is_some() {
+                self.translate_stale_layouts()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Bring the content list in line with the files in the cache directory,
+    /// in case it was not saved after a change: entries for missing files are
+    /// removed, and required files found with the right checksum are added.
+    pub fn reconcile(&mut self, required: &[ReqFile]) -> Result<()> {
+        let dir = &self.dir;
+        let before = self.content.len();
+        self.content.retain(|fname, _| dir.join(fname).is_file());
+        let mut changed = self.content.len() != before;
+        for file in required {
+            let ReqFile::File { id, typ, size, md5, name, .. } = file else { continue };
+            if self.content.contains_key(name) {
+                continue;
+            }
+            let Ok(data) = fs::read(self.dir.join(name)) else { continue };
+            if Md5::digest(&data).as_slice() == md5 {
+                log::info!("found unlisted {} {} in cache", typ, name);
+                self.add_file(*id, typ, *size, md5.clone(), name.clone())?;
+                changed = true;
+            }
+        }
+        if changed {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    /// Remove least recently used media that is not currently required, until
+    /// there is enough free space to download a file of the given size.
+    fn make_room(&mut self, size: u64) {
+        if self.min_free_space == 0 {
+            return;
+        }
+        let Ok((avail, _)) = util::space_info(&self.dir) else { return };
+        let needed = (size + self.min_free_space * 1024 * 1024).saturating_sub(avail);
+        if needed == 0 {
+            return;
+        }
+        let candidates = self.content.iter().filter_map(|(name, res)| match res {
+            Resource::Media(info) if !self.required.contains(name) => {
+                Some((name.clone(), self.last_used.get(name).copied().unwrap_or(0), info.size))
+            }
+            _ => None,
+        }).collect();
+        let evict = select_evictions(candidates, needed);
+        if evict.is_empty() {
+            log::warn!("disk space is low, but no cached media can be removed");
+            return;
         }
+        for name in evict {
+            log::info!("removing least recently used file {} to free disk space", name);
+            if let Err(e) = fs::remove_file(self.dir.join(&name)) {
+                log::warn!("could not remove {}: {}", name, e);
+            }
+            self.content.remove(&name);
+            self.last_used.remove(&name);
+        }
+        if let Err(e) = self.save() {
+            log::warn!("could not save cache content: {:#}", e);
+        }
+    }
+
+    /// Translate a cached layout into HTML.
+    fn translate_layout(&mut self, name: String, id: i64, md5: Vec<u8>) -> Result<()> {
+        let durations = self.known_durations();
+        let translation_key = self.layout_options.key(&durations);
+        let xl = layout::Translator::new(
+            &self.dir.join(&name),
+            &self.dir.join(format!("{}.html", name)),
+            self.layout_options.clone(),
+            durations,
+        )?;
+        let meta = xl.translate()?;
+        // keep the layout's structure for external tools
+        util::write_atomic(&self.dir.join(format!("{}.meta.json", id)),
+                           &serde_json::to_vec_pretty(&meta)?)
+            .context("writing layout metadata")?;
+        let size = (meta.width, meta.height);
+        self.content.insert(name, Resource::Layout(Arc::new(
+            LayoutInfo { id, md5, size, translation_key }
+        )));
         Ok(())
     }
 
-    fn download_http(&mut self, path: &str) -> Result<Vec<u8>> {
-        let mut data = Vec::new();
-        self.agent.get(path).call()?.into_reader().read_to_end(&mut data)?;
-        Ok(data)
+    /// Set the layout codes, used to resolve actions navigating to a layout,
+    /// and translate layouts again if they changed.
+    pub fn set_layout_codes(&mut self, codes: BTreeMap<String, i64>) -> Result<()> {
+        if codes == self.layout_codes {
+            return Ok(());
+        }
+        self.layout_codes = codes;
+        self.set_layout_options(self.layout_options.clone())
+    }
+
+    /// Set new options for layout translation, and translate all layouts
+    /// again that were translated with different options or an older version.
+    pub fn set_layout_options(&mut self, mut options: layout::Options) -> Result<()> {
+        options.layout_codes = self.layout_codes.clone();
+        self.layout_options = options;
+        self.translate_stale_layouts()
+    }
+
+    /// Translate all layouts again whose translation is outdated, also
+    /// because media durations have become known since.
+    fn translate_stale_layouts(&mut self) -> Result<()> {
+        let key = self.layout_options.key(&self.known_durations());
+        let stale = self.content.iter().filter_map(|(name, res)| match res {
+            Resource::Layout(info) if info.translation_key != key =>
+                Some((name.clone(), info.id, info.md5.clone())),
+            _ => None
+        }).collect::<Vec<_>>();
+        if stale.is_empty() {
+            return Ok(());
+        }
+        for (name, id, md5) in stale {
+            log::info!("translating layout {} again", id);
+            if let Err(e) = self.translate_layout(name, id, md5) {
+                log::error!("could not translate layout {}: {:#}", id, e);
+            }
+        }
+        self.save()
+    }
+
+    /// Check if a cached layout can be shown: all files it needs are present,
+    /// and it can be translated.  Returns a list of problems.
+    pub fn verify_layout(&self, id: i64) -> Vec<String> {
+        let name = format!("{}.xlf", id);
+        if self.get_layout(id).is_none() {
+            return vec!["layout is not cached".into()];
+        }
+        let mut problems = Vec::new();
+        match layout::required_files(&self.dir.join(&name)) {
+            Ok(files) => for file in files {
+                if !self.content.contains_key(&file) || !self.dir.join(&file).is_file() {
+                    problems.push(format!("missing file {}", file));
+                }
+            },
+            Err(e) => problems.push(format!("invalid layout: {:#}", e)),
+        }
+        // translate to a scratch file, to keep the current translation
+        let scratch = self.dir.join(format!("{}.verify.html", name));
+        let res = layout::Translator::new(&self.dir.join(&name), &scratch,
+                                          self.layout_options.clone(), self.known_durations())
+            .and_then(|xl| xl.translate());
+        let _ = fs::remove_file(&scratch);
+        if let Err(e) = res {
+            problems.push(format!("translation failed: {:#}", e));
+        }
+        problems
+    }
+
+    /// Collect durations of cached media known from probing.
+    fn known_durations(&self) -> layout::KnownDurations {
+        let mut durations = layout::KnownDurations::default();
+        for (name, res) in &self.content {
+            match res {
+                Resource::Media(info) => if let Some(duration) = info.duration {
+                    durations.media.insert(name.clone(), duration);
+                }
+                Resource::Resource(info) => {
+                    durations.resources.insert(info.id as i32, layout::ResourceDuration {
+                        duration: info.duration,
+                        numitems: info.numitems,
+                    });
+                }
+                Resource::Layout(_) => ()
+            }
+        }
+        durations
+    }
+
+    /// Record a failed download.  Returns true if the file should be
+    /// blacklisted since it has been repeatedly found missing on the CMS.
+    fn record_failure(&mut self, id: i64, failure: HttpFailure) -> bool {
+        if !matches!(failure, HttpFailure::NotFound(_)) {
+            // transient problems don't tell us anything about the file
+            self.not_found.remove(&id);
+            return false;
+        }
+        let count = self.not_found.entry(id).or_default();
+        *count += 1;
+        *count == NOT_FOUND_LIMIT
     }
 
     fn download_xmds(&mut self, id: i64, typ: &str, size: u64, cms: &mut xmds::Cms) -> Result<Vec<u8>> {
@@ -229,9 +652,363 @@ impl Cache {
         })
     }
 
-    fn save(&self) -> Result<()> {
-        let fp = fs::File::create(self.dir.join("content.json")).context("writing cache content")?;
-        serde_json::to_writer_pretty(fp, &self.content).context("serializing cache content")?;
+    /// Return @font-face rules for the fonts in the cache.
+    fn font_css(&self) -> String {
+        let mut fonts = self.content.keys().filter(|name| is_font(name)).collect::<Vec<_>>();
+        fonts.sort();
+        fonts.into_iter().map(|name| {
+            // the CMS doesn't send family names, the file name is a last resort
+            let family = fs::read(self.dir.join(name)).ok()
+                .and_then(|data| util::font_family(&data))
+                .unwrap_or_else(|| name.rsplit_once('.').map_or(&**name, |(stem, _)| stem).into());
+            format!("@font-face {{ font-family: '{}'; src: url('{}'); }}\n",
+                    family.replace(['\'', '\\'], ""), name)
+        }).collect()
+    }
+
+    /// Register the fonts in the cache in the HTML of all widgets.
+    fn update_resource_fonts(&self) -> Result<()> {
+        let css = self.font_css();
+        for (name, res) in &self.content {
+            if let Resource::Resource(_) = res {
+                let path = self.dir.join(name);
+                let html = fs::read_to_string(&path).with_context(|| format!("reading {}", name))?;
+                fs::write(&path, inject_fonts(&html, &css)).with_context(|| format!("writing {}", name))?;
+            }
+        }
         Ok(())
     }
+
+    fn save(&self) -> Result<()> {
+        let data = serde_json::to_vec_pretty(&self.content).context("serializing cache content")?;
+        util::write_atomic(&self.dir.join("content.json"), &data).context("writing cache content")
+    }
+}
+
+/// Select files to remove, least recently used first, until the given number
+/// of bytes is freed.  Candidates are given as (name, last use, size).
+fn select_evictions(mut candidates: Vec<(String, i64, u64)>, needed: u64) -> Vec<String> {
+    candidates.sort_by_key(|&(_, last_used, _)| last_used);
+    let mut freed = 0;
+    candidates.into_iter().take_while(|&(_, _, size)| {
+        let more = freed < needed;
+        freed += size;
+        more
+    }).map(|(name, _, _)| name).collect()
+}
+
+/// Get the value of a `<!-- NAME=value -->` comment in a resource's HTML.
+fn html_comment_value<T: std::str::FromStr>(html: &str, name: &str) -> Option<T> {
+    let start = html.find(&format!("<!-- {}=", name))? + name.len() + 6;
+    let end = html[start..].find("-->")?;
+    html[start..start + end].trim().parse().ok()
+}
+
+/// Insert @font-face rules into a widget's HTML, replacing those inserted
+/// before.
+fn inject_fonts(html: &str, css: &str) -> String {
+    const START: &str = "<style id='arexibo-fonts'>";
+    let mut html = html.to_owned();
+    if let Some(start) = html.find(START) {
+        if let Some(len) = html[start..].find("</style>") {
+            html.replace_range(start..start + len + "</style>".len(), "");
+        }
+    }
+    if !css.is_empty() {
+        let at = html.find("<head>").map_or(0, |pos| pos + "<head>".len());
+        html.insert_str(at, &format!("{}{}</style>", START, css));
+    }
+    html
+}
+
+/// Fill in the viewport width of a widget's HTML.  This is the width of the
+/// layout, which is fitted into the rotated window and therefore already is
+/// the logical width.  If the layout is not known yet, use the device width.
+fn replace_viewport(html: &str, layout_width: Option<i32>) -> String {
+    let width = layout_width.filter(|&w| w > 0)
+                            .map_or_else(|| "device-width".into(), |w| w.to_string());
+    html.replace("[[ViewPortWidth]]", &width)
+}
+
+/// Check if the file is a font, based on its extension.
+fn is_font(name: &str) -> bool {
+    let ext = name.rsplit('.').next().unwrap_or("").to_ascii_lowercase();
+    matches!(&*ext, "ttf" | "otf" | "woff" | "woff2")
+}
+
+/// Check if the file is a video or audio file, based on its extension.
+fn is_playable(name: &str) -> bool {
+    let ext = name.rsplit('.').next().unwrap_or("").to_ascii_lowercase();
+    matches!(&*ext, "mp4" | "avi" | "ogv" | "webm" | "mkv" | "mov" | "mpg" | "mpeg" |
+                    "mp3" | "m4a" | "ogg" | "oga" | "wav" | "flac")
+}
+
+#[test]
+fn test_media_info_duration() {
+    let info = MediaInfo { id: 1, size: 10, md5: vec![1, 2], duration: Some(12.5) };
+    let json = serde_json::to_string(&info).unwrap();
+    assert_eq!(serde_json::from_str::<MediaInfo>(&json).unwrap(), info);
+    // entries cached by older versions don't have a duration
+    let old: MediaInfo = serde_json::from_str(r#"{"id": 1, "size": 10, "md5": "0102"}"#).unwrap();
+    assert_eq!(old.duration, None);
+}
+
+#[test]
+fn test_translate_with_duration() {
+    let dir = std::env::temp_dir().join("arexibo-test-duration");
+    let mut cache = Cache::new(&CmsSettings::default(), dir.clone(), true).unwrap();
+    fs::write(dir.join("5.xlf"), r#"<layout width="100" height="100">
+<region id="1" left="0" top="0" width="100" height="100">
+  <media id="10" type="video" duration="0"><options><uri>a.mp4</uri></options></media>
+</region>
+</layout>"#).unwrap();
+    cache.translate_layout("5.xlf".into(), 5, vec![]).unwrap();
+    let key = cache.get_layout(5).unwrap().translation_key;
+    cache.content.insert("a.mp4".into(), Resource::Media(Arc::new(
+        MediaInfo { id: 1, size: 0, md5: vec![], duration: Some(12.5) })));
+    // the probed duration makes the translation outdated
+    cache.translate_stale_layouts().unwrap();
+    assert_ne!(cache.get_layout(5).unwrap().translation_key, key);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_verify_layout() {
+    let dir = std::env::temp_dir().join("arexibo-test-verify");
+    let mut cache = Cache::new(&CmsSettings::default(), dir.clone(), true).unwrap();
+    fs::write(dir.join("5.xlf"), r#"<layout width="100" height="100" background="bg.png">
+<region id="1" left="0" top="0" width="100" height="100">
+  <media id="10" type="image" duration="10"><options><uri>a.png</uri></options></media>
+  <media id="11" type="image" duration="10"><options><uri>b.png</uri></options></media>
+</region>
+</layout>"#).unwrap();
+    cache.translate_layout("5.xlf".into(), 5, vec![]).unwrap();
+    for name in ["bg.png", "a.png"] {
+        fs::write(dir.join(name), b"").unwrap();
+        cache.content.insert(name.into(), Resource::Media(Arc::new(
+            MediaInfo { id: 1, size: 0, md5: vec![], duration: None })));
+    }
+    // the layout is not healthy, since one image is missing
+    assert_eq!(cache.verify_layout(5), ["missing file b.png"]);
+    assert_eq!(cache.verify_layout(6), ["layout is not cached"]);
+    assert!(!dir.join("5.xlf.verify.html").exists());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_max_file_size() {
+    let file = |size| ReqFile::File { id: 1, typ: "media", size, md5: vec![], http: true,
+                                      path: "/x".into(), name: "x.mp4".into() };
+    assert!(file(4 << 30).check_size(0).is_ok());
+    assert!(file(100 << 20).check_size(100).is_ok());
+    let err = file(4 << 30).check_size(100).unwrap_err();
+    assert!(err.to_string().contains("exceeds the maximum of 100 MiB"));
+    // resources have no size and are always allowed
+    let res = ReqFile::Resource { id: 1, layoutid: 1, regionid: 1, mediaid: 1, updated: 0 };
+    assert!(res.check_size(1).is_ok());
+}
+
+#[test]
+fn test_http_failure() {
+    let status = |code| anyhow::Error::from(ureq::Error::Status(
+        code, ureq::Response::new(code, "", "").unwrap()));
+    let refused = ureq::get("http://127.0.0.1:1/").call().unwrap_err();
+    assert_eq!(HttpFailure::classify(&status(404)), HttpFailure::NotFound(404));
+    assert_eq!(HttpFailure::classify(&status(403)), HttpFailure::Denied(403));
+    assert_eq!(HttpFailure::classify(&status(500)), HttpFailure::Status(500));
+    assert_eq!(HttpFailure::classify(&refused.into()), HttpFailure::Connection);
+    assert_eq!(HttpFailure::classify(&anyhow::anyhow!("md5 mismatch")), HttpFailure::Other);
+    assert_eq!(HttpFailure::NotFound(404).to_string(), "file not found on server (HTTP 404)");
+
+    // only persistent "not found" errors lead to blacklisting
+    let dir = std::env::temp_dir().join("arexibo-test-http-failure");
+    let mut cache = Cache::new(&CmsSettings::default(), dir, true).unwrap();
+    for _ in 0..5 {
+        assert!(!cache.record_failure(1, HttpFailure::Connection));
+    }
+    assert!(!cache.record_failure(1, HttpFailure::NotFound(404)));
+    assert!(!cache.record_failure(1, HttpFailure::NotFound(404)));
+    assert!(cache.record_failure(1, HttpFailure::NotFound(404)));
+    // a connection error in between resets the count
+    assert!(!cache.record_failure(2, HttpFailure::NotFound(404)));
+    assert!(!cache.record_failure(2, HttpFailure::Connection));
+    assert!(!cache.record_failure(2, HttpFailure::NotFound(404)));
+    assert!(!cache.record_failure(2, HttpFailure::NotFound(404)));
+}
+
+#[test]
+fn test_html_comment_value() {
+    let html = "<html><!-- NUMITEMS=12 --><!-- DURATION=90.5 --><body></body></html>";
+    assert_eq!(html_comment_value::<u32>(html, "NUMITEMS"), Some(12));
+    assert_eq!(html_comment_value::<f64>(html, "DURATION"), Some(90.5));
+    assert_eq!(html_comment_value::<f64>(html, "OTHER"), None);
+    assert_eq!(html_comment_value::<u32>("<!-- NUMITEMS=x -->", "NUMITEMS"), None);
+}
+
+#[test]
+fn test_download_to_cache_dir() {
+    let envdir = std::env::temp_dir().join("arexibo-test-cachedir");
+    let cachedir = std::env::temp_dir().join("arexibo-test-cachedir-media");
+    fs::create_dir_all(&envdir).unwrap();
+    let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/a.png", server.server_addr().to_ip().unwrap());
+    std::thread::spawn(move || {
+        let req = server.recv().unwrap();
+        req.respond(tiny_http::Response::from_data(&b"image"[..])).unwrap();
+    });
+
+    let cms = CmsSettings::default();
+    let mut xmds = xmds::Cms::new(&cms, String::new(), envdir.clone()).unwrap();
+    let mut cache = Cache::new(&cms, cachedir.clone(), true).unwrap();
+    let file = ReqFile::File { id: 1, typ: "media", size: 5, md5: Md5::digest(b"image").to_vec(),
+                               http: true, path: url, name: "a.png".into() };
+    cache.download(file, &mut xmds).unwrap();
+    assert_eq!(fs::read(cachedir.join("a.png")).unwrap(), b"image");
+    assert!(cachedir.join("content.json").is_file());
+    assert!(!envdir.join("a.png").exists());
+    fs::remove_dir_all(&envdir).unwrap();
+    fs::remove_dir_all(&cachedir).unwrap();
+}
+
+#[test]
+fn test_select_evictions() {
+    let candidates = vec![("a".into(), 300, 10), ("b".into(), 100, 10),
+                          ("c".into(), 200, 30), ("d".into(), 400, 10)];
+    assert_eq!(select_evictions(candidates.clone(), 15), ["b", "c"]);
+    assert_eq!(select_evictions(candidates.clone(), 10), ["b"]);
+    assert_eq!(select_evictions(candidates.clone(), 100), ["b", "c", "a", "d"]);
+    assert!(select_evictions(candidates, 0).is_empty());
+}
+
+#[test]
+fn test_download_retries() {
+    let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/a.png", server.server_addr().to_ip().unwrap());
+    let requests = Arc::new(std::sync::atomic::AtomicU32::new(0));
+    let requests_2 = requests.clone();
+    std::thread::spawn(move || {
+        // fail the first two requests
+        for req in server.incoming_requests() {
+            let n = requests_2.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            let code = if n < 2 { 503 } else { 200 };
+            req.respond(tiny_http::Response::from_data(&b"image"[..]).with_status_code(code))
+               .unwrap();
+        }
+    });
+
+    let dir = std::env::temp_dir().join("arexibo-test-retries");
+    let cms = CmsSettings { download_retries: 1, download_retry_delay: 0, ..Default::default() };
+    let mut cache = Cache::new(&cms, dir.clone(), true).unwrap();
+    assert_eq!(HttpFailure::classify(&cache.http.fetch(&url).unwrap_err()),
+               HttpFailure::Status(503));
+    assert_eq!(requests.load(std::sync::atomic::Ordering::SeqCst), 2);
+    cache.http.retries = 2;
+    requests.store(0, std::sync::atomic::Ordering::SeqCst);
+    assert_eq!(cache.http.fetch(&url).unwrap(), b"image");
+    assert_eq!(requests.load(std::sync::atomic::Ordering::SeqCst), 3);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_download_many() {
+    let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+    let base = format!("http://{}", server.server_addr().to_ip().unwrap());
+    std::thread::spawn(move || {
+        for req in server.incoming_requests() {
+            let data = req.url().trim_start_matches('/').as_bytes().to_vec();
+            let code = if data.starts_with(b"missing") { 404 } else { 200 };
+            req.respond(tiny_http::Response::from_data(data).with_status_code(code)).unwrap();
+        }
+    });
+
+    let dir = std::env::temp_dir().join("arexibo-test-download-many");
+    let cms = CmsSettings::default();
+    let mut xmds = xmds::Cms::new(&cms, String::new(), dir.clone()).unwrap();
+    let mut cache = Cache::new(&cms, dir.clone(), true).unwrap();
+    let file = |id, name: &str| ReqFile::File {
+        id, typ: "media", size: name.len() as u64, md5: Md5::digest(name).to_vec(), http: true,
+        path: format!("{}/{}", base, name), name: name.into()
+    };
+    let files = vec![file(1, "a.png"), file(2, "missing.png"), file(3, "b.png"), file(4, "c.png")];
+    let results = cache.download_many(files, &mut xmds, 2);
+    assert_eq!(results.iter().map(|r| r.is_ok()).collect::<Vec<_>>(), [true, false, true, true]);
+    for name in ["a.png", "b.png", "c.png"] {
+        assert_eq!(fs::read_to_string(dir.join(name)).unwrap(), name);
+        assert!(cache.get_media(name).is_some());
+    }
+    assert!(!dir.join("missing.png").exists());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_reconcile() {
+    let dir = std::env::temp_dir().join("arexibo-test-reconcile");
+    let mut cache = Cache::new(&CmsSettings::default(), dir.clone(), true).unwrap();
+    let file = |id, name: &str, data: &[u8]| ReqFile::File {
+        id, typ: "media", size: data.len() as u64, md5: Md5::digest(data).to_vec(),
+        http: true, path: String::new(), name: name.into()
+    };
+    // written, but the content list was not saved
+    fs::write(dir.join("a.png"), b"image").unwrap();
+    // listed, but deleted
+    cache.content.insert("b.png".into(), Resource::Media(Arc::new(
+        MediaInfo { id: 2, size: 0, md5: vec![], duration: None })));
+    // present, but incomplete
+    fs::write(dir.join("c.png"), b"ima").unwrap();
+
+    cache.reconcile(&[file(1, "a.png", b"image"), file(3, "c.png", b"image")]).unwrap();
+    assert_eq!(cache.get_media("a.png").unwrap().id, 1);
+    assert!(cache.get_media("b.png").is_none());
+    assert!(cache.get_media("c.png").is_none());
+    // the result is saved
+    let cache = Cache::new(&CmsSettings::default(), dir.clone(), false).unwrap();
+    assert!(cache.get_media("a.png").is_some());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_purge() {
+    let dir = std::env::temp_dir().join("arexibo-test-purge");
+    let mut cache = Cache::new(&CmsSettings::default(), dir.clone(), true).unwrap();
+    fs::write(dir.join("a.png"), b"image").unwrap();
+    cache.content.insert("a.png".into(), Resource::Media(Arc::new(
+        MediaInfo { id: 1, size: 5, md5: vec![], duration: None })));
+    for file in ["7.xlf", "7.xlf.html", "7.meta.json", "unrelated.txt"] {
+        fs::write(dir.join(file), b"data").unwrap();
+    }
+    cache.content.insert("7.xlf".into(), Resource::Layout(Arc::new(
+        LayoutInfo { id: 7, md5: vec![], size: (0, 0), translation_key: 0 })));
+    cache.save().unwrap();
+
+    cache.purge().unwrap();
+    assert!(cache.get_media("a.png").is_none());
+    for file in ["a.png", "7.xlf", "7.xlf.html", "7.meta.json"] {
+        assert!(!dir.join(file).exists());
+    }
+    // files not belonging to the cache are left alone
+    assert!(dir.join("unrelated.txt").exists());
+    let cache = Cache::new(&CmsSettings::default(), dir.clone(), false).unwrap();
+    assert!(cache.get_media("a.png").is_none());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_inject_fonts() {
+    let css = "@font-face { font-family: 'Aileron'; src: url('a.otf'); }\n";
+    let html = inject_fonts("<html><head><title>x</title></head></html>", css);
+    assert_eq!(html, "<html><head><style id='arexibo-fonts'>@font-face { font-family: 'Aileron'; \
+                      src: url('a.otf'); }\n</style><title>x</title></head></html>");
+    // rules are replaced when the fonts change
+    let html = inject_fonts(&html, "");
+    assert_eq!(html, "<html><head><title>x</title></head></html>");
+    assert_eq!(inject_fonts("<p>x</p>", css), format!("<style id='arexibo-fonts'>{}</style><p>x</p>", css));
+}
+
+#[test]
+fn test_replace_viewport() {
+    let html = r#"<meta name="viewport" content="width=[[ViewPortWidth]]">"#;
+    // a portrait layout has its logical width, regardless of the screen
+    assert_eq!(replace_viewport(html, Some(1080)), r#"<meta name="viewport" content="width=1080">"#);
+    assert_eq!(replace_viewport(html, None), r#"<meta name="viewport" content="width=device-width">"#);
+    assert_eq!(replace_viewport(html, Some(0)), replace_viewport(html, None));
 }
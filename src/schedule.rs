@@ -3,38 +3,74 @@
 
 //! Schedule parsing and scheduling.
 
-use std::{cmp::Ordering, sync::Arc};
-use anyhow::{Context, Result};
-use time::{OffsetDateTime, PrimitiveDateTime};
+use std::{cmp::Ordering, collections::HashMap, sync::Arc};
+use anyhow::{bail, Context, Result};
+use md5::{Md5, Digest};
+use time::{Duration, OffsetDateTime, PrimitiveDateTime, UtcOffset};
 use elementtree::Element;
 use crate::resource::{Cache, LayoutInfo};
 use crate::util::{TIME_FMT, ElementExt};
 
 type LayoutId = i64;
+/// Start, end, layout and priority of a scheduled window.
+type Window = (OffsetDateTime, OffsetDateTime, LayoutId, i32);
+/// Polygon of (longitude, latitude) points, as in GeoJSON.
+type Polygon = Vec<(f64, f64)>;
 
 #[derive(Debug, Default)]
 pub struct Schedule {
     default: Option<LayoutId>,
-    schedules: Vec<(OffsetDateTime, OffsetDateTime, LayoutId, i32)>,
+    schedules: Vec<Window>,
+    /// Layouts shown on top of the scheduled layout.
+    overlays: Vec<Window>,
+    /// Index into `schedules` -> area in which the layout may be shown.
+    geofences: HashMap<usize, Polygon>,
+    /// Index into `schedules` -> recurrence of the window.
+    recurrences: HashMap<usize, Recurrence>,
+}
+
+/// Recurrence of a schedule window, for schedules where the CMS hasn't
+/// already expanded it into single windows.
+#[derive(Debug, Clone, PartialEq)]
+struct Recurrence {
+    /// Time between repetitions; for weekly recurrence on certain days,
+    /// the time between the weeks.
+    period: Duration,
+    /// ISO weekday numbers (Monday = 1) for weekly recurrence on certain days.
+    weekdays: Vec<u8>,
+    /// No repetitions start after this time.
+    until: Option<OffsetDateTime>,
 }
 
 impl Schedule {
     pub fn parse(tree: Element) -> Result<Self> {
         let tz_offset = OffsetDateTime::now_local().unwrap().offset();
         let mut schedules = Vec::new();
+        let mut geofences = HashMap::new();
+        let mut recurrences = HashMap::new();
         for layout in tree.find_all("layout") {
-            let id = layout.parse_attr("file")?;
-            let prio = layout.parse_attr("priority")?;
-            let from = layout.get_attr("fromdt").context("missing fromdt")?;
-            let to = layout.get_attr("todt").context("missing todt")?;
-            let from = PrimitiveDateTime::parse(from, &TIME_FMT)
-                .context("invalid fromdt")?
-                .assume_offset(tz_offset);
-            let to = PrimitiveDateTime::parse(to, &TIME_FMT)
-                .context("invalid todt")?
-                .assume_offset(tz_offset);
-            schedules.push((from, to, id, prio));
+            let window = parse_window(layout, tz_offset)?;
+            if layout.def_attr("isGeoAware", "0") == "1" {
+                // a broken area shouldn't lose the rest of the schedule
+                match layout.get_attr("geoLocation").context("missing geoLocation")
+                            .and_then(|geo| parse_geofence(geo).context("invalid geoLocation")) {
+                    Ok(polygon) => { geofences.insert(schedules.len(), polygon); }
+                    Err(e) => log::warn!("schedule: layout {} shown everywhere: {:#}",
+                                         window.2, e),
+                }
+            }
+            match Recurrence::parse(layout, tz_offset) {
+                Ok(Some(recurrence)) => { recurrences.insert(schedules.len(), recurrence); }
+                Ok(None) => (),
+                Err(e) => log::warn!("schedule: layout {} shown only in its first window: {:#}",
+                                     window.2, e),
+            }
+            schedules.push(window);
         }
+        let overlays = tree.find("overlays").into_iter()
+                           .flat_map(|el| el.find_all("overlay"))
+                           .map(|el| parse_window(el, tz_offset).context("invalid overlay"))
+                           .collect::<Result<_>>()?;
         let mut default = None;
         if let Some(def) = tree.find("default") {
             default = Some(def.parse_attr("file")?);
@@ -42,36 +78,407 @@ impl Schedule {
 
         Ok(Self {
             default,
-            schedules
+            schedules,
+            overlays,
+            geofences,
+            recurrences,
         })
     }
 
-    pub fn layouts_now(&self, cache: &Cache) -> Vec<Arc<LayoutInfo>> {
-        let now = OffsetDateTime::now_local().unwrap();
+    /// Return a hash of the schedule's content, which does not depend on
+    /// the order of entries.
+    pub fn hash(&self) -> String {
+        let mut entries = self.schedules.iter().enumerate().map(|(i, &(from, to, lid, prio))| {
+            format!("{}-{}-{}-{}{:?}{:?};", from.unix_timestamp(), to.unix_timestamp(), lid, prio,
+                    self.geofences.get(&i), self.recurrences.get(&i))
+        }).collect::<Vec<_>>();
+        entries.extend(self.overlays.iter().map(|&(from, to, lid, prio)| {
+            format!("overlay:{}-{}-{}-{};", from.unix_timestamp(), to.unix_timestamp(), lid, prio)
+        }));
+        entries.sort();
+        let to_hash = format!("{}default:{:?}", entries.concat(), self.default);
+        hex::encode(Md5::digest(to_hash))
+    }
+
+    /// Select the layouts to show now.  Also returns the IDs of scheduled
+    /// layouts that are missing from the cache.  If `dedup` is true, layouts
+    /// scheduled several times are only shown once per rotation.
+    ///
+    /// Geo-aware layouts are only shown if the display's location, given as
+    /// (latitude, longitude), is inside their area.  Without a location,
+    /// they are always shown.
+    pub fn layouts_now(&self, cache: &Cache, dedup: bool, location: Option<(f64, f64)>)
+                       -> (Vec<Arc<LayoutInfo>>, Vec<LayoutId>) {
+        self.layouts_at(OffsetDateTime::now_local().unwrap(), dedup, location,
+                        |lid| cache.get_layout(lid))
+    }
+
+    /// Select the overlay layouts to show now, on top of the scheduled
+    /// layout.  Also returns the IDs of those missing from the cache.
+    pub fn overlays_now(&self, cache: &Cache) -> (Vec<Arc<LayoutInfo>>, Vec<LayoutId>) {
+        self.overlays_at(OffsetDateTime::now_local().unwrap(), |lid| cache.get_layout(lid))
+    }
+
+    fn overlays_at(&self, now: OffsetDateTime,
+                   get_layout: impl Fn(LayoutId) -> Option<Arc<LayoutInfo>>)
+                   -> (Vec<Arc<LayoutInfo>>, Vec<LayoutId>) {
+        let mut layouts: Vec<Arc<LayoutInfo>> = Vec::new();
+        let mut missing = Vec::new();
+        // all active overlays are shown, higher priority ones on top
+        let mut active = self.overlays.iter().filter(|&&(from, to, ..)| from <= now && now <= to)
+                                             .collect::<Vec<_>>();
+        active.sort_by_key(|&&(.., prio)| prio);
+        for &(_, _, lid, _) in active {
+            if layouts.iter().any(|l| l.id == lid) || missing.contains(&lid) {
+                continue;
+            }
+            match get_layout(lid) {
+                Some(info) => layouts.push(info),
+                None => missing.push(lid),
+            }
+        }
+        (layouts, missing)
+    }
+
+    /// Windows are compared as absolute datetimes, so that windows crossing
+    /// midnight need no special handling.  The CMS usually expands recurring
+    /// events into one entry per occurrence, otherwise the recurrence is
+    /// checked here.
+    fn layouts_at(&self, now: OffsetDateTime, dedup: bool, location: Option<(f64, f64)>,
+                  get_layout: impl Fn(LayoutId) -> Option<Arc<LayoutInfo>>)
+                  -> (Vec<Arc<LayoutInfo>>, Vec<LayoutId>) {
         let mut cur_prio = 0;
         let mut layouts = Vec::new();
-        for &(from, to, lid, prio) in &self.schedules {
-            if from <= now && now <= to {
+        let mut missing = Vec::new();
+        for (i, &(from, to, lid, prio)) in self.schedules.iter().enumerate() {
+            let active = match self.recurrences.get(&i) {
+                Some(recurrence) => recurrence.is_active(from, to, now),
+                None => from <= now && now <= to,
+            };
+            if active && !self.outside_geofence(i, location) {
                 match prio.cmp(&cur_prio) {
                     Ordering::Less => continue,
                     Ordering::Greater => {
                         cur_prio = prio;
                         layouts.clear();
+                        missing.clear();
                     }
                     _ => ()
                 }
-                if let Some(info) = cache.get_layout(lid) {
+                if dedup && (layouts.iter().any(|l| l.id == lid) || missing.contains(&lid)) {
+                    continue;
+                }
+                if let Some(info) = get_layout(lid) {
                     layouts.push(info);
+                } else {
+                    missing.push(lid);
                 }
             }
         }
         if layouts.is_empty() {
             if let Some(def) = self.default {
-                if let Some(info) = cache.get_layout(def) {
+                if let Some(info) = get_layout(def) {
                     layouts.push(info);
+                } else {
+                    missing.push(def);
                 }
             }
         }
-        layouts
+        (layouts, missing)
+    }
+
+    /// Check if the location is outside the area of the given schedule entry.
+    fn outside_geofence(&self, index: usize, location: Option<(f64, f64)>) -> bool {
+        match (location, self.geofences.get(&index)) {
+            (Some((lat, long)), Some(polygon)) => !in_polygon((long, lat), polygon),
+            _ => false,
+        }
     }
 }
+
+/// Parse the window of a layout or overlay schedule entry.
+fn parse_window(el: &Element, tz_offset: UtcOffset) -> Result<Window> {
+    let id = el.parse_attr("file")?;
+    let prio = el.parse_attr("priority")?;
+    let from = el.get_attr("fromdt").context("missing fromdt")?;
+    let to = el.get_attr("todt").context("missing todt")?;
+    let from = PrimitiveDateTime::parse(from, &TIME_FMT)
+        .context("invalid fromdt")?
+        .assume_offset(tz_offset);
+    let to = PrimitiveDateTime::parse(to, &TIME_FMT)
+        .context("invalid todt")?
+        .assume_offset(tz_offset);
+    Ok((from, to, id, prio))
+}
+
+impl Recurrence {
+    /// Parse the recurrence attributes of a schedule entry, if any.
+    fn parse(layout: &Element, tz_offset: UtcOffset) -> Result<Option<Self>> {
+        let unit = match layout.def_attr("recurrenceType", "") {
+            "" => return Ok(None),
+            "Minute" => Duration::minutes(1),
+            "Hour" => Duration::hours(1),
+            "Day" => Duration::days(1),
+            "Week" => Duration::weeks(1),
+            typ => {
+                log::warn!("schedule: unsupported recurrence type {:?}, ignoring", typ);
+                return Ok(None);
+            }
+        };
+        let detail = layout.def_attr("recurrenceDetail", "1").parse::<i32>()
+                           .context("invalid recurrenceDetail")?.max(1);
+        let weekdays = if unit == Duration::weeks(1) {
+            layout.def_attr("recurrenceRepeatsOn", "").split(',').filter(|s| !s.is_empty())
+                  .map(|s| s.trim().parse()).collect::<Result<_, _>>()
+                  .context("invalid recurrenceRepeatsOn")?
+        } else {
+            Vec::new()
+        };
+        // the range is given as Unix timestamp by some CMS versions
+        let until = match layout.def_attr("recurrenceRange", "") {
+            "" | "0" => None,
+            range => Some(match range.parse() {
+                Ok(ts) => OffsetDateTime::from_unix_timestamp(ts)?,
+                Err(_) => PrimitiveDateTime::parse(range, &TIME_FMT)
+                    .context("invalid recurrenceRange")?.assume_offset(tz_offset),
+            }),
+        };
+        Ok(Some(Self { period: unit * detail, weekdays, until }))
+    }
+
+    /// Check if `now` is within any repetition of the window from `from` to `to`.
+    fn is_active(&self, from: OffsetDateTime, to: OffsetDateTime, now: OffsetDateTime) -> bool {
+        let length = to - from;
+        let in_window = |start: OffsetDateTime| {
+            start <= now && now <= start + length && self.until.map_or(true, |u| start <= u)
+        };
+        if now < from {
+            false
+        } else if self.weekdays.is_empty() {
+            // go back through the repetitions that could still be running
+            let period = self.period.whole_seconds();
+            let last = (now - from).whole_seconds() / period;
+            (0..=last).rev().map(|k| from + Duration::seconds(k * period))
+                          .take_while(|&start| start + length >= now)
+                          .any(in_window)
+        } else {
+            // check the days on which a still running repetition could have started
+            let week_start = from.date() - Duration::days(
+                from.weekday().number_days_from_monday() as i64);
+            let today = now.to_offset(from.offset()).date();
+            (0..=length.whole_days() + 1).any(|days| {
+                let start = (today - Duration::days(days)).with_time(from.time())
+                                                           .assume_offset(from.offset());
+                start >= from && in_window(start) &&
+                    self.weekdays.contains(&start.weekday().number_from_monday()) &&
+                    (start.date() - week_start).whole_weeks() % self.period.whole_weeks() == 0
+            })
+        }
+    }
+}
+
+/// Parse the polygon from a GeoJSON Feature or Polygon, or the first
+/// feature of a FeatureCollection.
+fn parse_geofence(geojson: &str) -> Result<Polygon> {
+    let mut value: serde_json::Value = serde_json::from_str(geojson)?;
+    if value["type"] == "FeatureCollection" {
+        value = value.get_mut("features").and_then(|f| f.get_mut(0))
+                     .map(serde_json::Value::take).unwrap_or_default();
+    }
+    if value["type"] == "Feature" {
+        value = value["geometry"].take();
+    }
+    if value["type"] != "Polygon" {
+        bail!("unsupported geometry {}", value["type"]);
+    }
+    // only the outer ring is used
+    value["coordinates"][0].as_array().context("missing coordinates")?.iter().map(|point| {
+        Ok((point[0].as_f64().context("invalid point")?,
+            point[1].as_f64().context("invalid point")?))
+    }).collect()
+}
+
+/// Check if the point is inside the polygon, by counting the edges that a
+/// ray from the point crosses.
+fn in_polygon((x, y): (f64, f64), polygon: &[(f64, f64)]) -> bool {
+    let mut inside = false;
+    for (i, &(x1, y1)) in polygon.iter().enumerate() {
+        let (x2, y2) = polygon[(i + 1) % polygon.len()];
+        if (y1 > y) != (y2 > y) && x < x1 + (y - y1) / (y2 - y1) * (x2 - x1) {
+            inside = !inside;
+        }
+    }
+    inside
+}
+
+#[test]
+fn test_schedule_hash() {
+    let ts = |t| OffsetDateTime::from_unix_timestamp(t).unwrap();
+    let sched = |schedules| Schedule { default: Some(1), schedules, ..Default::default() };
+    let hash = sched(vec![(ts(0), ts(100), 2, 0), (ts(50), ts(150), 3, 1)]).hash();
+    // order of entries doesn't matter
+    assert_eq!(hash, sched(vec![(ts(50), ts(150), 3, 1), (ts(0), ts(100), 2, 0)]).hash());
+    // any change in entries or default layout does
+    assert_ne!(hash, sched(vec![(ts(0), ts(100), 2, 0), (ts(50), ts(151), 3, 1)]).hash());
+    assert_ne!(hash, sched(vec![(ts(0), ts(100), 2, 0)]).hash());
+    assert_ne!(hash, Schedule { default: None, ..sched(vec![(ts(0), ts(100), 2, 0),
+                                                             (ts(50), ts(150), 3, 1)]) }.hash());
+}
+
+#[test]
+fn test_missing_layouts() {
+    let ts = |t| OffsetDateTime::from_unix_timestamp(t).unwrap();
+    let sched = Schedule { default: Some(1), schedules: vec![(ts(0), ts(100), 2, 0),
+                                                             (ts(0), ts(100), 3, 0)],
+                           ..Default::default() };
+    let cached = |lid| (lid == 3).then(|| Arc::new(LayoutInfo { id: lid, ..Default::default() }));
+    let (layouts, missing) = sched.layouts_at(ts(50), true, None, cached);
+    assert_eq!(layouts.iter().map(|l| l.id).collect::<Vec<_>>(), [3]);
+    assert_eq!(missing, [2]);
+    // the default layout is needed when nothing else is scheduled
+    let (layouts, missing) = sched.layouts_at(ts(200), true, None, cached);
+    assert!(layouts.is_empty());
+    assert_eq!(missing, [1]);
+}
+
+#[test]
+fn test_duplicate_layouts() {
+    let ts = |t| OffsetDateTime::from_unix_timestamp(t).unwrap();
+    let sched = Schedule { default: None, schedules: vec![(ts(0), ts(100), 2, 0),
+                                                          (ts(0), ts(100), 3, 0),
+                                                          (ts(50), ts(150), 2, 0)],
+                           ..Default::default() };
+    let cached = |lid| Some(Arc::new(LayoutInfo { id: lid, ..Default::default() }));
+    let ids = |(layouts, _): (Vec<Arc<LayoutInfo>>, Vec<LayoutId>)| {
+        layouts.iter().map(|l| l.id).collect::<Vec<_>>()
+    };
+    assert_eq!(ids(sched.layouts_at(ts(60), true, None, cached)), [2, 3]);
+    // repeats can be kept, e.g. for weighting
+    assert_eq!(ids(sched.layouts_at(ts(60), false, None, cached)), [2, 3, 2]);
+}
+
+#[test]
+fn test_overnight_windows() {
+    let dt = |s: &str| PrimitiveDateTime::parse(s, &TIME_FMT).unwrap().assume_utc();
+    let cached = |lid| Some(Arc::new(LayoutInfo { id: lid, ..Default::default() }));
+    let ids = |(layouts, _): (Vec<Arc<LayoutInfo>>, Vec<LayoutId>)| {
+        layouts.iter().map(|l| l.id).collect::<Vec<_>>()
+    };
+    // a single window from 22:00 to 02:00
+    let sched = Schedule { default: Some(1), schedules: vec![
+        (dt("2024-03-01 22:00:00"), dt("2024-03-02 02:00:00"), 2, 0),
+    ], ..Default::default() };
+    assert_eq!(ids(sched.layouts_at(dt("2024-03-01 23:30:00"), true, None, cached)), [2]);
+    assert_eq!(ids(sched.layouts_at(dt("2024-03-02 01:00:00"), true, None, cached)), [2]);
+    assert_eq!(ids(sched.layouts_at(dt("2024-03-02 03:00:00"), true, None, cached)), [1]);
+    assert_eq!(ids(sched.layouts_at(dt("2024-03-01 21:00:00"), true, None, cached)), [1]);
+
+    // a daily recurring event 22:00-02:00, as expanded by the CMS
+    let sched = Schedule { default: Some(1), schedules: (1..=3).map(|day| (
+        dt(&format!("2024-03-0{} 22:00:00", day)), dt(&format!("2024-03-0{} 02:00:00", day + 1)),
+        2, 0
+    )).collect(), ..Default::default() };
+    assert_eq!(ids(sched.layouts_at(dt("2024-03-03 01:00:00"), true, None, cached)), [2]);
+    assert_eq!(ids(sched.layouts_at(dt("2024-03-03 12:00:00"), true, None, cached)), [1]);
+    assert_eq!(ids(sched.layouts_at(dt("2024-03-04 01:59:59"), true, None, cached)), [2]);
+    assert_eq!(ids(sched.layouts_at(dt("2024-03-04 02:00:01"), true, None, cached)), [1]);
+}
+
+#[test]
+fn test_window_around_now() {
+    let now = OffsetDateTime::now_utc();
+    let hour = time::Duration::hours(1);
+    let cached = |lid| Some(Arc::new(LayoutInfo { id: lid, ..Default::default() }));
+    let sched = Schedule { default: Some(1), schedules: vec![(now - hour, now + hour, 2, 0),
+                                                             (now + hour, now + 2 * hour, 3, 0)],
+                           ..Default::default() };
+    let (layouts, _) = sched.layouts_at(now, true, None, cached);
+    // a window that has started is active, one that starts later is not
+    assert_eq!(layouts.iter().map(|l| l.id).collect::<Vec<_>>(), [2]);
+}
+
+#[test]
+fn test_geofence() {
+    let fence = parse_geofence(r#"{"type": "Feature", "properties": {}, "geometry": {"type": "Polygon",
+        "coordinates": [[[11.0, 49.0], [12.0, 49.0], [12.0, 50.0], [11.0, 50.0], [11.0, 49.0]]]}}"#).unwrap();
+    assert_eq!(fence.len(), 5);
+    assert!(parse_geofence(r#"{"type": "Point", "coordinates": [1.0, 2.0]}"#).is_err());
+
+    let now = OffsetDateTime::now_utc();
+    let hour = time::Duration::hours(1);
+    let sched = Schedule { default: None, schedules: vec![(now - hour, now + hour, 2, 0),
+                                                          (now - hour, now + hour, 3, 0)],
+                           geofences: [(0, fence)].into(), ..Default::default() };
+    let cached = |lid| Some(Arc::new(LayoutInfo { id: lid, ..Default::default() }));
+    let ids = |(layouts, _): (Vec<Arc<LayoutInfo>>, Vec<LayoutId>)| {
+        layouts.iter().map(|l| l.id).collect::<Vec<_>>()
+    };
+    assert_eq!(ids(sched.layouts_at(now, true, Some((49.5, 11.5)), cached)), [2, 3]);
+    assert_eq!(ids(sched.layouts_at(now, true, Some((48.0, 11.5)), cached)), [3]);
+    // without a location, geofences are ignored
+    assert_eq!(ids(sched.layouts_at(now, true, None, cached)), [2, 3]);
+    // the fence is part of the schedule's identity
+    assert_ne!(sched.hash(), Schedule { geofences: Default::default(), ..sched }.hash());
+
+    assert!(in_polygon((0.5, 0.2), &[(0.0, 0.0), (1.0, 0.0), (0.0, 1.0)]));
+    assert!(!in_polygon((0.8, 0.8), &[(0.0, 0.0), (1.0, 0.0), (0.0, 1.0)]));
+}
+
+#[test]
+fn test_recurrence() {
+    let dt = |s: &str| PrimitiveDateTime::parse(s, &TIME_FMT).unwrap().assume_utc();
+    let cached = |lid| Some(Arc::new(LayoutInfo { id: lid, ..Default::default() }));
+    let ids = |(layouts, _): (Vec<Arc<LayoutInfo>>, Vec<LayoutId>)| {
+        layouts.iter().map(|l| l.id).collect::<Vec<_>>()
+    };
+    let parse = |attrs: &str| {
+        let xml = format!("<layout {}/>", attrs);
+        Recurrence::parse(&Element::from_reader(xml.as_bytes()).unwrap(), UtcOffset::UTC).unwrap()
+    };
+    assert_eq!(parse(""), None);
+    assert_eq!(parse(r#"recurrenceType="Month""#), None);
+
+    // daily from 09:00 to 17:00, until March 10
+    let daily = parse(r#"recurrenceType="Day" recurrenceDetail="1" recurrenceRange="1710072000""#);
+    let sched = Schedule { default: Some(1), schedules: vec![
+        (dt("2024-03-04 09:00:00"), dt("2024-03-04 17:00:00"), 2, 0),
+    ], recurrences: [(0, daily.unwrap())].into(), ..Default::default() };
+    assert_eq!(ids(sched.layouts_at(dt("2024-03-04 10:00:00"), true, None, cached)), [2]);
+    assert_eq!(ids(sched.layouts_at(dt("2024-03-06 16:59:00"), true, None, cached)), [2]);
+    assert_eq!(ids(sched.layouts_at(dt("2024-03-06 08:00:00"), true, None, cached)), [1]);
+    assert_eq!(ids(sched.layouts_at(dt("2024-03-03 10:00:00"), true, None, cached)), [1]);
+    assert_eq!(ids(sched.layouts_at(dt("2024-03-11 10:00:00"), true, None, cached)), [1]);
+
+    // every other week on Monday and Friday, overnight from 22:00 to 02:00
+    let weekly = parse(r#"recurrenceType="Week" recurrenceDetail="2" recurrenceRepeatsOn="1,5""#);
+    let sched = Schedule { default: Some(1), schedules: vec![
+        (dt("2024-03-04 22:00:00"), dt("2024-03-05 02:00:00"), 2, 0),
+    ], recurrences: [(0, weekly.unwrap())].into(), ..Default::default() };
+    assert_eq!(ids(sched.layouts_at(dt("2024-03-05 01:00:00"), true, None, cached)), [2]);
+    assert_eq!(ids(sched.layouts_at(dt("2024-03-08 23:00:00"), true, None, cached)), [2]);
+    assert_eq!(ids(sched.layouts_at(dt("2024-03-09 01:30:00"), true, None, cached)), [2]);
+    assert_eq!(ids(sched.layouts_at(dt("2024-03-06 23:00:00"), true, None, cached)), [1]);
+    // not in the week in between
+    assert_eq!(ids(sched.layouts_at(dt("2024-03-11 23:00:00"), true, None, cached)), [1]);
+    assert_eq!(ids(sched.layouts_at(dt("2024-03-18 23:00:00"), true, None, cached)), [2]);
+}
+
+#[test]
+fn test_overlays() {
+    let now = OffsetDateTime::now_utc();
+    let hour = time::Duration::hours(1);
+    let cached = |lid| (lid != 6).then(|| Arc::new(LayoutInfo { id: lid, ..Default::default() }));
+    let sched = Schedule { default: Some(1), overlays: vec![(now - hour, now + hour, 4, 2),
+                                                            (now - hour, now + hour, 5, 1),
+                                                            (now - hour, now + hour, 6, 0),
+                                                            (now + hour, now + 2 * hour, 7, 0)],
+                           ..Default::default() };
+    let (layouts, missing) = sched.overlays_at(now, cached);
+    // the highest priority is shown on top, i.e. last
+    assert_eq!(layouts.iter().map(|l| l.id).collect::<Vec<_>>(), [5, 4]);
+    assert_eq!(missing, [6]);
+    // overlays don't influence the normal layouts
+    let (layouts, _) = sched.layouts_at(now, true, None, cached);
+    assert_eq!(layouts.iter().map(|l| l.id).collect::<Vec<_>>(), [1]);
+    assert_ne!(sched.hash(), Schedule { overlays: vec![], ..sched }.hash());
+}
@@ -3,48 +3,114 @@
 
 //! Internal webserver to point the webview to.
 
-use std::{sync::Arc, fs, io::Read, io::Seek, thread};
+use std::{sync::Arc, fs, io, io::Read, io::Seek, thread, time::Duration};
+use std::net::TcpListener;
 use std::path::{Path, PathBuf};
-use anyhow::{anyhow, bail, ensure, Result};
+use anyhow::{anyhow, bail, ensure, Context, Result};
 use itertools::Itertools;
-use tiny_http::{Request, Response, ResponseBox, Header, StatusCode};
+use nix::sys::{socket::{setsockopt, sockopt}, time::TimeVal};
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use flate2::{Compression, write::GzEncoder};
+use qrcode::{QrCode, render::svg};
+use tiny_http::{Method, Request, Response, ResponseBox, Header, StatusCode};
+use crate::collect::ToGui;
 
 
 pub struct Server {
     dir: PathBuf,
+    info: String,
+    pages: Arc<Vec<(&'static str, String)>>,
+    to_gui: glib::Sender<ToGui>,
     server: tiny_http::Server,
 }
 
 impl Server {
-    pub fn new(dir: PathBuf, port: u16) -> Result<Self> {
-        let server = tiny_http::Server::http(("127.0.0.1", port))
+    /// Create a new server, serving files from `dir`.  `info` is the JSON
+    /// document returned by the `/info` API endpoint, `pages` are generated
+    /// HTML pages with their paths.  Control requests are sent to the GUI
+    /// via `to_gui`.  Connections that stall for longer than `timeout` while
+    /// sending or receiving are closed.
+    pub fn new(dir: PathBuf, port: u16, timeout: Duration, info: String,
+               pages: Vec<(&'static str, String)>, to_gui: glib::Sender<ToGui>) -> Result<Self> {
+        let server = tiny_http::Server::from_listener(bind(port, timeout)?, None)
             .map_err(|e| anyhow!(e))?;
-        Ok(Self { dir, server })
+        Ok(Self { dir, info, pages: Arc::new(pages), to_gui, server })
     }
 
-    pub fn start_pool(self) {
+    /// The port the server is actually listening on.
+    pub fn port(&self) -> u16 {
+        self.server.server_addr().to_ip().map_or(0, |addr| addr.port())
+    }
+
+    /// Start `workers` threads to process requests.
+    pub fn start_pool(self, workers: usize) {
+        let port = self.port();
         let server = Arc::new(self.server);
-        for _ in 0..4 {
+        for _ in 0..workers.max(1) {
             let server = server.clone();
             let dir = self.dir.clone();
+            let info = self.info.clone();
+            let pages = self.pages.clone();
+            let to_gui = self.to_gui.clone();
             thread::spawn(move || {
                 loop {
                     let req = server.recv().unwrap();
-                    match Self::serve(&dir, &req) {
-                        Ok(resp) => {  let _ = req.respond(resp); }
-                        Err(e) => {
-                            log::warn!("processing HTTP req {}: {:#}", req.url(), e);
-                            let _ = req.respond(Response::empty(500));
+                    if let Some(msg) = control_request(req.method(), req.url()) {
+                        if !is_local_request(req.headers(), port) {
+                            log::warn!("rejected control request {} from another origin", req.url());
+                            let _ = req.respond(Response::empty(403));
+                            continue;
                         }
+                        let _ = to_gui.send(msg);
+                        let _ = req.respond(Response::empty(204));
+                        continue;
+                    }
+                    let url = req.url().to_owned();
+                    let resp = Self::serve(&dir, &info, &pages, &req).unwrap_or_else(|e| {
+                        log::warn!("processing HTTP req {}: {:#}", url, e);
+                        Response::empty(500).boxed()
+                    });
+                    match req.respond(resp) {
+                        Err(e) if is_timeout(&e) =>
+                            log::warn!("HTTP req {}: client stalled, response timed out", url),
+                        Err(e) => log::warn!("HTTP req {}: sending response failed: {}", url, e),
+                        Ok(()) => (),
                     }
                 }
             });
         }
     }
 
-    /// Serve a single HTTP request.
-    fn serve(dir: &Path, req: &Request) -> Result<ResponseBox> {
+    /// Serve a single HTTP request.  Responses allow cross-origin requests,
+    /// since widgets may be loaded from other origins, except where noted
+    /// by `allows_cors`.
+    fn serve(dir: &Path, info: &str, pages: &[(&str, String)],
+             req: &Request) -> Result<ResponseBox> {
         log::debug!("HTTP request: {}", req.url());
+        let cors: fn(ResponseBox) -> ResponseBox =
+            if allows_cors(req.url()) { with_cors } else { std::convert::identity };
+        // CORS preflight
+        if *req.method() == Method::Options {
+            return Ok(cors(Response::empty(204).boxed()));
+        }
+        Self::serve_content(dir, info, pages, req).map(cors)
+    }
+
+    fn serve_content(dir: &Path, info: &str, pages: &[(&str, String)],
+                     req: &Request) -> Result<ResponseBox> {
+        if let Some((status, body)) = serve_api(req.method(), req.url(), info) {
+            return Ok(Response::from_string(body)
+                .with_status_code(status)
+                .with_header(Header::from_bytes(&b"Content-Type"[..],
+                                                &b"application/json"[..]).unwrap())
+                .boxed());
+        }
+        if let Some((_, page)) = pages.iter().find(|(path, _)| *path == req.url()) {
+            return Ok(Response::from_string(page.as_str())
+                .with_header(Header::from_bytes(&b"Content-Type"[..],
+                                                &b"text/html; charset=utf-8"[..]).unwrap())
+                .boxed());
+        }
         Ok(match req.url() {
             // built-in files?
             "/splash.jpg" => Response::from_data(SPLASH_JPG).boxed(),
@@ -57,55 +123,156 @@ impl Server {
                     log::warn!("processing HTTP req {}: 404 not found", req.url());
                     return Ok(Response::empty(404).boxed());
                 }
-                let mut fp = fs::File::open(&path)?;
-
-                // implement HTTP Range query for gstreamer
-                for h in req.headers() {
-                    if h.field.equiv("Range") {
-                        let total_size = fp.metadata()?.len();
-                        let (from, to, size) = parse_range(total_size,
-                                                           h.value.to_string())?;
-                        fp.seek(std::io::SeekFrom::Start(from))?;
-                        let stream = fp.take(size);
-
-                        let range = format!("bytes {}-{}/{}", from, to, total_size);
-                        return Ok(Response::new(
-                            StatusCode(206),
-                            vec![
-                                Header::from_bytes(&b"Content-Range"[..],
-                                                   range.as_bytes()).unwrap(),
-                                Header::from_bytes(&b"Content-Type"[..],
-                                                   &b"video/mp4"[..]).unwrap(),
-                            ],
-                            stream,
-                            Some(size as usize),
-                            None
-                        ).with_chunked_threshold(usize::MAX).boxed());
-                    }
-                }
+                // let the webview cache files, but check layouts for changes
+                let etag = file_etag(&fs::metadata(&path)?);
+                let cache_control = if mime_type(&path) == Some("text/html") {
+                    "no-cache"
+                } else {
+                    "max-age=3600"
+                };
+                let mut resp = if etag_matches(req, &etag) {
+                    Response::empty(304).boxed()
+                } else {
+                    Self::serve_file(&path, req)?
+                };
+                resp.add_header(Header::from_bytes(&b"ETag"[..], etag.as_bytes()).unwrap());
+                resp.add_header(Header::from_bytes(&b"Cache-Control"[..],
+                                                   cache_control.as_bytes()).unwrap());
+                resp
+            }
+        })
+    }
 
-                // guess the MIME type based on filename
-                let ctype = match path.extension().and_then(|e| e.to_str()) {
-                    Some("html") => "text/html",
-                    Some("js") => "text/javascript",
-                    Some("ttf" | "otf") => "application/font-sfnt",
-                    Some("jpg" | "jpeg") => "image/jpeg",
-                    Some("png") => "image/png",
-                    Some("pdf") => "application/pdf",
-                    Some("mp4") => "video/mp4",
-                    Some("avi") => "video/avi",
-                    Some("ogv") => "video/ogg",
-                    Some("webm") => "video/webm",
-                    _ => "",
+    /// Serve a static file.
+    fn serve_file(path: &Path, req: &Request) -> Result<ResponseBox> {
+        let mut fp = fs::File::open(path)?;
+        // guess the MIME type based on filename
+        let ctype = mime_type(path).unwrap_or("application/octet-stream");
+
+        // implement HTTP Range query for gstreamer
+        for h in req.headers() {
+            if h.field.equiv("Range") {
+                let total_size = fp.metadata()?.len();
+                let (from, to) = match parse_range(total_size, h.value.as_str()) {
+                    Ok(Some(range)) => range,
+                    Ok(None) => {
+                        let range = format!("bytes */{}", total_size);
+                        return Ok(Response::empty(416).with_header(
+                            Header::from_bytes(&b"Content-Range"[..],
+                                               range.as_bytes()).unwrap()
+                        ).boxed());
+                    }
+                    // a Range header that can't be parsed is ignored
+                    Err(e) => {
+                        log::warn!("processing HTTP req {}: {}", req.url(), e);
+                        break;
+                    }
                 };
+                let size = to - from + 1;
+                fp.seek(std::io::SeekFrom::Start(from))?;
+                let stream = fp.take(size);
 
-                Response::from_file(fp)
-                    // for gstreamer, need a response with Content-Length => no chunked
-                    .with_chunked_threshold(usize::MAX)
-                    .with_header(Header::from_bytes(&b"Content-Type"[..], ctype.as_bytes()).unwrap())
-                    .boxed()
+                let range = format!("bytes {}-{}/{}", from, to, total_size);
+                return Ok(Response::new(
+                    StatusCode(206),
+                    vec![
+                        Header::from_bytes(&b"Content-Range"[..],
+                                           range.as_bytes()).unwrap(),
+                        Header::from_bytes(&b"Content-Type"[..],
+                                           ctype.as_bytes()).unwrap(),
+                    ],
+                    stream,
+                    Some(size as usize),
+                    None
+                ).with_chunked_threshold(usize::MAX).boxed());
             }
-        })
+        }
+
+        // compress text files if possible; media is compressed already
+        if is_compressible(ctype) && accepts_gzip(req) {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            std::io::copy(&mut fp, &mut encoder)?;
+            return Ok(Response::from_data(encoder.finish()?)
+                .with_header(Header::from_bytes(&b"Content-Type"[..], ctype.as_bytes()).unwrap())
+                .with_header(Header::from_bytes(&b"Content-Encoding"[..], &b"gzip"[..]).unwrap())
+                .with_header(Header::from_bytes(&b"Vary"[..], &b"Accept-Encoding"[..]).unwrap())
+                .boxed());
+        }
+
+        Ok(Response::from_file(fp)
+            // for gstreamer, need a response with Content-Length => no chunked
+            .with_chunked_threshold(usize::MAX)
+            .with_header(Header::from_bytes(&b"Content-Type"[..], ctype.as_bytes()).unwrap())
+            .boxed())
+    }
+}
+
+/// Answer requests to the local HTTP API that the Xibo widget SDK
+/// (xibo-interactive-control) expects from the player.  Implemented are:
+///
+/// * `POST /duration/set`, `/duration/extend` and `/duration/expire`:
+///   accepted, but ignored since region timing is determined by the layout
+/// * `POST /trigger`: accepted, but ignored since actions are triggered
+///   by the layout itself or via XMR
+/// * `GET /info`: information about the player and display
+/// * `GET /realtime`: realtime data is not supported, answered with 404
+///   so that the SDK uses its fallback
+///
+/// Returns status code and JSON body, or None if the request is not for the API.
+fn serve_api(method: &Method, url: &str, info: &str) -> Option<(u16, String)> {
+    let path = url.split('?').next().unwrap_or(url);
+    Some(match (method, path) {
+        (Method::Post, "/duration/set" | "/duration/extend" | "/duration/expire") =>
+            (200, "{}".into()),
+        (Method::Post, "/trigger") => (200, "{}".into()),
+        (Method::Get, "/info") => (200, info.into()),
+        (Method::Get, "/realtime") => (404, "{}".into()),
+        _ => return None,
+    })
+}
+
+/// Check if cross-origin requests are allowed for the URL.  Information
+/// about the display and control of the player are only for local clients,
+/// not for other origins such as web pages shown in a layout.
+fn allows_cors(url: &str) -> bool {
+    let path = url.split('?').next().unwrap_or(url);
+    !(path == "/info" || path.starts_with("/control/"))
+}
+
+/// Check that a request comes from the player's own pages or a local tool,
+/// not from some web page: browsers send the Origin of cross-origin POST
+/// requests, and a Host other than the loopback address hints at DNS
+/// rebinding.
+fn is_local_request(headers: &[Header], port: u16) -> bool {
+    let is_local = |value: &str, scheme: &str| ["localhost", "127.0.0.1"].iter().any(
+        |host| value == format!("{}{}:{}", scheme, host, port));
+    headers.iter().all(|h| {
+        if h.field.equiv("Origin") {
+            is_local(h.value.as_str(), "http://")
+        } else if h.field.equiv("Host") {
+            is_local(h.value.as_str(), "")
+        } else {
+            true
+        }
+    })
+}
+
+/// Add headers allowing cross-origin requests to the response.
+fn with_cors(mut resp: ResponseBox) -> ResponseBox {
+    for (name, value) in [("Access-Control-Allow-Origin", "*"),
+                          ("Access-Control-Allow-Methods", "GET, POST, OPTIONS"),
+                          ("Access-Control-Allow-Headers", "*")] {
+        resp.add_header(Header::from_bytes(name.as_bytes(), value.as_bytes()).unwrap());
+    }
+    resp
+}
+
+/// Handle requests that control the player.
+fn control_request(method: &Method, url: &str) -> Option<ToGui> {
+    match (method, url) {
+        (Method::Post, "/control/pause") => Some(ToGui::Pause(true)),
+        (Method::Post, "/control/resume") => Some(ToGui::Pause(false)),
+        _ => None,
     }
 }
 
@@ -119,17 +286,441 @@ const SPLASH_HTML: &[u8] = br#"<!doctype html>
 
 const SPLASH_JPG: &[u8] = include_bytes!("../assets/splash.jpg");
 
+/// Create the page shown while the display waits for authorization in the
+/// CMS, with a QR code linking to the CMS display administration.
+pub fn register_page(cms_address: &str, display_id: &str) -> String {
+    let url = format!("{}/display/view?hardwareKey={}",
+                      cms_address.trim_end_matches('/'), display_id);
+    let qr = match QrCode::new(url.as_bytes()) {
+        Ok(code) => code.render::<svg::Color>().min_dimensions(300, 300).build(),
+        Err(e) => {
+            log::warn!("could not create QR code: {}", e);
+            String::new()
+        }
+    };
+    format!(r#"<!doctype html>
+<html>
+<head><meta charset="utf-8"></head>
+<body style="margin: 0; background: #fff; font-family: sans-serif; text-align: center">
+<h1 style="margin-top: 10vh">Waiting for authorization</h1>
+<p>Please authorize this display in the CMS at {}.</p>
+<p>Display ID: <code>{}</code></p>
+{}
+</body>
+</html>
+"#, escape_html(cms_address), escape_html(display_id), qr)
+}
+
+/// Create the page shown while the CMS can't be reached and nothing else is
+/// available to show.  If an image is configured, it is embedded in the page.
+pub fn fallback_page(cms_address: &str, image: Option<&Path>) -> String {
+    let image = image.and_then(|path| match fs::read(path) {
+        Ok(data) => Some(format!("data:{};base64,{}",
+                                 mime_type(path).unwrap_or("application/octet-stream"),
+                                 BASE64.encode(data))),
+        Err(e) => {
+            log::warn!("could not read fallback image {}: {}", path.display(), e);
+            None
+        }
+    });
+    if let Some(src) = image {
+        return format!(r#"<!doctype html>
+<html>
+<body style="margin: 0">
+<img style="display: block; width: 100%; height: 100%; object-fit: contain" src="{}">
+</body>
+</html>
+"#, src);
+    }
+    format!(r#"<!doctype html>
+<html>
+<head><meta charset="utf-8"></head>
+<body style="margin: 0; background: #fff; font-family: sans-serif; text-align: center">
+<img style="display: block; width: 100%; height: 80vh; object-fit: contain" src="splash.jpg">
+<p>Connecting to the CMS at {}...</p>
+</body>
+</html>
+"#, escape_html(cms_address))
+}
+
+/// Guess the MIME type based on the file name.
+fn mime_type(path: &Path) -> Option<&'static str> {
+    Some(match path.extension().and_then(|ext| ext.to_str())? {
+        "html" => "text/html",
+        "js" => "text/javascript",
+        "css" => "text/css",
+        "json" => "application/json",
+        "ttf" | "otf" => "application/font-sfnt",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "pdf" => "application/pdf",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "svg" => "image/svg+xml",
+        "webp" => "image/webp",
+        "mp4" => "video/mp4",
+        "avi" => "video/avi",
+        "ogv" => "video/ogg",
+        "webm" => "video/webm",
+        "mp3" => "audio/mpeg",
+        "m4a" => "audio/mp4",
+        "ogg" | "oga" => "audio/ogg",
+        "wav" => "audio/wav",
+        "flac" => "audio/flac",
+        _ => return None,
+    })
+}
+
+/// Create a weak ETag for a file from its modification time and size.
+fn file_etag(meta: &fs::Metadata) -> String {
+    let mtime = meta.modified().ok()
+                    .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+                    .unwrap_or_default();
+    format!("W/\"{:x}-{:x}-{:x}\"", mtime.as_secs(), mtime.subsec_nanos(), meta.len())
+}
+
+/// Check if the request's If-None-Match header matches the ETag.
+fn etag_matches(req: &Request, etag: &str) -> bool {
+    req.headers().iter().filter(|h| h.field.equiv("If-None-Match")).any(|h| {
+        h.value.as_str().split(',').map(str::trim).any(|tag| tag == etag || tag == "*")
+    })
+}
+
+/// Check if files of the MIME type are worth compressing.
+fn is_compressible(ctype: &str) -> bool {
+    matches!(ctype, "text/html" | "text/javascript" | "text/css" | "application/json" |
+             "application/font-sfnt")
+}
+
+/// Check if the client accepts gzip encoded responses.
+fn accepts_gzip(req: &Request) -> bool {
+    req.headers().iter().filter(|h| h.field.equiv("Accept-Encoding")).any(|h| {
+        h.value.as_str().split(',').any(|enc| {
+            let mut parts = enc.split(';').map(str::trim);
+            parts.next() == Some("gzip") && parts.all(|p| p.replace(' ', "") != "q=0")
+        })
+    })
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
 
-/// Parse a HTTP Range header.
-fn parse_range(total_size: u64, header: String) -> Result<(u64, u64, u64)> {
-    let mut parts = header.split(&['=', '-'][..]);
-    let (from, to) = match parts.next_tuple() {
-        Some(("bytes", from, to)) => {
-            (from.parse().unwrap_or(0), to.parse().unwrap_or(total_size - 1))
+/// Bind the listening socket.  Accepted connections inherit its send and
+/// receive timeouts, so that a stalled client fails the blocked write or
+/// read and its connection is closed, instead of occupying a worker.
+fn bind(port: u16, timeout: Duration) -> Result<TcpListener> {
+    let listener = TcpListener::bind(("127.0.0.1", port)).context("binding socket")?;
+    let timeout = TimeVal::new(timeout.as_secs() as _, timeout.subsec_micros() as _);
+    setsockopt(&listener, sockopt::SendTimeout, &timeout).context("setting send timeout")?;
+    setsockopt(&listener, sockopt::ReceiveTimeout, &timeout).context("setting receive timeout")?;
+    Ok(listener)
+}
+
+/// Check if an I/O error is caused by the socket timeouts set in `bind`.
+fn is_timeout(err: &io::Error) -> bool {
+    matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
+
+
+/// Parse a HTTP Range header with a single range, which can be open-ended
+/// (`bytes=500-`) or a suffix (`bytes=-500`).  Returns the first and last
+/// byte, or None if the range can't be satisfied.
+fn parse_range(total_size: u64, header: &str) -> Result<Option<(u64, u64)>> {
+    let (from, to) = match header.trim().split(&['=', '-'][..]).collect_tuple() {
+        Some(("bytes", from, to)) => (from.trim(), to.trim()),
+        _ => bail!("unsupported Range header {:?}", header)
+    };
+    let parse = |s: &str| s.parse::<u64>().map_err(|_| anyhow!("invalid Range header {:?}", header));
+    let (from, to) = match (from, to) {
+        ("", "") => bail!("invalid Range header {:?}", header),
+        ("", suffix) => (total_size.saturating_sub(parse(suffix)?), total_size.saturating_sub(1)),
+        (from, "") => (parse(from)?, total_size.saturating_sub(1)),
+        (from, to) => {
+            let (from, to) = (parse(from)?, parse(to)?);
+            ensure!(from <= to, "invalid Range header {:?}", header);
+            (from, to.min(total_size.saturating_sub(1)))
         }
-        _ => bail!("invalid Range header")
     };
-    ensure!(from <= to && to < total_size, "invalid Range from/to");
-    let size = to - from + 1;
-    Ok((from, to, size))
+    if from >= total_size {
+        return Ok(None);
+    }
+    Ok(Some((from, to)))
+}
+
+#[test]
+fn test_serve_api() {
+    let info = r#"{"hardwareKey":"abc"}"#;
+    for path in ["/duration/set", "/duration/extend", "/duration/expire", "/trigger"] {
+        assert_eq!(serve_api(&Method::Post, path, info), Some((200, "{}".into())));
+    }
+    assert_eq!(serve_api(&Method::Get, "/info", info), Some((200, info.into())));
+    assert_eq!(serve_api(&Method::Get, "/realtime?dataKey=x", info), Some((404, "{}".into())));
+    // everything else is served from files
+    assert_eq!(serve_api(&Method::Get, "/duration/set", info), None);
+    assert_eq!(serve_api(&Method::Get, "/1.xlf.html", info), None);
+
+    // the API takes precedence over files, even if they don't exist
+    let dir = std::env::temp_dir().join("arexibo-test-nonexisting");
+    let req: Request = tiny_http::TestRequest::new()
+        .with_method(Method::Post)
+        .with_path("/duration/expire")
+        .into();
+    let resp = Server::serve(&dir, "{}", &[], &req).unwrap();
+    assert_eq!(resp.status_code(), StatusCode(200));
+    assert_eq!(resp.headers().iter().find(|h| h.field.equiv("Content-Type"))
+               .map(|h| h.value.to_string()).as_deref(), Some("application/json"));
+}
+
+#[test]
+fn test_register_page() {
+    let page = register_page("https://cms.example.com/", "0123abcd");
+    assert!(page.contains("<code>0123abcd</code>"));
+    assert!(page.contains("<svg"));
+    assert!(page.contains("cms.example.com"));
+}
+
+#[test]
+fn test_connection_timeout() {
+    let listener = bind(0, Duration::from_secs(2)).unwrap();
+    let _client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (conn, _) = listener.accept().unwrap();
+    // the timeouts apply to each connection
+    assert_eq!(conn.write_timeout().unwrap(), Some(Duration::from_secs(2)));
+    assert_eq!(conn.read_timeout().unwrap(), Some(Duration::from_secs(2)));
+}
+
+#[test]
+fn test_slow_client() {
+    use std::{io::Write, net::TcpStream, time::Instant};
+    let dir = std::env::temp_dir().join(format!("arexibo-test-slow-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    // large enough to fill the socket buffers of a client that doesn't read
+    fs::write(dir.join("big.mp4"), vec![0u8; 64 << 20]).unwrap();
+    let (to_gui, _rx) = glib::MainContext::channel(glib::PRIORITY_DEFAULT);
+    let timeout = Duration::from_millis(500);
+    let server = Server::new(dir.clone(), 0, timeout, "{}".into(), vec![], to_gui).unwrap();
+    let port = server.port();
+    server.start_pool(1);
+
+    // one client connects and sends nothing, another one stops reading its
+    // response and blocks the only worker
+    let _idle = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    let mut stalled = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stalled.write_all(b"GET /big.mp4 HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    thread::sleep(Duration::from_millis(100));
+
+    // the next request is served once the stalled one has timed out
+    let start = Instant::now();
+    let mut client = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    client.write_all(b"GET /splash.jpg HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+          .unwrap();
+    let mut resp = Vec::new();
+    client.read_to_end(&mut resp).unwrap();
+    assert!(resp.starts_with(b"HTTP/1.1 200 OK"));
+    assert!(start.elapsed() >= timeout - Duration::from_millis(100));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_timeout_error() {
+    assert!(is_timeout(&io::Error::from(io::ErrorKind::WouldBlock)));
+    assert!(is_timeout(&io::Error::from(io::ErrorKind::TimedOut)));
+    assert!(!is_timeout(&io::Error::from(io::ErrorKind::BrokenPipe)));
+}
+
+#[test]
+fn test_control_request() {
+    assert!(matches!(control_request(&Method::Post, "/control/pause"), Some(ToGui::Pause(true))));
+    assert!(matches!(control_request(&Method::Post, "/control/resume"), Some(ToGui::Pause(false))));
+    assert!(control_request(&Method::Get, "/control/pause").is_none());
+    assert!(control_request(&Method::Post, "/duration/set").is_none());
+}
+
+#[test]
+fn test_local_request() {
+    let header = |name: &str, value: &str| Header::from_bytes(name.as_bytes(), value.as_bytes()).unwrap();
+    assert!(is_local_request(&[header("Host", "127.0.0.1:9696")], 9696));
+    assert!(is_local_request(&[header("Host", "localhost:9696"),
+                               header("Origin", "http://localhost:9696")], 9696));
+    assert!(!is_local_request(&[header("Host", "localhost:9696"),
+                                header("Origin", "https://example.com")], 9696));
+    assert!(!is_local_request(&[header("Host", "evil.example.com:9696")], 9696));
+    assert!(!is_local_request(&[header("Host", "localhost:9697")], 9696));
+}
+
+#[test]
+fn test_fallback_page() {
+    let page = fallback_page("https://cms.example.com/", None);
+    assert!(page.contains("Connecting to the CMS at https://cms.example.com/"));
+    let path = std::env::temp_dir().join("arexibo-test-fallback.png");
+    fs::write(&path, b"PNG").unwrap();
+    let page = fallback_page("https://cms.example.com/", Some(&path));
+    assert!(page.contains(r#"src="data:image/png;base64,UE5H""#));
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_range_content_type() {
+    let dir = std::env::temp_dir().join("arexibo-test-range");
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("a.mp3"), b"0123456789").unwrap();
+    let req: Request = tiny_http::TestRequest::new()
+        .with_path("/a.mp3")
+        .with_header(Header::from_bytes(&b"Range"[..], &b"bytes=2-5"[..]).unwrap())
+        .into();
+    let resp = Server::serve(&dir, "{}", &[], &req).unwrap();
+    assert_eq!(resp.status_code(), StatusCode(206));
+    let header = |name: &str| resp.headers().iter().find(|h| h.field.equiv(name))
+                                  .map(|h| h.value.to_string());
+    assert_eq!(header("Content-Type").as_deref(), Some("audio/mpeg"));
+    assert_eq!(header("Content-Range").as_deref(), Some("bytes 2-5/10"));
+
+    let req: Request = tiny_http::TestRequest::new()
+        .with_path("/a.mp3")
+        .with_header(Header::from_bytes(&b"Range"[..], &b"bytes=10-"[..]).unwrap())
+        .into();
+    let resp = Server::serve(&dir, "{}", &[], &req).unwrap();
+    assert_eq!(resp.status_code(), StatusCode(416));
+    assert_eq!(resp.headers().iter().find(|h| h.field.equiv("Content-Range"))
+               .map(|h| h.value.to_string()).as_deref(), Some("bytes */10"));
+}
+
+#[test]
+fn test_parse_range() {
+    assert_eq!(parse_range(10, "bytes=2-5").unwrap(), Some((2, 5)));
+    assert_eq!(parse_range(10, "bytes=5-").unwrap(), Some((5, 9)));
+    assert_eq!(parse_range(10, "bytes=-3").unwrap(), Some((7, 9)));
+    assert_eq!(parse_range(10, "bytes=-30").unwrap(), Some((0, 9)));
+    assert_eq!(parse_range(10, "bytes=8-20").unwrap(), Some((8, 9)));
+    assert_eq!(parse_range(10, "bytes=10-").unwrap(), None);
+    assert_eq!(parse_range(10, "bytes=-0").unwrap(), None);
+    assert_eq!(parse_range(0, "bytes=0-").unwrap(), None);
+    assert!(parse_range(10, "bytes=5-2").is_err());
+    assert!(parse_range(10, "bytes=0-1,5-6").is_err());
+    assert!(parse_range(10, "items=0-1").is_err());
+}
+
+#[test]
+fn test_bound_port() {
+    let (to_gui, _rx) = glib::MainContext::channel(glib::PRIORITY_DEFAULT);
+    // with port 0, the OS chooses the port
+    let server = Server::new(std::env::temp_dir(), 0, Duration::from_secs(30), "{}".into(),
+                             vec![], to_gui).unwrap();
+    let port = server.port();
+    assert_ne!(port, 0);
+    assert!(std::net::TcpStream::connect(("127.0.0.1", port)).is_ok());
+}
+
+#[test]
+fn test_cors() {
+    let dir = std::env::temp_dir().join("arexibo-test-nonexisting");
+    let header = |resp: &ResponseBox, name: &str| resp.headers().iter().find(|h| h.field.equiv(name))
+                                                     .map(|h| h.value.to_string());
+    let req: Request = tiny_http::TestRequest::new()
+        .with_method(Method::Options)
+        .with_path("/duration/set")
+        .into();
+    let resp = Server::serve(&dir, "{}", &[], &req).unwrap();
+    assert_eq!(resp.status_code(), StatusCode(204));
+    assert_eq!(header(&resp, "Access-Control-Allow-Origin").as_deref(), Some("*"));
+    assert_eq!(header(&resp, "Access-Control-Allow-Methods").as_deref(), Some("GET, POST, OPTIONS"));
+    // also on normal responses
+    let req: Request = tiny_http::TestRequest::new().with_path("/0.xlf.html").into();
+    let resp = Server::serve(&dir, "{}", &[], &req).unwrap();
+    assert_eq!(resp.status_code(), StatusCode(200));
+    assert_eq!(header(&resp, "Access-Control-Allow-Origin").as_deref(), Some("*"));
+    // but not for display information and player control
+    for (method, path) in [(Method::Get, "/info"), (Method::Options, "/info"),
+                           (Method::Options, "/control/pause")] {
+        let req: Request = tiny_http::TestRequest::new().with_method(method).with_path(path).into();
+        let resp = Server::serve(&dir, "{}", &[], &req).unwrap();
+        assert_eq!(header(&resp, "Access-Control-Allow-Origin"), None);
+    }
+}
+
+#[test]
+fn test_gzip() {
+    let dir = std::env::temp_dir().join("arexibo-test-gzip");
+    fs::create_dir_all(&dir).unwrap();
+    let html = "<div class='media'></div>\n".repeat(100);
+    fs::write(dir.join("1.xlf.html"), &html).unwrap();
+    fs::write(dir.join("a.mp4"), b"0123456789").unwrap();
+    let get = |path: &str, headers: &[(&str, &str)]| {
+        let mut req = tiny_http::TestRequest::new().with_path(path);
+        for (name, value) in headers {
+            req = req.with_header(Header::from_bytes(name.as_bytes(), value.as_bytes()).unwrap());
+        }
+        let resp = Server::serve(&dir, "{}", &[], &req.into()).unwrap();
+        let encoding = resp.headers().iter().find(|h| h.field.equiv("Content-Encoding"))
+                                            .map(|h| h.value.to_string());
+        let mut body = Vec::new();
+        resp.into_reader().read_to_end(&mut body).unwrap();
+        (encoding, body)
+    };
+
+    let (encoding, body) = get("/1.xlf.html", &[("Accept-Encoding", "deflate, gzip")]);
+    assert_eq!(encoding.as_deref(), Some("gzip"));
+    assert!(body.len() < html.len());
+    let mut decoded = String::new();
+    flate2::read::GzDecoder::new(&body[..]).read_to_string(&mut decoded).unwrap();
+    assert_eq!(decoded, html);
+
+    // not without the header, or if gzip is refused
+    assert_eq!(get("/1.xlf.html", &[]), (None, html.clone().into_bytes()));
+    assert_eq!(get("/1.xlf.html", &[("Accept-Encoding", "gzip;q=0")]).0, None);
+    // not for media and ranges
+    assert_eq!(get("/a.mp4", &[("Accept-Encoding", "gzip")]).0, None);
+    assert_eq!(get("/1.xlf.html", &[("Accept-Encoding", "gzip"), ("Range", "bytes=0-9")]).0, None);
+}
+
+#[test]
+fn test_caching() {
+    let dir = std::env::temp_dir().join("arexibo-test-caching");
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("1.xlf.html"), "<html></html>").unwrap();
+    fs::write(dir.join("a.mp4"), b"0123456789").unwrap();
+    let get = |path: &str, etag: Option<&str>| {
+        let mut req = tiny_http::TestRequest::new().with_path(path);
+        if let Some(etag) = etag {
+            req = req.with_header(Header::from_bytes(&b"If-None-Match"[..], etag.as_bytes()).unwrap());
+        }
+        let resp = Server::serve(&dir, "{}", &[], &req.into()).unwrap();
+        let header = |name: &str| resp.headers().iter().find(|h| h.field.equiv(name))
+                                      .map(|h| h.value.to_string()).unwrap();
+        (resp.status_code().0, header("ETag"), header("Cache-Control"))
+    };
+    let (status, etag, cache) = get("/a.mp4", None);
+    assert_eq!((status, cache.as_str()), (200, "max-age=3600"));
+    assert_eq!(get("/a.mp4", Some(&etag)).0, 304);
+    assert_eq!(get("/a.mp4", Some(&format!("\"other\", {}", etag))).0, 304);
+    assert_eq!(get("/a.mp4", Some("\"other\"")).0, 200);
+    // layouts are always checked for changes
+    let (status, etag, cache) = get("/1.xlf.html", None);
+    assert_eq!((status, cache.as_str()), (200, "no-cache"));
+    assert_eq!(get("/1.xlf.html", Some(&etag)).0, 304);
+    fs::write(dir.join("1.xlf.html"), "<html>changed</html>").unwrap();
+    assert_eq!(get("/1.xlf.html", Some(&etag)).0, 200);
+}
+
+#[test]
+fn test_mime_type() {
+    let ctype = |name: &str| {
+        let dir = std::env::temp_dir().join("arexibo-test-mime");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(name), b"data").unwrap();
+        let req: Request = tiny_http::TestRequest::new().with_path(&format!("/{}", name)).into();
+        let resp = Server::serve(&dir, "{}", &[], &req).unwrap();
+        resp.headers().iter().find(|h| h.field.equiv("Content-Type")).map(|h| h.value.to_string())
+    };
+    for (name, expected) in [("a.svg", "image/svg+xml"), ("a.woff", "font/woff"),
+                             ("a.woff2", "font/woff2"), ("a.webp", "image/webp"),
+                             ("a.gif", "image/gif"), ("a.mp3", "audio/mpeg"),
+                             ("a.m4a", "audio/mp4"), ("a.json", "application/json"),
+                             ("a.css", "text/css"), ("a.bin", "application/octet-stream")] {
+        assert_eq!(ctype(name).as_deref(), Some(expected), "{}", name);
+    }
 }
@@ -0,0 +1,236 @@
+// Xibo player Rust implementation, (c) 2022-2024 Georg Brandl.
+// Licensed under the GNU AGPL, version 3 or later.
+
+//! Proof-of-play statistics.
+
+use std::{collections::{HashMap, VecDeque}, fs, path::PathBuf};
+use anyhow::{Context, Result};
+use elementtree::Element;
+use serde::{Serialize, Deserialize};
+use time::{OffsetDateTime, UtcOffset};
+use crate::config::StatsAggregation;
+use crate::util::{TIME_FMT, write_atomic};
+
+/// Maximum number of records to keep while they can't be submitted.
+const MAX_RECORDS: usize = 10000;
+/// Maximum number of records to submit with one call.
+const BATCH_SIZE: usize = 300;
+
+/// A single statistics record.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Record {
+    /// "layout" or "media"
+    pub typ: String,
+    pub layout_id: i64,
+    pub media_id: Option<i64>,
+    /// Start of the play as Unix timestamp.
+    pub from: i64,
+    /// End of the play as Unix timestamp.
+    pub to: i64,
+    pub count: u32,
+}
+
+impl Record {
+    fn new(typ: &str, layout_id: i64, media_id: Option<i64>, from: i64, to: i64) -> Self {
+        Self { typ: typ.into(), layout_id, media_id, from, to, count: 1 }
+    }
+}
+
+/// Tracks the currently shown layout and media, to create records when
+/// they stop being shown.
+#[derive(Debug, Default)]
+pub struct Tracker {
+    /// Layout ID and start time.
+    layout: Option<(i64, i64)>,
+    /// Media ID -> layout ID and start time.
+    media: HashMap<i64, (i64, i64)>,
+}
+
+impl Tracker {
+    /// A new layout is shown (0 for the splash screen).  Returns the records
+    /// for the previous layout and its media.
+    pub fn layout_shown(&mut self, layout_id: i64, now: i64) -> Vec<Record> {
+        let mut records = self.media.drain().map(|(mid, (lid, from))| {
+            Record::new("media", lid, Some(mid), from, now)
+        }).collect::<Vec<_>>();
+        records.sort_by_key(|r| r.media_id);
+        if let Some((lid, from)) = self.layout.take() {
+            records.push(Record::new("layout", lid, None, from, now));
+        }
+        if layout_id != 0 {
+            self.layout = Some((layout_id, now));
+        }
+        records
+    }
+
+    /// A media of the current layout starts or stops being shown.  Returns
+    /// the record if it stopped.
+    pub fn media_event(&mut self, start: bool, media_id: i64, now: i64) -> Option<Record> {
+        if start {
+            let (layout_id, _) = self.layout?;
+            self.media.insert(media_id, (layout_id, now));
+            None
+        } else {
+            let (layout_id, from) = self.media.remove(&media_id)?;
+            Some(Record::new("media", layout_id, Some(media_id), from, now))
+        }
+    }
+}
+
+/// Disk-backed queue of statistics records waiting for submission to the CMS.
+pub struct Queue {
+    path: PathBuf,
+    records: VecDeque<Record>,
+}
+
+impl Queue {
+    /// Load the queue from the given file, if it exists.
+    pub fn load(path: PathBuf) -> Self {
+        let records = fs::File::open(&path).ok()
+            .and_then(|fp| serde_json::from_reader(fp).ok())
+            .unwrap_or_default();
+        Self { path, records }
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// Add new records, dropping the oldest ones if the queue is full.
+    pub fn push(&mut self, records: impl IntoIterator<Item=Record>,
+                aggregation: StatsAggregation) -> Result<()> {
+        for record in records {
+            match aggregation {
+                StatsAggregation::Individual => self.records.push_back(record),
+                StatsAggregation::Hourly => self.add_hourly(record),
+            }
+        }
+        if self.records.len() > MAX_RECORDS {
+            let excess = self.records.len() - MAX_RECORDS;
+            log::warn!("stats queue is full, dropping {} oldest records", excess);
+            self.records.drain(..excess);
+        }
+        self.save()
+    }
+
+    /// Count the record in the record for the hour it started in.
+    fn add_hourly(&mut self, record: Record) {
+        let from = record.from - record.from.rem_euclid(3600);
+        let existing = self.records.iter_mut().rev().find(|r| {
+            r.typ == record.typ && r.layout_id == record.layout_id &&
+                r.media_id == record.media_id && r.from == from && r.to == from + 3600
+        });
+        if let Some(existing) = existing {
+            existing.count += record.count;
+        } else {
+            self.records.push_back(Record { from, to: from + 3600, ..record });
+        }
+    }
+
+    /// Submit all queued records in batches using the given function.
+    /// Only successfully submitted records are removed from the queue.
+    pub fn submit(&mut self, mut submit: impl FnMut(&str) -> Result<()>) -> Result<()> {
+        while !self.records.is_empty() {
+            let nrecords = self.records.len().min(BATCH_SIZE);
+            submit(&to_xml(self.records.range(..nrecords))?)?;
+            self.records.drain(..nrecords);
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    fn save(&self) -> Result<()> {
+        let data = serde_json::to_vec(&self.records).context("serializing stats")?;
+        write_atomic(&self.path, &data).context("writing stats queue")
+    }
+}
+
+/// Format records as XML as expected by the SubmitStats call.
+fn to_xml<'a>(records: impl Iterator<Item=&'a Record>) -> Result<String> {
+    let offset = UtcOffset::current_local_offset().unwrap_or(UtcOffset::UTC);
+    let format = |ts| -> Result<String> {
+        Ok(OffsetDateTime::from_unix_timestamp(ts)?.to_offset(offset).format(&TIME_FMT)?)
+    };
+    let mut stats = Element::new("stats");
+    for record in records {
+        let stat = stats.append_child(Element::new("stat"));
+        stat.set_attr("type", &record.typ);
+        stat.set_attr("fromdt", format(record.from)?);
+        stat.set_attr("todt", format(record.to)?);
+        stat.set_attr("scheduleid", "0");
+        stat.set_attr("layoutid", record.layout_id.to_string());
+        stat.set_attr("mediaid", record.media_id.map(|id| id.to_string()).unwrap_or_default());
+        stat.set_attr("tag", "");
+        stat.set_attr("count", record.count.to_string());
+    }
+    Ok(stats.to_string()?)
+}
+
+#[test]
+fn test_queue_persistence() {
+    let path = std::env::temp_dir().join(format!("arexibo-test-stats-{}.json",
+                                                 std::process::id()));
+    let _ = fs::remove_file(&path);
+    let record = Record { typ: "media".into(), layout_id: 1, media_id: Some(2),
+                          from: 1700000000, to: 1700000010, count: 1 };
+    let mut queue = Queue::load(path.clone());
+    queue.push(vec![record; 2], StatsAggregation::Individual).unwrap();
+    // CMS is unreachable: records are kept
+    assert!(queue.submit(|_| anyhow::bail!("unreachable")).is_err());
+    // simulate a restart
+    let mut queue = Queue::load(path.clone());
+    assert_eq!(queue.len(), 2);
+    let mut submitted = Vec::new();
+    queue.submit(|xml| { submitted.push(xml.to_owned()); Ok(()) }).unwrap();
+    assert_eq!(submitted.len(), 1);
+    assert_eq!(submitted[0].matches("<stat ").count(), 2);
+    assert!(Queue::load(path.clone()).is_empty());
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_tracker() {
+    let mut tracker = Tracker::default();
+    // nothing is recorded for the splash screen
+    assert!(tracker.layout_shown(0, 100).is_empty());
+    assert_eq!(tracker.media_event(true, 10, 100), None);
+    assert_eq!(tracker.media_event(false, 10, 105), None);
+
+    assert!(tracker.layout_shown(1, 110).is_empty());
+    assert_eq!(tracker.media_event(true, 10, 110), None);
+    assert_eq!(tracker.media_event(true, 11, 110), None);
+    assert_eq!(tracker.media_event(false, 10, 120),
+               Some(Record::new("media", 1, Some(10), 110, 120)));
+    // changing the layout ends the layout and all its media
+    assert_eq!(tracker.layout_shown(2, 130), vec![
+        Record::new("media", 1, Some(11), 110, 130),
+        Record::new("layout", 1, None, 110, 130),
+    ]);
+    assert_eq!(tracker.layout_shown(0, 140), vec![Record::new("layout", 2, None, 130, 140)]);
+}
+
+#[test]
+fn test_hourly_aggregation() {
+    let path = std::env::temp_dir().join(format!("arexibo-test-stats-hourly-{}.json",
+                                                 std::process::id()));
+    let mut queue = Queue::load(path.clone());
+    // 7200 is on the hour
+    let plays = (0..14).map(|i| Record::new("media", 1, Some(2), 7200 + i * 300, 7200 + i * 300 + 10));
+    queue.push(plays, StatsAggregation::Hourly).unwrap();
+    queue.push([Record::new("media", 1, Some(3), 7300, 7310),
+                Record::new("layout", 1, None, 7200, 7400)], StatsAggregation::Hourly).unwrap();
+    assert_eq!(queue.records, [
+        Record { count: 12, ..Record::new("media", 1, Some(2), 7200, 10800) },
+        Record { count: 2, ..Record::new("media", 1, Some(2), 10800, 14400) },
+        Record::new("media", 1, Some(3), 7200, 10800),
+        Record::new("layout", 1, None, 7200, 10800),
+    ]);
+    // individual records are kept as they are
+    queue.push([Record::new("media", 1, Some(3), 7300, 7310)], StatsAggregation::Individual).unwrap();
+    assert_eq!(queue.len(), 5);
+    fs::remove_file(&path).unwrap();
+}
@@ -3,13 +3,16 @@
 
 //! Various utilities.
 
-use std::{fs, fmt, path::Path, str::FromStr, time::Duration};
-use anyhow::{Context, Result};
+use std::{fs, fmt, io::Read, process::Command, str::FromStr, time::Duration};
+use std::path::{Component, Path};
+use std::net::{IpAddr, UdpSocket};
+use std::os::unix::net::UnixDatagram;
+use anyhow::{ensure, Context, Result};
 use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
 use dbus::blocking::{Connection};
 use md5::{Md5, Digest};
 use nix::{sys::statvfs, unistd::gethostname};
-use once_cell::sync::Lazy;
+use once_cell::sync::{Lazy, OnceCell};
 use serde::{Deserialize, Deserializer, Serializer, de::Error};
 
 /// Common time format used by the CMS.
@@ -42,6 +45,8 @@ pub trait ElementExt {
         where T::Err: std::error::Error + Sync + Send + 'static;
     fn parse_child<T: FromStr>(&self, child: &str) -> Result<T>
         where T::Err: std::error::Error + Sync + Send + 'static;
+    fn parse_child_or<T: FromStr>(&self, child: &str, def: T) -> Result<T>
+        where T::Err: std::error::Error + Sync + Send + 'static;
 }
 
 impl ElementExt for elementtree::Element {
@@ -63,26 +68,36 @@ impl ElementExt for elementtree::Element {
                         .text()
                         .parse().with_context(|| format!("invalid {}", child))
     }
+
+    fn parse_child_or<T: FromStr>(&self, child: &str, def: T) -> Result<T>
+        where T::Err: std::error::Error+Sync+Send+'static
+    {
+        match self.find(child) {
+            Some(el) => el.text().parse().with_context(|| format!("invalid {}", child)),
+            None => Ok(def),
+        }
+    }
 }
 
 
+/// Decode %XX escapes, interpreting the result as UTF-8.
 pub fn percent_decode(s: &str) -> String {
-    let mut res = String::new();
-    let mut iter = s.char_indices();
-    while let Some((i, ch)) = iter.next() {
-        match ch {
-            '%' => {
-                let codepoint = s.get(i+1..i+3)
-                                 .and_then(|s| u8::from_str_radix(s, 16).ok());
-                if let Some(hex) = codepoint {
-                    res.push(hex as char);
+    let mut res = Vec::with_capacity(s.len());
+    let mut iter = s.bytes().enumerate();
+    while let Some((i, byte)) = iter.next() {
+        match byte {
+            b'%' => {
+                let decoded = s.get(i+1..i+3)
+                               .and_then(|s| u8::from_str_radix(s, 16).ok());
+                if let Some(byte) = decoded {
+                    res.push(byte);
                     iter.nth(1);
                 }
             },
-            _ => res.push(ch),
+            _ => res.push(byte),
         }
     }
-    res
+    String::from_utf8_lossy(&res).into_owned()
 }
 
 
@@ -98,16 +113,31 @@ pub fn de_hex<'de, D: Deserializer<'de>>(d: D) -> std::result::Result<Vec<u8>, D
 }
 
 
-/// Retrieve MAC address of a system interface.
-pub fn retrieve_mac() -> Option<String> {
-    for entry in fs::read_dir("/sys/class/net").ok()? {
+/// Retrieve MAC address of a system interface.  If an interface is given,
+/// its address is used, otherwise the first suitable interface found.
+pub fn retrieve_mac(interface: Option<&str>) -> Option<String> {
+    let (name, addr) = find_mac(Path::new("/sys/class/net"), interface)?;
+    log::info!("using MAC address {} of interface {}", addr, name);
+    Some(addr)
+}
+
+fn find_mac(netdir: &Path, interface: Option<&str>) -> Option<(String, String)> {
+    let valid = |addr: &str| !addr.is_empty() && !addr.ends_with(":00:00");
+    if let Some(name) = interface {
+        match fs::read_to_string(netdir.join(name).join("address")) {
+            Ok(addr) if valid(addr.trim()) => return Some((name.into(), addr.trim().into())),
+            _ => log::warn!("interface {} has no usable MAC address, ignoring it", name),
+        }
+    }
+    for entry in fs::read_dir(netdir).ok()? {
         let path = entry.ok()?.path();
         // addr_assign_type 0 means that it is an actual permanent address.
         if let Ok("0\n" | "3\n") = fs::read_to_string(path.join("addr_assign_type")).as_deref() {
             if let Ok("1\n") = fs::read_to_string(path.join("carrier")).as_deref() {
                 if let Ok(addr) = fs::read_to_string(path.join("address")) {
-                    if !addr.ends_with(":00:00\n") {
-                        return Some(addr.trim().into());
+                    if valid(addr.trim()) {
+                        let name = path.file_name()?.to_string_lossy().into_owned();
+                        return Some((name, addr.trim().into()));
                     }
                 }
             }
@@ -117,7 +147,7 @@ pub fn retrieve_mac() -> Option<String> {
 }
 
 /// Generate a display ID.  Tries /etc/machine-id, the DMI board id, the MAC or the hostname.
-pub fn get_display_id() -> String {
+pub fn get_display_id(mac_interface: Option<&str>) -> String {
     if let Ok(id) = fs::read_to_string("/etc/machine-id") {
         return id.trim().into();
     }
@@ -127,7 +157,7 @@ pub fn get_display_id() -> String {
         "{:?}{:?}{:?}{:?}",
         fs::read_to_string("/sys/devices/virtual/dmi/id/board_name"),
         fs::read_to_string("/sys/devices/virtual/dmi/id/board_version"),
-        retrieve_mac(),
+        retrieve_mac(mac_interface),
         gethostname().ok().and_then(|s| s.into_string().ok())
     );
     hex::encode(Md5::digest(idstring.as_bytes()))
@@ -145,12 +175,41 @@ const SS_PATH: &str  = "/ScreenSaver";
 const SS_IFACE: &str = "org.freedesktop.ScreenSaver";
 const SS_METH: &str  = "Inhibit";
 
-/// Inhibit the screensaver.
-pub fn inhibit_screensaver() -> Result<u32> {
+/// An inhibited screensaver, which is allowed again when dropped.
+///
+/// The D-Bus connection is kept open, since the screensaver drops the
+/// inhibition when its client disconnects.
+pub struct ScreensaverInhibit {
+    conn: Connection,
+    cookie: u32,
+}
+
+impl Drop for ScreensaverInhibit {
+    fn drop(&mut self) {
+        let proxy = self.conn.with_proxy(SS_SVC, SS_PATH, Duration::from_millis(500));
+        let res: Result<(), _> = proxy.method_call(SS_IFACE, "UnInhibit", (self.cookie,));
+        if let Err(e) = res {
+            log::warn!("could not allow screensaver again: {}", e);
+        }
+    }
+}
+
+/// Inhibit the screensaver, until the returned guard is dropped.
+pub fn inhibit_screensaver() -> Result<ScreensaverInhibit> {
     let conn = Connection::new_session().context("connecting to dbus")?;
-    let proxy = conn.with_proxy(SS_SVC, SS_PATH, Duration::from_millis(500));
-    let res: (u32,) = proxy.method_call(SS_IFACE, SS_METH, ("Arexibo", "Showing signage"))?;
-    Ok(res.0)
+    let (cookie,): (u32,) = conn.with_proxy(SS_SVC, SS_PATH, Duration::from_millis(500))
+        .method_call(SS_IFACE, SS_METH, ("Arexibo", "Showing signage"))?;
+    Ok(ScreensaverInhibit { conn, cookie })
+}
+
+
+/// Write a file atomically, by writing a temporary file and renaming it.
+pub fn write_atomic(path: &Path, data: &[u8]) -> Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    fs::write(&tmp, data)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
 }
 
 
@@ -161,8 +220,145 @@ pub fn space_info(path: &Path) -> Result<(u64, u64)> {
         res.blocks() * res.fragment_size()))
 }
 
+/// Determine the duration of a video or audio file in seconds, using ffprobe
+/// if it is installed.
+pub fn probe_duration(path: &Path) -> Option<f64> {
+    let output = Command::new("ffprobe")
+        .args(["-v", "error", "-show_entries", "format=duration",
+               "-of", "default=noprint_wrappers=1:nokey=1"])
+        .arg(path)
+        .output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    String::from_utf8_lossy(&output.stdout).trim().parse().ok()
+}
+
+/// Read the family name of a TrueType/OpenType or WOFF font from its name
+/// table.  WOFF2 fonts are not supported.
+pub fn font_family(data: &[u8]) -> Option<String> {
+    let be16 = |d: &[u8], at: usize| d.get(at..at + 2).map(|b| u16::from_be_bytes([b[0], b[1]]));
+    let be32 = |d: &[u8], at: usize| d.get(at..at + 4)
+                                      .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]) as usize);
+    // find the name table in the table directory
+    let woff = data.starts_with(b"wOFF");
+    let (first, size) = if woff { (44, 20) } else { (12, 16) };
+    let ntables = be16(data, if woff { 12 } else { 4 })? as usize;
+    let entry = (0..ntables).map(|i| first + i * size)
+                            .find(|&at| data.get(at..at + 4) == Some(b"name"))?;
+    let table = if woff {
+        let (offset, length, orig_length) = (be32(data, entry + 4)?, be32(data, entry + 8)?,
+                                             be32(data, entry + 12)?);
+        let raw = data.get(offset..offset + length)?;
+        if length < orig_length {
+            let mut table = Vec::with_capacity(orig_length);
+            flate2::read::ZlibDecoder::new(raw).read_to_end(&mut table).ok()?;
+            table
+        } else {
+            raw.to_vec()
+        }
+    } else {
+        let (offset, length) = (be32(data, entry + 8)?, be32(data, entry + 12)?);
+        data.get(offset..offset + length)?.to_vec()
+    };
+    // prefer the typographic family (ID 16) over the legacy family (ID 1)
+    let count = be16(&table, 2)? as usize;
+    let strings = be16(&table, 4)? as usize;
+    let mut names = (0..count).filter_map(|i| {
+        let rec = 6 + i * 12;
+        let (platform, name_id) = (be16(&table, rec)?, be16(&table, rec + 6)?);
+        let (length, offset) = (be16(&table, rec + 8)? as usize, be16(&table, rec + 10)? as usize);
+        let raw = table.get(strings + offset..strings + offset + length)?;
+        let name: String = if platform == 1 {
+            raw.iter().map(|&b| b as char).collect()
+        } else {
+            let units = raw.chunks_exact(2).map(|c| u16::from_be_bytes([c[0], c[1]]));
+            char::decode_utf16(units).collect::<Result<String, _>>().ok()?
+        };
+        matches!(name_id, 1 | 16).then_some((name_id, name))
+    }).filter(|(_, name)| !name.is_empty()).collect::<Vec<_>>();
+    names.sort_by_key(|&(name_id, _)| std::cmp::Reverse(name_id));
+    names.into_iter().next().map(|(_, name)| name)
+}
+
+/// Signal that the player is showing content: create the given file, if
+/// any, and notify systemd if running as a notify service.
+pub fn notify_ready(ready_file: Option<&Path>) {
+    if let Some(path) = ready_file {
+        if let Err(e) = fs::write(path, b"") {
+            log::warn!("could not create ready file {}: {}", path.display(), e);
+        }
+    }
+    if let Some(socket) = std::env::var_os("NOTIFY_SOCKET") {
+        // abstract socket names are not supported
+        let res = UnixDatagram::unbound().and_then(|sock| sock.send_to(b"READY=1", &socket));
+        if let Err(e) = res {
+            log::warn!("could not notify systemd: {}", e);
+        }
+    }
+}
+
+/// Get the local IP address used for outgoing connections.
+pub fn local_ip() -> Option<IpAddr> {
+    // connecting a UDP socket doesn't send anything, but selects the route
+    let socket = UdpSocket::bind("0.0.0.0:0").ok()?;
+    socket.connect("192.0.2.1:80").ok()?;
+    Some(socket.local_addr().ok()?.ip())
+}
+
+/// Supported values for the GDK backend selection.
+pub const GDK_BACKENDS: &[&str] = &["x11", "wayland", "broadway"];
+
+/// Select the GDK backend and/or force software rendering by setting the
+/// respective environment variables.  Must be called before starting threads.
+pub fn select_rendering(backend: Option<&str>, software: bool) -> Result<()> {
+    if let Some(backend) = backend {
+        anyhow::ensure!(GDK_BACKENDS.contains(&backend),
+                        "unsupported backend {:?}, must be one of {}",
+                        backend, GDK_BACKENDS.join(", "));
+        std::env::set_var("GDK_BACKEND", backend);
+    }
+    if software {
+        std::env::set_var("WEBKIT_DISABLE_COMPOSITING_MODE", "1");
+        std::env::set_var("LIBGL_ALWAYS_SOFTWARE", "1");
+    }
+    log::info!("using {} backend{}",
+               std::env::var("GDK_BACKEND").as_deref().unwrap_or("default"),
+               if software { " with software rendering" } else { "" });
+    Ok(())
+}
+
+/// Directory of the tz database.
+const ZONEINFO: &str = "/usr/share/zoneinfo";
+
+/// Timezone configured to override the system's.
+static TIMEZONE: OnceCell<String> = OnceCell::new();
+
+/// Override the system timezone, which must be in the tz database.  This
+/// sets TZ for the process, so it must be called before starting threads.
+pub fn set_timezone(zone: &str) -> Result<()> {
+    check_timezone(Path::new(ZONEINFO), zone)?;
+    std::env::set_var("TZ", zone);
+    let _ = TIMEZONE.set(zone.into());
+    Ok(())
+}
+
+fn check_timezone(zoneinfo: &Path, zone: &str) -> Result<()> {
+    let path = Path::new(zone);
+    ensure!(!zone.is_empty() && path.components().all(|c| matches!(c, Component::Normal(_))),
+            "invalid timezone name {:?}", zone);
+    let mut magic = [0; 4];
+    fs::File::open(zoneinfo.join(path)).and_then(|mut fp| fp.read_exact(&mut magic))
+        .with_context(|| format!("unknown timezone {:?}", zone))?;
+    ensure!(&magic == b"TZif", "unknown timezone {:?}", zone);
+    Ok(())
+}
+
 /// Get current IANA timezone name ("Europe/Berlin").
 pub fn timezone() -> String {
+    if let Some(zone) = TIMEZONE.get() {
+        return zone.clone();
+    }
     // try /etc/timezone which should have the name
     if let Ok(zone) = fs::read_to_string("/etc/timezone") {
         return zone.trim().into();
@@ -176,3 +372,93 @@ pub fn timezone() -> String {
     }
     Default::default()
 }
+
+#[test]
+fn test_percent_decode() {
+    assert_eq!(percent_decode("http://host/a%20b?x=%3D"), "http://host/a b?x==");
+    assert_eq!(percent_decode("caf%C3%A9 %E2%82%AC"), "café €");
+    assert_eq!(percent_decode("%F0%9F%98%80 and 😀"), "😀 and 😀");
+    // invalid UTF-8 is replaced
+    assert_eq!(percent_decode("a%E2%82b"), "a\u{FFFD}b");
+}
+
+#[test]
+fn test_find_mac() {
+    let dir = std::env::temp_dir().join("arexibo-test-find-mac");
+    let iface = |name: &str, assign: &str, carrier: &str, addr: &str| {
+        let path = dir.join(name);
+        fs::create_dir_all(&path).unwrap();
+        fs::write(path.join("addr_assign_type"), format!("{}\n", assign)).unwrap();
+        fs::write(path.join("carrier"), format!("{}\n", carrier)).unwrap();
+        fs::write(path.join("address"), format!("{}\n", addr)).unwrap();
+    };
+    iface("lo", "0", "1", "00:00:00:00:00:00");
+    iface("eth1", "0", "1", "02:00:00:00:01:01");
+    iface("eth0", "0", "0", "02:00:00:00:00:01");
+    iface("wlan0", "0", "0", "02:00:00:00:02:01");
+    iface("veth0", "1", "1", "02:00:00:00:03:01");
+
+    // the only suitable interface
+    assert_eq!(find_mac(&dir, None), Some(("eth1".into(), "02:00:00:00:01:01".into())));
+    // a pinned interface is used even without carrier
+    assert_eq!(find_mac(&dir, Some("eth0")), Some(("eth0".into(), "02:00:00:00:00:01".into())));
+    assert_eq!(find_mac(&dir, Some("wlan0")).unwrap().0, "wlan0");
+    // unknown or unusable interfaces fall back
+    assert_eq!(find_mac(&dir, Some("eth9")).unwrap().0, "eth1");
+    assert_eq!(find_mac(&dir, Some("lo")).unwrap().0, "eth1");
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_check_timezone() {
+    let dir = std::env::temp_dir().join("arexibo-test-timezone");
+    fs::create_dir_all(dir.join("Europe")).unwrap();
+    fs::write(dir.join("Europe/Berlin"), b"TZif2\0\0\0").unwrap();
+    fs::write(dir.join("zone.tab"), b"# tz zone descriptions").unwrap();
+    assert!(check_timezone(&dir, "Europe/Berlin").is_ok());
+    assert!(check_timezone(&dir, "Europe/Nowhere").is_err());
+    assert!(check_timezone(&dir, "zone.tab").is_err());
+    assert!(check_timezone(&dir, "../etc/passwd").is_err());
+    assert!(check_timezone(&dir, "/etc/localtime").is_err());
+    assert!(check_timezone(&dir, "").is_err());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(test)]
+fn font_with_names(names: &[(u16, u16, &str)]) -> Vec<u8> {
+    // a font consisting only of a name table
+    let mut table = vec![0, 0];
+    table.extend((names.len() as u16).to_be_bytes());
+    table.extend((6 + 12 * names.len() as u16).to_be_bytes());
+    let mut strings = Vec::new();
+    for &(platform, name_id, name) in names {
+        let raw: Vec<u8> = if platform == 1 {
+            name.bytes().collect()
+        } else {
+            name.encode_utf16().flat_map(u16::to_be_bytes).collect()
+        };
+        for value in [platform, if platform == 1 { 0 } else { 1 }, 0, name_id,
+                      raw.len() as u16, strings.len() as u16] {
+            table.extend(value.to_be_bytes());
+        }
+        strings.extend(raw);
+    }
+    table.extend(strings);
+    let mut font = vec![0, 1, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+    font.extend(b"name");
+    for value in [0, 28, table.len() as u32] {
+        font.extend(value.to_be_bytes());
+    }
+    font.extend(table);
+    font
+}
+
+#[test]
+fn test_font_family() {
+    let font = font_with_names(&[(3, 1, "Aileron"), (3, 2, "Heavy")]);
+    assert_eq!(font_family(&font).as_deref(), Some("Aileron"));
+    let font = font_with_names(&[(1, 1, "Open Sans"), (3, 16, "Open Sans Variable")]);
+    assert_eq!(font_family(&font).as_deref(), Some("Open Sans Variable"));
+    assert_eq!(font_family(&font_with_names(&[(3, 2, "Bold")])), None);
+    assert_eq!(font_family(b"wOF2"), None);
+}
@@ -2,6 +2,18 @@
 // Licensed under the GNU AGPL, version 3 or later.
 
 //! Send SOAP requests to the XMDS endpoint.
+//!
+//! The SOAP glue is generated from the v5 WSDL, which works for the newer
+//! protocol versions as well.  The differences that concern us:
+//!
+//! - v6 adds `licenceResult` to RegisterDisplay, which the CMS treats as
+//!   optional, and adds some settings to the activation message.  Some
+//!   settings are also omitted by newer CMS releases, so all of them are
+//!   optional when parsing.
+//! - v7 can list dependencies (fonts, player bundles) with `download="xmds"`
+//!   in RequiredFiles, which we skip, and adds widget data files, which are
+//!   ignored like other unknown file types.
+//! - Schedule and the other operations are unchanged.
 
 /// Includes the SOAP glue code generated by build.rs.
 mod soap {
@@ -9,15 +21,21 @@ mod soap {
     include!(concat!(env!("OUT_DIR"), "/xmds_soap.rs"));
 }
 
+use std::{collections::BTreeMap, fs, path::PathBuf};
 use anyhow::{ensure, Context, Result};
 use elementtree::Element;
+use md5::{Md5, Digest};
 use serde::Serialize;
-use crate::config::{CmsSettings, PlayerSettings};
+use crate::bundle;
+use crate::config::{CmsSettings, PlayerSettings, StatsAggregation, StoredCommand};
 use crate::util::{TIME_FMT, Base64Field, ElementExt, retrieve_mac, get_display_name};
 use crate::resource::ReqFile;
 use crate::schedule::Schedule;
 use crate::logger::LogEntry;
 
+/// XMDS protocol versions that can be selected.
+pub const VERSIONS: &[u32] = &[5, 6, 7];
+
 /// Proxy for the XMDS calls to the CMS.
 pub struct Cms {
     service: soap::Service,
@@ -27,23 +45,36 @@ pub struct Cms {
     cms_key: String,
     hw_key: String,
     pub_key: String,
+    /// Directory to keep the last responses in, for diagnostics.
+    dump_dir: PathBuf,
 }
 
 impl Cms {
-    pub fn new(cms: &CmsSettings, pub_key: String) -> Result<Self> {
+    pub fn new(cms: &CmsSettings, pub_key: String, dump_dir: PathBuf) -> Result<Self> {
+        ensure!(VERSIONS.contains(&cms.xmds_version),
+                "unsupported XMDS version {}, use one of {:?}", cms.xmds_version, VERSIONS);
         Ok(Self {
-            service: soap::Service::new(format!("{}/xmds.php?v=5", cms.address),
+            service: soap::Service::new(format!("{}/xmds.php?v={}", cms.address, cms.xmds_version),
                                         cms.make_agent()?),
             display_name: cms.display_name.as_ref().map_or_else(get_display_name,
                                                                 |name| name.to_owned()),
-            mac_addr: retrieve_mac().unwrap_or_else(|| "00:00:00:00:00:00".into()),
+            mac_addr: retrieve_mac(cms.mac_interface.as_deref())
+                .unwrap_or_else(|| "00:00:00:00:00:00".into()),
             channel: cms.xmr_channel(),
             cms_key: cms.key.to_owned(),
             hw_key: cms.display_id.to_owned(),
-            pub_key
+            pub_key,
+            dump_dir,
         })
     }
 
+    /// Keep the last response of the given kind.
+    fn dump(&self, name: &str, xml: &str) {
+        if let Err(e) = fs::write(self.dump_dir.join(name), xml) {
+            log::debug!("could not save {}: {}", name, e);
+        }
+    }
+
     pub fn register_display(&mut self) -> Result<Option<PlayerSettings>> {
         let xml = self.service.RegisterDisplay(
             soap::RegisterDisplayRequest {
@@ -59,41 +90,31 @@ impl Cms {
                 xmrPubKey: &self.pub_key,
             }
         ).context("registering display")?.ActivationMessage;
-
-        let tree = Element::from_reader(&mut xml.as_bytes()).context("parsing activation message")?;
-        let code = tree.get_attr("code").context("no result code in activation")?;
-        if code != "READY" {
-            Ok(None)
-        } else {
-            Ok(Some(PlayerSettings {
-                xmr_network_address: tree.parse_child("xmrNetworkAddress")?,
-                log_level: tree.parse_child("logLevel")?,
-                display_name: tree.parse_child("displayName")?,
-                stats_enabled: tree.parse_child::<i32>("statsEnabled")? != 0,
-                prevent_sleep: tree.parse_child::<i32>("preventSleep")? != 0,
-                collect_interval: tree.parse_child("collectInterval")?,
-                screenshot_interval: tree.parse_child("screenShotRequestInterval")?,
-                embedded_server_port: tree.parse_child("embeddedServerPort")?,
-                size_x: tree.parse_child("sizeX")?,
-                size_y: tree.parse_child("sizeY")?,
-                pos_x: tree.parse_child("offsetX")?,
-                pos_y: tree.parse_child("offsetY")?,
-            }))
-        }
+        self.dump(bundle::REGISTER_XML, &xml);
+        parse_settings(&xml)
     }
 
-    pub fn required_files(&mut self) -> Result<Vec<ReqFile>> {
+    /// Get the required files, and the codes of the layouts among them,
+    /// which interactive actions use to refer to layouts.
+    pub fn required_files(&mut self) -> Result<(Vec<ReqFile>, BTreeMap<String, i64>)> {
         let xml = self.service.RequiredFiles(
             soap::RequiredFilesRequest {
                 serverKey: &self.cms_key,
                 hardwareKey: &self.hw_key,
             }
         ).context("getting required files")?.RequiredFilesXml;
+        self.dump(bundle::REQUIRED_XML, &xml);
 
         let tree = Element::from_reader(&mut xml.as_bytes()).context("parsing required files")?;
         let mut res = vec![];
+        let mut codes = BTreeMap::new();
         for file in tree.find_all("file") {
             let typ = file.get_attr("type").context("missing file type")?;
+            if typ == "layout" {
+                if let Some(code) = file.get_attr("code").filter(|code| !code.is_empty()) {
+                    codes.insert(code.into(), file.parse_attr("id")?);
+                }
+            }
             if typ == "media" || typ == "layout" {
                 let http = file.get_attr("download").context("missing download")? == "http";
                 let (path, name) = if http {
@@ -114,6 +135,22 @@ impl Cms {
                     md5: hex::decode(&file.parse_attr::<String>("md5")?)?,
                     path, name, http,
                 });
+            } else if typ == "dependency" {
+                // dependencies such as fonts can only be downloaded via HTTP
+                // with this XMDS version
+                let name = file.parse_attr::<String>("saveAs")?;
+                if file.get_attr("download") != Some("http") {
+                    log::warn!("dependency {} is not available via HTTP, skipping it", name);
+                    continue;
+                }
+                res.push(ReqFile::File {
+                    id: file.parse_attr("id").unwrap_or_else(|_| dependency_id(&name)),
+                    typ: "dependency",
+                    size: file.parse_attr("size")?,
+                    md5: hex::decode(&file.parse_attr::<String>("md5")?)?,
+                    path: file.parse_attr("path")?,
+                    name, http: true,
+                });
             } else if typ == "resource" {
                 res.push(ReqFile::Resource {
                     id: file.parse_attr("id")?,
@@ -126,7 +163,7 @@ impl Cms {
                 continue;
             }
         }
-        Ok(res)
+        Ok((res, codes))
     }
 
     pub fn get_schedule(&mut self) -> Result<Schedule> {
@@ -136,6 +173,7 @@ impl Cms {
                 hardwareKey: &self.hw_key,
             }
         ).context("getting schedule")?.ScheduleXml;
+        self.dump(bundle::SCHEDULE_XML, &xml);
 
         let tree = Element::from_reader(&mut xml.as_bytes()).context("parsing schedule")?;
         Schedule::parse(tree)
@@ -209,6 +247,10 @@ impl Cms {
             log.set_attr("date", entry.date.format(&TIME_FMT).expect("time fmt"));
             log.set_attr("category", entry.category);
             log.append_child(Element::new("message")).set_text(&entry.message);
+            if let Some((typ, id)) = entry.file {
+                let tag = if typ == "layout" { "layoutID" } else { "mediaID" };
+                log.append_child(Element::new(tag)).set_text(id.to_string());
+            }
             logs.append_child(log);
         }
 
@@ -225,11 +267,12 @@ impl Cms {
     }
 
     pub fn submit_stats(&mut self, stat_xml: &str) -> Result<()> {
+        let stat_xml = format!("<![CDATA[{}]]>", stat_xml);
         let res = self.service.SubmitStats(
             soap::SubmitStatsRequest {
                 serverKey: &self.cms_key,
                 hardwareKey: &self.hw_key,
-                statXml: stat_xml
+                statXml: &stat_xml
             }
         ).context("submitting stats")?;
         ensure!(res.success, "submitting stats not successful");
@@ -260,6 +303,82 @@ impl Cms {
         ensure!(res.success, "status notification not successful");
         Ok(())
     }
+
+    /// Report the current faults, replacing those reported before.
+    pub fn report_faults(&mut self, faults: &[Fault]) -> Result<()> {
+        let json_faults = serde_json::to_string(faults)?;
+        let res = self.service.ReportFaults(
+            soap::ReportFaultsRequest {
+                serverKey: &self.cms_key,
+                hardwareKey: &self.hw_key,
+                fault: &json_faults,
+            }
+        ).context("reporting faults")?;
+        ensure!(res.success, "fault report not successful");
+        Ok(())
+    }
+}
+
+/// Make up an ID for a dependency whose ID is not numeric, stable across
+/// collect cycles and negative to keep it apart from the CMS's IDs.
+fn dependency_id(name: &str) -> i64 {
+    let hash = Md5::digest(name);
+    -i64::from_be_bytes([0, hash[0], hash[1], hash[2], hash[3], hash[4], hash[5], hash[6]]) - 1
+}
+
+/// Parse the settings from the XML of the activation message, if the display
+/// is authorized.
+pub fn parse_settings(xml: &str) -> Result<Option<PlayerSettings>> {
+    let tree = Element::from_reader(&mut xml.as_bytes()).context("parsing activation message")?;
+    parse_activation(&tree)
+}
+
+/// Parse the settings from the activation message, if the display is
+/// authorized.  Settings missing in the message get their default value.
+fn parse_activation(tree: &Element) -> Result<Option<PlayerSettings>> {
+    let code = tree.get_attr("code").context("no result code in activation")?;
+    if code != "READY" {
+        return Ok(None);
+    }
+    let def = PlayerSettings::default();
+    Ok(Some(PlayerSettings {
+        xmr_network_address: tree.parse_child_or("xmrNetworkAddress", def.xmr_network_address)?,
+        log_level: tree.parse_child_or("logLevel", def.log_level)?,
+        display_name: tree.parse_child_or("displayName", def.display_name)?,
+        stats_enabled: tree.parse_child_or::<i32>("statsEnabled", 0)? != 0,
+        stats_aggregation: StatsAggregation::from_cms(
+            tree.find("aggregationLevel").map_or("", |el| el.text())),
+        prevent_sleep: tree.parse_child_or::<i32>("preventSleep", 0)? != 0,
+        collect_interval: tree.parse_child_or("collectInterval", def.collect_interval)?,
+        screenshot_interval: tree.parse_child_or("screenShotRequestInterval",
+                                                 def.screenshot_interval)?,
+        embedded_server_port: tree.parse_child_or("embeddedServerPort",
+                                                  def.embedded_server_port)?,
+        size_x: tree.parse_child_or("sizeX", def.size_x)?,
+        size_y: tree.parse_child_or("sizeY", def.size_y)?,
+        pos_x: tree.parse_child_or("offsetX", def.pos_x)?,
+        pos_y: tree.parse_child_or("offsetY", def.pos_y)?,
+        commands: tree.find("commands").map(|cmds| cmds.children().filter_map(|cmd| {
+            let validation = cmd.find("validationString").map(|el| el.text())
+                                .filter(|text| !text.is_empty()).map(Into::into);
+            Some((cmd.tag().name().into(), StoredCommand {
+                command: cmd.find("commandString")?.text().into(),
+                validation,
+            }))
+        }).collect()).unwrap_or_default(),
+    }))
+}
+
+/// A fault of the display, shown to operators in the CMS.
+#[allow(non_snake_case)]
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct Fault {
+    pub date: String,
+    pub expires: String,
+    pub code: i64,
+    pub reason: String,
+    pub layoutId: i64,
+    pub mediaId: i64,
 }
 
 #[allow(non_snake_case)]
@@ -271,6 +390,53 @@ pub struct Status<'s> {
     pub lastCommandSuccess: bool,
     pub deviceName: &'s str,
     pub timeZone: &'s str,
-    // pub latitude: f64,
-    // pub longitude: f64,
+    pub scheduleHash: &'s str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latitude: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub longitude: Option<f64>,
+}
+
+#[test]
+fn test_parse_activation() {
+    let parse = |xml: &str| parse_activation(&Element::from_reader(xml.as_bytes()).unwrap());
+    assert!(parse(r#"<display code="WAITING"/>"#).unwrap().is_none());
+
+    // v5 style, with all settings
+    let settings = parse(r#"<display code="READY"><collectInterval>60</collectInterval>
+        <xmrNetworkAddress>tcp://cms:9505</xmrNetworkAddress><logLevel>error</logLevel>
+        <displayName>Lobby</displayName><statsEnabled>1</statsEnabled>
+        <preventSleep>0</preventSleep><screenShotRequestInterval>5</screenShotRequestInterval>
+        <embeddedServerPort>9696</embeddedServerPort><sizeX>1920</sizeX><sizeY>1080</sizeY>
+        <offsetX>0</offsetX><offsetY>0</offsetY></display>"#).unwrap().unwrap();
+    assert_eq!(settings.collect_interval, 60);
+    assert_eq!(settings.display_name, "Lobby");
+    assert!(settings.stats_enabled);
+
+    // newer style, with additional and without some settings
+    let settings = parse(r#"<display code="READY"><collectInterval>300</collectInterval>
+        <xmrWebSocketAddress>wss://cms/xmr</xmrWebSocketAddress>
+        <licenceCode>abc</licenceCode></display>"#).unwrap().unwrap();
+    assert_eq!(settings.collect_interval, 300);
+    assert_eq!(settings.log_level, PlayerSettings::default().log_level);
+    assert_eq!(settings.embedded_server_port, PlayerSettings::default().embedded_server_port);
+
+    // stored commands, with the validation regex for the reply if given
+    let settings = parse(r#"<display code="READY"><commands>
+        <reboot><commandString>sudo reboot</commandString><validationString/></reboot>
+        <power><commandString>tcp|beamer:4352|PWR ON</commandString>
+        <validationString>^OK</validationString></power></commands></display>"#).unwrap().unwrap();
+    assert_eq!(settings.commands["reboot"],
+               StoredCommand { command: "sudo reboot".into(), validation: None });
+    assert_eq!(settings.commands["power"].validation.as_deref(), Some("^OK"));
+
+    // present, but invalid settings are still an error
+    assert!(parse(r#"<display code="READY"><sizeX>wide</sizeX></display>"#).is_err());
+}
+
+#[test]
+fn test_dependency_id() {
+    assert_eq!(dependency_id("Aileron-Heavy.otf"), dependency_id("Aileron-Heavy.otf"));
+    assert_ne!(dependency_id("Aileron-Heavy.otf"), dependency_id("Aileron-Light.otf"));
+    assert!(dependency_id("") < 0);
 }
@@ -3,20 +3,33 @@
 
 //! Receive, decrypt and handle incoming XMR messages from CMS.
 
+use std::sync::{Arc, atomic::{AtomicI64, Ordering}};
 use anyhow::{Context, Result};
 use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
 use crossbeam_channel::{Receiver, Sender, unbounded};
 use rsa::RsaPrivateKey;
 use serde::{Deserialize, Deserializer, de::Error};
 use serde_json::from_slice;
-use time::{OffsetDateTime, Duration};
+use time::{OffsetDateTime, PrimitiveDateTime, UtcOffset, Duration};
 use crate::config::CmsSettings;
+use crate::util::TIME_FMT;
 
 /// Possible messages to forward to the collect thread.
 #[derive(Debug)]
 pub enum Message {
     CollectNow,
     Screenshot,
+    /// Show the layout instead of the schedule, for the given number of
+    /// seconds (0 = until reverted).
+    ChangeLayout { layout_id: i64, duration: u64, download_required: bool },
+    /// Go back to the schedule after ChangeLayout.
+    RevertToSchedule,
+    /// Remove all cached files and download them again.
+    Purge,
+    /// Run layout actions triggered by the given webhook code.
+    WebHook(String),
+    /// Run the stored command with the given code.
+    Command(String),
 }
 
 pub struct Manager {
@@ -25,20 +38,30 @@ pub struct Manager {
     #[allow(unused)]  // need to hold onto the context
     context: zmq::Context,
     socket: zmq::Socket,
+    address: String,
+    channel: String,
+    /// Unix timestamp of the last (re)connect.
+    connected: i64,
+    /// Unix timestamp of the last received message (0 = none yet).
+    last_seen: Arc<AtomicI64>,
 }
 
 const HEARTBEAT: &[u8] = b"H";
 
+/// Timeout in milliseconds for waiting for a message, after which the
+/// connection is checked.
+const RECV_TIMEOUT: i32 = 10_000;
+/// Time in seconds without any message, including heartbeats, after which
+/// the connection is considered dead.
+const RECONNECT_AFTER: i64 = 180;
+
 impl Manager {
     pub fn new(settings: &CmsSettings, connect: &str,
                private_key: RsaPrivateKey) -> Result<(Self, Receiver<Message>)> {
         let channel = settings.xmr_channel();
         let context = zmq::Context::new();
-        let socket = context.socket(zmq::SUB).context("creating XMR socket")?;
-        socket.connect(connect).context("connecting XMR socket")?;
-        socket.set_linger(0)?;
-        socket.set_subscribe(channel.as_bytes())?;
-        socket.set_subscribe(HEARTBEAT)?;
+        let socket = subscribe(&context, connect, &channel)?;
+        log::info!("subscribed to XMR at {} on channel {}", connect, channel);
         let (sender, receiver) = unbounded();
 
         Ok((Self {
@@ -46,13 +69,37 @@ impl Manager {
             sender,
             context,
             socket,
+            address: connect.into(),
+            channel,
+            connected: OffsetDateTime::now_utc().unix_timestamp(),
+            last_seen: Arc::new(AtomicI64::new(0)),
         }, receiver))
     }
 
+    /// Return a handle to the timestamp of the last received message,
+    /// including heartbeats.
+    pub fn last_seen(&self) -> Arc<AtomicI64> {
+        self.last_seen.clone()
+    }
+
     pub fn run(mut self) {
         loop {
-            if let Err(e) = self.process_msg() {
-                log::error!("handling XMR message: {:#}", e);
+            match self.process_msg() {
+                Ok(()) => (),
+                Err(e) if e.downcast_ref::<zmq::Error>() == Some(&zmq::Error::EAGAIN) => {
+                    let now = OffsetDateTime::now_utc().unix_timestamp();
+                    let last = self.last_seen.load(Ordering::Relaxed);
+                    if is_dead(self.connected, last, now) {
+                        log::warn!("no XMR message for {}s, reconnecting to {}",
+                                   now - last.max(self.connected), self.address);
+                        self.connected = now;
+                        match subscribe(&self.context, &self.address, &self.channel) {
+                            Ok(socket) => self.socket = socket,
+                            Err(e) => log::error!("reconnecting XMR: {:#}", e),
+                        }
+                    }
+                }
+                Err(e) => log::error!("handling XMR message: {:#}", e),
             }
         }
     }
@@ -64,6 +111,7 @@ impl Manager {
         assert!(key.get_more());
         let content = self.socket.recv_msg(0)?;
         assert!(!content.get_more());
+        self.last_seen.store(OffsetDateTime::now_utc().unix_timestamp(), Ordering::Relaxed);
         if &*channel != HEARTBEAT {
             let json_msg = JsonMessage::new(&self.private_key, &key, &content)?;
             log::debug!("got XMR message: {:?}", json_msg);
@@ -83,6 +131,21 @@ struct JsonMessage {
     created: OffsetDateTime,
     #[serde(default)]
     ttl: i64,
+    // for changeLayout
+    #[serde(rename = "layoutId", default)]
+    layout_id: i64,
+    #[serde(default)]
+    duration: i64,
+    #[serde(rename = "changeMode", default)]
+    change_mode: String,
+    #[serde(rename = "downloadRequired", default)]
+    download_required: bool,
+    // for triggerWebhook
+    #[serde(rename = "triggerCode", default)]
+    trigger_code: String,
+    // for commandAction
+    #[serde(rename = "commandCode", default)]
+    command_code: String,
 }
 
 impl JsonMessage {
@@ -107,6 +170,18 @@ impl JsonMessage {
             // we treat this the same as a collect, which will re-send the pubkey
             "rekeyAction" => Some(Message::CollectNow),
             "screenShot" => Some(Message::Screenshot),
+            "revertToSchedule" => Some(Message::RevertToSchedule),
+            "purgeAll" => Some(Message::Purge),
+            "triggerWebhook" => Some(Message::WebHook(self.trigger_code)),
+            "commandAction" => Some(Message::Command(self.command_code)),
+            "changeLayout" => {
+                if self.change_mode == "queue" {
+                    log::warn!("queueing layouts via XMR is not supported, changing immediately");
+                }
+                Some(Message::ChangeLayout { layout_id: self.layout_id,
+                                             duration: self.duration.max(0) as u64,
+                                             download_required: self.download_required })
+            }
             _ => {
                 log::info!("got unsupported XMR action {:?}", self.action);
                 None
@@ -115,10 +190,35 @@ impl JsonMessage {
     }
 }
 
+/// Create a socket subscribed to our channel and heartbeats.
+fn subscribe(context: &zmq::Context, address: &str, channel: &str) -> Result<zmq::Socket> {
+    let socket = context.socket(zmq::SUB).context("creating XMR socket")?;
+    socket.connect(address).context("connecting XMR socket")?;
+    socket.set_linger(0)?;
+    socket.set_rcvtimeo(RECV_TIMEOUT)?;
+    socket.set_subscribe(channel.as_bytes())?;
+    socket.set_subscribe(HEARTBEAT)?;
+    Ok(socket)
+}
+
+/// Check if the connection is likely dead, given the time of the last
+/// (re)connect and of the last message.
+fn is_dead(connected: i64, last_seen: i64, now: i64) -> bool {
+    now - connected.max(last_seen) > RECONNECT_AFTER
+}
+
 fn deserialize_datetime<'de, D: Deserializer<'de>>(d: D) -> std::result::Result<OffsetDateTime, D::Error> {
     let s = <String as Deserialize>::deserialize(d)?;
-    OffsetDateTime::parse(&s, &time::format_description::well_known::Rfc3339)
-        .map_err(|_| D::Error::custom("invalid datetime string"))
+    parse_datetime(&s).ok_or_else(|| D::Error::custom(format!("invalid datetime string {:?}", s)))
+}
+
+/// Parse a datetime as RFC3339, or in the CMS's usual format without
+/// offset, which is taken as local time.
+fn parse_datetime(s: &str) -> Option<OffsetDateTime> {
+    OffsetDateTime::parse(s, &time::format_description::well_known::Rfc3339).ok().or_else(|| {
+        let offset = UtcOffset::current_local_offset().unwrap_or(UtcOffset::UTC);
+        Some(PrimitiveDateTime::parse(s, &TIME_FMT).ok()?.assume_offset(offset))
+    })
 }
 
 fn decrypt_private_key(enc_key: &[u8], private_key: &RsaPrivateKey) -> Result<Vec<u8>> {
@@ -154,3 +254,60 @@ cwFD2YnuxuF9szIeWPTmHUl6aXRIByuKNexbHqTeNhY=
                                  gCSdfpe5DTA0bEvwXzDst1KtGjK1Nvdg==").unwrap();
     assert_eq!(msg.action, "screenShot");
 }
+
+#[test]
+fn test_parse_datetime() {
+    let dt = parse_datetime("2024-03-01T12:30:00+02:00").unwrap();
+    assert_eq!(dt.unix_timestamp(), 1709289000);
+    let dt = parse_datetime("2024-03-01 12:30:00").unwrap();
+    assert_eq!((dt.hour(), dt.minute()), (12, 30));
+    assert!(parse_datetime("01.03.2024").is_none());
+
+    let msg: JsonMessage = serde_json::from_str(
+        r#"{"action": "collectNow", "createdDt": "2024-03-01 12:30:00", "ttl": 60}"#).unwrap();
+    assert!(msg.is_expired());
+    assert!(serde_json::from_str::<JsonMessage>(
+        r#"{"action": "collectNow", "createdDt": "yesterday"}"#).is_err());
+}
+
+#[test]
+fn test_is_dead() {
+    // heartbeats keep the connection alive
+    assert!(!is_dead(1000, 1100, 1100 + RECONNECT_AFTER));
+    assert!(is_dead(1000, 1100, 1101 + RECONNECT_AFTER));
+    // a new connection gets time to receive the first heartbeat
+    assert!(!is_dead(1000, 0, 1000 + RECONNECT_AFTER));
+    assert!(is_dead(1000, 0, 1001 + RECONNECT_AFTER));
+    // after reconnecting, the old last message doesn't count
+    assert!(!is_dead(2000, 1100, 2000 + RECONNECT_AFTER));
+}
+
+#[test]
+fn test_change_layout() {
+    let created = OffsetDateTime::now_utc().format(&time::format_description::well_known::Rfc3339).unwrap();
+    let msg: JsonMessage = serde_json::from_str(&format!(
+        r#"{{"action": "changeLayout", "createdDt": "{}", "ttl": 60, "layoutId": 12,
+            "duration": 30, "downloadRequired": true, "changeMode": "replace"}}"#, created)).unwrap();
+    assert!(matches!(msg.into_msg(), Some(Message::ChangeLayout { layout_id: 12, duration: 30,
+                                                                  download_required: true })));
+}
+
+#[test]
+fn test_revert_to_schedule() {
+    let created = OffsetDateTime::now_utc().format(&time::format_description::well_known::Rfc3339).unwrap();
+    let msg: JsonMessage = serde_json::from_str(&format!(
+        r#"{{"action": "revertToSchedule", "createdDt": "{}", "ttl": 60}}"#, created)).unwrap();
+    assert!(matches!(msg.into_msg(), Some(Message::RevertToSchedule)));
+}
+
+#[test]
+fn test_purge_webhook_command() {
+    let created = OffsetDateTime::now_utc().format(&time::format_description::well_known::Rfc3339).unwrap();
+    let parse = |rest: &str| serde_json::from_str::<JsonMessage>(&format!(
+        r#"{{"createdDt": "{}", "ttl": 60, {}}}"#, created, rest)).unwrap().into_msg();
+    assert!(matches!(parse(r#""action": "purgeAll""#), Some(Message::Purge)));
+    assert!(matches!(parse(r#""action": "triggerWebhook", "triggerCode": "next""#),
+                     Some(Message::WebHook(code)) if code == "next"));
+    assert!(matches!(parse(r#""action": "commandAction", "commandCode": "reboot""#),
+                     Some(Message::Command(code)) if code == "reboot"));
+}